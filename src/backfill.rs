@@ -0,0 +1,228 @@
+//! Fetching what was said on a channel before we joined or while we were
+//! away.
+//!
+//! Nodes keeping a history answer [`HistoryRequest`]s for the channels they
+//! are in with a page of the messages they stored, going back from the
+//! newest one or from a message the asker already has. Messages are sent as
+//! their authors signed them, so whoever serves a page may leave messages out
+//! but can't forge any: each one is checked like a message arriving over
+//! gossipsub, and those without a valid author signature are dropped. Each
+//! peer may ask for [`SERVE_BURST`] pages at once and one every two seconds
+//! beyond that, leaves serve none.
+//!
+//! The newest page of a channel is asked for on joining it, once a member
+//! serving history shows up, and older ones with [`Node::earlier`]. Of the
+//! members serving history, the one expected to answer the soonest is
+//! asked, and the next one if it fails or stalls for
+//! [`STALL_TIMEOUT`].
+//!
+//! [`Node::earlier`]: crate::Node::earlier
+
+use std::time::{Duration, Instant};
+
+use libp2p::{request_response::RequestId, PeerId};
+
+use crate::{
+    broadcast,
+    history::History,
+    message::{HistoryRequest, HistoryResponse},
+    Published,
+};
+
+/// How long a member may take to answer before the next one is asked.
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Messages asked for at once.
+pub const PAGE_SIZE: u32 = 50;
+
+/// Most messages sent in a single page, whatever was asked for.
+pub const MAX_PAGE: u32 = 100;
+
+/// Pages a single peer may ask for at once.
+pub const SERVE_BURST: u32 = 5;
+
+/// Pages per second a single peer may ask for beyond [`SERVE_BURST`].
+pub const SERVE_RATE: f64 = 0.5;
+
+// Bytes of messages in a single page at most, well within what the codec
+// reads
+const MAX_PAGE_BYTES: usize = 512 * 1024;
+
+/// A page of history we asked for.
+pub(crate) struct Fetch {
+    // The id it was handed out with, that of the first request for it
+    pub(crate) id: RequestId,
+    pub(crate) channel: String,
+    pub(crate) before: Vec<u8>,
+    pub(crate) limit: u32,
+    // Providers asked so far, the last one being asked now since `sent`
+    pub(crate) tried: Vec<PeerId>,
+    pub(crate) sent: Instant,
+    // Whether it was asked for on joining the channel, rather than by the user
+    pub(crate) catch_up: bool,
+}
+
+/// The answer to `request` from what `history` holds. Reads from disk, so
+/// best called away from the swarm.
+pub(crate) fn serve(history: &History, request: &HistoryRequest) -> HistoryResponse {
+    let limit = request.limit.min(MAX_PAGE) as usize;
+    let page = match history.page(&request.channel, &request.before, limit) {
+        Ok(page) => page,
+        Err(e) => {
+            log::warn!("failed to read history of {}: {:#}", request.channel, e);
+            return refusal("history unavailable");
+        }
+    };
+    // Cut short from the oldest end, so the page still ends at `before`
+    let mut size = 0;
+    let mut messages: Vec<Vec<u8>> = page
+        .iter()
+        .rev()
+        .map(Published::store)
+        .take_while(|stored| {
+            size += stored.len();
+            size <= MAX_PAGE_BYTES
+        })
+        .collect();
+    messages.reverse();
+    HistoryResponse {
+        messages,
+        error: String::new(),
+    }
+}
+
+/// A refused request, telling why.
+pub(crate) fn refusal(error: &str) -> HistoryResponse {
+    HistoryResponse {
+        messages: Vec::new(),
+        error: error.to_owned(),
+    }
+}
+
+/// The valid messages in a page of `channel` asked for with `limit`, oldest
+/// first, or `None` if the page holds more than that.
+pub(crate) fn check(
+    channel: &str,
+    limit: u32,
+    response: HistoryResponse,
+) -> Option<Vec<Published>> {
+    if response.messages.len() > limit as usize {
+        return None;
+    }
+    let total = response.messages.len();
+    let owner = broadcast::owner(channel);
+    let messages: Vec<Published> = response
+        .messages
+        .into_iter()
+        .filter_map(Published::load)
+        .filter(|message| message.channel == channel)
+        .filter(|message| owner.as_ref().is_none_or(|owner| broadcast::verify(message, owner)))
+        // Nothing but the author's signature vouches for a stored message
+        .filter(|message| message.is_signed() && message.verify())
+        .collect();
+    if messages.len() < total {
+        log::debug!("dropped {} invalid messages of {}", total - messages.len(), channel);
+    }
+    Some(messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::{identity::Keypair, PeerId};
+
+    use super::*;
+    use crate::message::{self, ChatMessage};
+
+    fn message(keypair: &Keypair, channel: &str, content: &str) -> Published {
+        let message = ChatMessage {
+            display_name: String::from("alice"),
+            content: content.to_owned(),
+            channel: channel.to_owned(),
+            author: PeerId::from(keypair.public()).to_bytes(),
+            author_key: keypair.public().into_protobuf_encoding(),
+            ..ChatMessage::default()
+        };
+        Published::sign(message, keypair, None, false).unwrap()
+    }
+
+    fn request(channel: &str, before: &[u8], limit: u32) -> HistoryRequest {
+        HistoryRequest {
+            channel: channel.to_owned(),
+            before: before.to_vec(),
+            limit,
+        }
+    }
+
+    fn contents(messages: &[Published]) -> Vec<&str> {
+        messages.iter().map(|message| message.content.as_str()).collect()
+    }
+
+    #[test]
+    fn pages_back_from_a_message() {
+        let keypair = Keypair::generate_ed25519();
+        let history = History::temporary();
+        for n in 0..5 {
+            history.append(&message(&keypair, "chat", &n.to_string())).unwrap();
+            history.append(&message(&keypair, "other", "elsewhere")).unwrap();
+        }
+
+        let newest = check("chat", 2, serve(&history, &request("chat", &[], 2))).unwrap();
+        assert_eq!(contents(&newest), vec!["3", "4"]);
+        let before = newest[0].digest();
+        let older = check("chat", 2, serve(&history, &request("chat", &before, 2))).unwrap();
+        assert_eq!(contents(&older), vec!["1", "2"]);
+        let before = older[0].digest();
+        let oldest = check("chat", 2, serve(&history, &request("chat", &before, 2))).unwrap();
+        assert_eq!(contents(&oldest), vec!["0"]);
+
+        // Nothing for a message we don't have
+        let unknown = serve(&history, &request("chat", &[0; 32], 2));
+        assert!(unknown.messages.is_empty() && unknown.error.is_empty());
+    }
+
+    #[test]
+    fn caps_pages() {
+        let keypair = Keypair::generate_ed25519();
+        let history = History::temporary();
+        for n in 0..MAX_PAGE + 10 {
+            history.append(&message(&keypair, "chat", &n.to_string())).unwrap();
+        }
+        let response = serve(&history, &request("chat", &[], u32::MAX));
+        assert_eq!(response.messages.len(), MAX_PAGE as usize);
+    }
+
+    #[test]
+    fn drops_invalid_messages() {
+        let keypair = Keypair::generate_ed25519();
+        // As older versions published it
+        let unsigned = message::encode(&ChatMessage {
+            content: String::from("unsigned"),
+            channel: String::from("chat"),
+            ..ChatMessage::default()
+        });
+        let forged = message(&keypair, "chat", "forged").message().clone();
+        let forged = Published::sign(forged, &Keypair::generate_ed25519(), None, false).unwrap();
+        let response = HistoryResponse {
+            messages: vec![
+                message(&keypair, "chat", "valid").store(),
+                message(&keypair, "other", "elsewhere").store(),
+                forged.store(),
+                message::wrap(message::Kind::Chat, unsigned),
+                b"garbage".to_vec(),
+            ],
+            error: String::new(),
+        };
+        let messages = check("chat", PAGE_SIZE, response).unwrap();
+        assert_eq!(contents(&messages), vec!["valid"]);
+    }
+
+    #[test]
+    fn refuses_more_than_asked() {
+        let keypair = Keypair::generate_ed25519();
+        let response = HistoryResponse {
+            messages: vec![message(&keypair, "chat", "hi").store(); 3],
+            error: String::new(),
+        };
+        assert!(check("chat", 2, response).is_none());
+    }
+}
@@ -0,0 +1,1867 @@
+use core::task::{Context, Poll};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    iter,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail};
+use blocking::unblock;
+use futures::{future::BoxFuture, prelude::*, stream::FuturesUnordered};
+use futures_timer::Delay;
+use libp2p::{
+    gossipsub::{
+        Gossipsub, GossipsubConfigBuilder, GossipsubEvent, GossipsubMessage, IdentTopic as Topic,
+        MessageAcceptance, MessageAuthenticity, MessageId, PeerScoreParams, PeerScoreThresholds,
+        TopicHash,
+    },
+    identify::{Identify, IdentifyEvent},
+    kad::{
+        record::{store::MemoryStore, Key},
+        Kademlia, KademliaConfig, KademliaEvent, QueryResult,
+    },
+    mdns::{Mdns, MdnsEvent},
+    ping::{Ping, PingConfig, PingEvent, PingSuccess},
+    request_response::{
+        ProtocolSupport, RequestId, RequestResponse, RequestResponseConfig, RequestResponseEvent,
+        RequestResponseMessage, ResponseChannel,
+    },
+    swarm::{
+        DialPeerCondition, NetworkBehaviourAction, NetworkBehaviourEventProcess, PollParameters,
+    },
+    NetworkBehaviour, PeerId,
+};
+use prost::Message;
+
+use crate::{
+    backfill::{self, Fetch},
+    bootstrap::{BootstrapEvent, Bootstrapper},
+    broadcast,
+    capabilities::{self, Capabilities, Capability, Conversation, Downgrade, PROTOCOL_VERSION},
+    codec::{
+        DirectCodec, FileCodec, HistoryCodec, DIRECT_PROTOCOL, FILE_PROTOCOL, HISTORY_PROTOCOL,
+    },
+    dedup::Seen,
+    degree::Degree,
+    fragment::Reassembler,
+    gate::Tracker,
+    history::History,
+    latency::LatencyTracker,
+    metrics::{Metrics, Recorder},
+    message::{
+        self, Announcement, Chat, Control, DirectAck, DirectMessage, FileAck, FileChunk,
+        HistoryRequest, HistoryResponse, Kind, Status,
+    },
+    moderation::{self, Moderation},
+    order::{Arrivals, Order},
+    outbox::{Failure, Outbox, Received},
+    presence::{self, Roster},
+    providers::{Providers, MAX_ATTEMPTS},
+    ratelimit::{RateLimiter, Verdict},
+    receipt::{self, Receipts},
+    redial::{RedialEvent, Redialer},
+    schedule::Schedule,
+    seniority::{self, Seniority},
+    standby::Standby,
+    transfer::{self, Direction, Incoming, Outgoing},
+    Config, Error, NodeEvent, PeerInfo, Published,
+};
+
+// How often to refresh the DHT and re-announce ourselves as a channel provider.
+const DISCOVERY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+// How often peer scores are brought up to date with how long we know them.
+const SCORE_INTERVAL: Duration = Duration::from_secs(10);
+// How often waiting direct messages are expired and their recipients dialed.
+const OUTBOX_INTERVAL: Duration = Duration::from_secs(30);
+// How often incoming transfers are given up on if their sender went quiet.
+const TRANSFER_INTERVAL: Duration = Duration::from_secs(10);
+// How often the peers kept on standby are dialed if we lost them.
+const STANDBY_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "NodeEvent", poll_method = "poll")]
+pub(crate) struct MyBehaviour {
+    pub(crate) gossipsub: Gossipsub,
+    kademlia: Kademlia<MemoryStore>,
+    mdns: Mdns,
+    pub(crate) direct: RequestResponse<DirectCodec>,
+    files: RequestResponse<FileCodec>,
+    history: RequestResponse<HistoryCodec>,
+    ping: Ping,
+    identify: Identify,
+    tracker: Tracker,
+    pub(crate) recorder: Recorder,
+    pub(crate) redialer: Redialer,
+    bootstrapper: Bootstrapper,
+
+    #[behaviour(ignore)]
+    local_peer_id: PeerId,
+    // Whether gossipsub waits for our verdict before forwarding a message
+    #[behaviour(ignore)]
+    validate_messages: bool,
+    // Whether no message of others is ever forwarded, see `Config::leaf`
+    #[behaviour(ignore)]
+    leaf: bool,
+    // Channels we are subscribed to, each one a gossipsub topic
+    #[behaviour(ignore)]
+    pub(crate) channels: BTreeSet<String>,
+    #[behaviour(ignore)]
+    discovery_timer: Delay,
+    #[behaviour(ignore)]
+    display_name: String,
+    #[behaviour(ignore)]
+    presence_timer: Delay,
+    // Whether we told others we joined
+    #[behaviour(ignore)]
+    joined: bool,
+    #[behaviour(ignore)]
+    pub(crate) roster: Roster,
+    #[behaviour(ignore)]
+    pub(crate) seniority: Seniority,
+    #[behaviour(ignore)]
+    probation: Duration,
+    #[behaviour(ignore)]
+    score_timer: Delay,
+    #[behaviour(ignore)]
+    pub(crate) outbox: Outbox,
+    #[behaviour(ignore)]
+    outbox_timer: Delay,
+    // Direct messages received lately, to drop copies of them
+    #[behaviour(ignore)]
+    received: Received,
+    #[behaviour(ignore)]
+    pub(crate) standby: Standby,
+    #[behaviour(ignore)]
+    standby_timer: Delay,
+    #[behaviour(ignore)]
+    pub(crate) receipts: Receipts,
+    #[behaviour(ignore)]
+    receipt_timer: Delay,
+    // Whether we acknowledge messages we get
+    #[behaviour(ignore)]
+    send_receipts: bool,
+    // Last message hash seen from each author per channel, used to detect
+    // missing messages
+    #[behaviour(ignore)]
+    pub(crate) chains: HashMap<(PeerId, String), Vec<u8>>,
+    // Messages received or sent lately, to drop copies of them
+    #[behaviour(ignore)]
+    pub(crate) seen: Seen,
+    // Pieces of messages too large to be published whole, with the gossipsub
+    // messages they came in, left unreported until the whole message is checked
+    #[behaviour(ignore)]
+    fragments: Reassembler<(MessageId, PeerId)>,
+    // Where each message falls among those by the same author
+    #[behaviour(ignore)]
+    arrivals: Arrivals,
+    // How fast each peer has been publishing
+    #[behaviour(ignore)]
+    limiter: RateLimiter,
+    // Peer last seen using each display name, so direct messages can be
+    // addressed by name
+    #[behaviour(ignore)]
+    pub(crate) names: HashMap<String, PeerId>,
+    // What each identified pingpong peer announced it supports
+    #[behaviour(ignore)]
+    pub(crate) capabilities: HashMap<PeerId, Capabilities>,
+    // What each identified peer told about itself
+    #[behaviour(ignore)]
+    pub(crate) peer_info: HashMap<PeerId, PeerInfo>,
+    #[behaviour(ignore)]
+    pub(crate) latency: LatencyTracker,
+    #[behaviour(ignore)]
+    metrics: Arc<Metrics>,
+    #[behaviour(ignore)]
+    moderation: Moderation,
+    // Refuse downgrades instead of warning about them
+    #[behaviour(ignore)]
+    strict: bool,
+    // Downgrades already warned about
+    #[behaviour(ignore)]
+    downgrades: HashSet<(Conversation, Downgrade)>,
+    // Peers whose next ping result was asked for with `ping`
+    #[behaviour(ignore)]
+    pending_pings: HashSet<PeerId>,
+    // Where received files go, incoming transfers are refused without one
+    #[behaviour(ignore)]
+    download_dir: Option<PathBuf>,
+    #[behaviour(ignore)]
+    max_file_size: u64,
+    #[behaviour(ignore)]
+    next_transfer_id: u64,
+    // Files being sent, keyed by the request carrying their latest chunk
+    #[behaviour(ignore)]
+    outgoing: HashMap<RequestId, Outgoing>,
+    // How file chunks make way for chat
+    #[behaviour(ignore)]
+    schedule: Schedule,
+    // When a chat or direct message was last sent or received
+    #[behaviour(ignore)]
+    last_chat: Option<Instant>,
+    // Files whose next chunk waits for its turn, until when
+    #[behaviour(ignore)]
+    held: VecDeque<(Instant, Outgoing)>,
+    #[behaviour(ignore)]
+    held_timer: Delay,
+    // Files being received, waiting for their next chunk
+    #[behaviour(ignore)]
+    incoming: HashMap<(PeerId, u64), Incoming>,
+    // Files being received whose latest chunk is being written
+    #[behaviour(ignore)]
+    writing: HashSet<(PeerId, u64)>,
+    #[behaviour(ignore)]
+    transfer_timer: Delay,
+    // Reading, hashing and writing files, away from the swarm
+    #[behaviour(ignore)]
+    file_io: FuturesUnordered<BoxFuture<'static, FileIo>>,
+    // Our own history, served to the members of our channels
+    #[behaviour(ignore)]
+    stored: Option<History>,
+    // Pages of history being read for peers, with where to send them
+    #[behaviour(ignore)]
+    serving: FuturesUnordered<BoxFuture<'static, Serving>>,
+    // How often each peer has been asking for history
+    #[behaviour(ignore)]
+    history_limiter: RateLimiter,
+    // Pages of history we asked for, by the id of the request out for each
+    #[behaviour(ignore)]
+    fetches: HashMap<RequestId, Fetch>,
+    // How fast the members serving us history answered so far
+    #[behaviour(ignore)]
+    providers: Providers,
+    // Channels joined whose newest page of history was not asked for yet
+    #[behaviour(ignore)]
+    catch_up: BTreeSet<String>,
+    // Peers discovered through mDNS or the DHT that we still have to connect to
+    #[behaviour(ignore)]
+    to_dial: VecDeque<PeerId>,
+    #[behaviour(ignore)]
+    events: VecDeque<NodeEvent>,
+}
+
+/// What a swarm takes over from the one it replaces, or from the last run.
+pub(crate) struct Carried {
+    pub(crate) chains: HashMap<(PeerId, String), Vec<u8>>,
+    pub(crate) seniority: Seniority,
+    pub(crate) seen: Seen,
+}
+
+impl MyBehaviour {
+    pub(crate) async fn new(
+        config: &Config,
+        channels: &[String],
+        carried: Carried,
+        history: Option<History>,
+        moderation: Moderation,
+        metrics: Arc<Metrics>,
+    ) -> Result<Self, Error> {
+        let Carried {
+            chains,
+            seniority,
+            seen,
+        } = carried;
+        let local_peer_id = config.local_peer_id();
+        let mut gossipsub_config = match &config.budget {
+            Some(budget) => {
+                let degree = Degree::new(seniority.len(), budget);
+                log::info!(
+                    "forwarding to {} mesh peers per channel, {} at most",
+                    degree.mesh_n,
+                    degree.mesh_n_high
+                );
+                degree.apply(&config.gossipsub)
+            }
+            None => config.gossipsub.clone(),
+        };
+        // Only messages waiting for our verdict can be kept from being forwarded
+        let leaf = config.is_leaf();
+        if leaf {
+            log::info!("running as a leaf, forwarding no one's messages");
+            gossipsub_config = GossipsubConfigBuilder::from(gossipsub_config)
+                .validate_messages()
+                .build()
+                .expect("valid gossipsub config");
+        }
+        // Sign every published message with our identity key
+        let mut gossipsub = Gossipsub::new(
+            MessageAuthenticity::Signed(config.keypair.clone()),
+            gossipsub_config,
+        )
+        .map_err(Error::config)?;
+        // Score peers so long-known identities are kept in the mesh, see `seniority`
+        gossipsub
+            .with_peer_score(PeerScoreParams::default(), PeerScoreThresholds::default())
+            .map_err(Error::config)?;
+        // Use our own DHT protocol so we don't end up crawling other networks
+        let mut kademlia_config = KademliaConfig::default();
+        kademlia_config.set_protocol_name(&b"/pingpong/kad/1.0.0"[..]);
+        let mut kademlia = Kademlia::with_config(
+            local_peer_id,
+            MemoryStore::new(local_peer_id),
+            kademlia_config,
+        );
+        for (peer_id, addr) in &config.bootstrap {
+            kademlia.add_address(peer_id, addr.clone());
+        }
+        let mdns = Mdns::new().await.map_err(Error::transport)?;
+        // With entry points to race, discovery starts once one of them answers
+        let bootstrapper = Bootstrapper::new(config);
+        let first_discovery = if bootstrapper.is_racing() {
+            DISCOVERY_INTERVAL
+        } else {
+            Duration::from_secs(0)
+        };
+        let direct = RequestResponse::new(
+            DirectCodec::default(),
+            iter::once((DIRECT_PROTOCOL, ProtocolSupport::Full)),
+            RequestResponseConfig::default(),
+        );
+        // Only offer to receive files if we have somewhere to put them
+        let file_support = match config.download_dir {
+            Some(_) => ProtocolSupport::Full,
+            None => ProtocolSupport::Outbound,
+        };
+        let files = RequestResponse::new(
+            FileCodec::default(),
+            iter::once((FILE_PROTOCOL, file_support)),
+            RequestResponseConfig::default(),
+        );
+        // Nor to serve history without keeping one, or as a leaf
+        let history_support = match history {
+            Some(_) if !leaf => ProtocolSupport::Full,
+            _ => ProtocolSupport::Outbound,
+        };
+        let history_requests = RequestResponse::new(
+            HistoryCodec::default(),
+            iter::once((HISTORY_PROTOCOL, history_support)),
+            // Another member is asked when one stalls
+            RequestResponseConfig::default()
+                .set_request_timeout(backfill::STALL_TIMEOUT)
+                .clone(),
+        );
+        let mut behaviour = MyBehaviour {
+            gossipsub,
+            kademlia,
+            mdns,
+            direct,
+            files,
+            history: history_requests,
+            // Pinging every peer also keeps idle connections open
+            ping: Ping::new(PingConfig::new().with_keep_alive(true)),
+            identify: Identify::new(
+                PROTOCOL_VERSION.to_owned(),
+                capabilities::agent_version(&capabilities::of(config)),
+                config.keypair.public(),
+            ),
+            tracker: Tracker::new(moderation::gater(config, &moderation)),
+            recorder: Recorder::new(metrics.clone()),
+            redialer: Redialer::new(config),
+            bootstrapper,
+            local_peer_id,
+            validate_messages: leaf || config.gossipsub.validate_messages(),
+            leaf,
+            channels: BTreeSet::new(),
+            discovery_timer: Delay::new(first_discovery),
+            display_name: config.display_name.clone(),
+            presence_timer: Delay::new(presence::ANNOUNCE_INTERVAL),
+            joined: false,
+            roster: Roster::default(),
+            seniority,
+            probation: config.probation,
+            score_timer: Delay::new(SCORE_INTERVAL),
+            outbox: Outbox::new(config.outbox_ttl),
+            outbox_timer: Delay::new(OUTBOX_INTERVAL),
+            received: Received::default(),
+            standby: Standby::new(config.standby),
+            standby_timer: Delay::new(STANDBY_INTERVAL),
+            receipts: Receipts::default(),
+            receipt_timer: Delay::new(receipt::INTERVAL),
+            send_receipts: config.receipts,
+            chains,
+            seen,
+            fragments: Reassembler::default(),
+            arrivals: Arrivals::default(),
+            limiter: RateLimiter::new(config.message_rate, config.message_burst),
+            names: HashMap::new(),
+            capabilities: HashMap::new(),
+            peer_info: HashMap::new(),
+            latency: LatencyTracker::default(),
+            metrics,
+            moderation,
+            strict: config.strict,
+            downgrades: HashSet::new(),
+            pending_pings: HashSet::new(),
+            download_dir: config.download_dir.clone(),
+            max_file_size: config.max_file_size,
+            next_transfer_id: 0,
+            outgoing: HashMap::new(),
+            schedule: config.schedule,
+            last_chat: None,
+            held: VecDeque::new(),
+            held_timer: Delay::new(Duration::from_secs(0)),
+            incoming: HashMap::new(),
+            writing: HashSet::new(),
+            transfer_timer: Delay::new(TRANSFER_INTERVAL),
+            file_io: FuturesUnordered::new(),
+            stored: history,
+            serving: FuturesUnordered::new(),
+            history_limiter: RateLimiter::new(backfill::SERVE_RATE, backfill::SERVE_BURST),
+            fetches: HashMap::new(),
+            providers: Providers::default(),
+            catch_up: BTreeSet::new(),
+            to_dial: VecDeque::new(),
+            events: VecDeque::new(),
+        };
+
+        behaviour
+            .gossipsub
+            .subscribe(&Topic::new(presence::TOPIC))
+            .map_err(|e| {
+                Error::transport(format!("failed to subscribe to presence announcements: {:?}", e))
+            })?;
+        for channel in channels {
+            behaviour.join(channel).map_err(Error::config)?;
+        }
+        Ok(behaviour)
+    }
+
+    // Subscribe to a channel, returning false if we already were.
+    pub(crate) fn join(&mut self, channel: &str) -> anyhow::Result<bool> {
+        if channel == presence::TOPIC {
+            bail!("{} is reserved for presence announcements", channel);
+        }
+        if self.channels.contains(channel) {
+            return Ok(false);
+        }
+        self.gossipsub
+            .subscribe(&Topic::new(channel))
+            .map_err(|e| anyhow!("failed to join {}: {:?}", channel, e))?;
+        self.channels.insert(channel.to_owned());
+        // Look for other members now rather than at the next discovery round
+        self.provide(channel);
+        self.catch_up.insert(channel.to_owned());
+        self.catch_up();
+        Ok(true)
+    }
+
+    // Unsubscribe from a channel, returning false if we were not in it.
+    pub(crate) fn leave(&mut self, channel: &str) -> anyhow::Result<bool> {
+        if !self.channels.remove(channel) {
+            return Ok(false);
+        }
+        self.gossipsub
+            .unsubscribe(&Topic::new(channel))
+            .map_err(|e| anyhow!("failed to leave {}: {:?}", channel, e))?;
+        self.kademlia.stop_providing(&provider_key(channel));
+        self.receipts.leave(channel);
+        self.catch_up.remove(channel);
+        Ok(true)
+    }
+
+    fn poll<TEv>(
+        &mut self,
+        cx: &mut Context<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<NetworkBehaviourAction<TEv, NodeEvent>> {
+        while self.discovery_timer.poll_unpin(cx).is_ready() {
+            self.discovery_timer.reset(DISCOVERY_INTERVAL);
+            self.discover();
+        }
+        while self.presence_timer.poll_unpin(cx).is_ready() {
+            self.presence_timer.reset(presence::ANNOUNCE_INTERVAL);
+            self.announce(Status::Alive);
+            for (peer_id, presence) in self.roster.expire() {
+                self.events.push_back(NodeEvent::PeerOffline {
+                    peer_id,
+                    display_name: presence.display_name,
+                });
+            }
+        }
+        while self.score_timer.poll_unpin(cx).is_ready() {
+            self.score_timer.reset(SCORE_INTERVAL);
+            let peers: Vec<PeerId> = self
+                .gossipsub
+                .all_peers()
+                .map(|(peer_id, _)| *peer_id)
+                .collect();
+            for peer_id in peers {
+                self.rank(peer_id);
+            }
+        }
+        while self.outbox_timer.poll_unpin(cx).is_ready() {
+            self.outbox_timer.reset(OUTBOX_INTERVAL);
+            for (peer_id, request_id) in self.outbox.expire() {
+                self.events.push_back(NodeEvent::Expired {
+                    peer_id,
+                    request_id,
+                });
+            }
+            // Keep trying to reach whoever we have messages for
+            for peer_id in self.outbox.recipients() {
+                self.dial(peer_id);
+            }
+        }
+        while self.standby_timer.poll_unpin(cx).is_ready() {
+            self.standby_timer.reset(STANDBY_INTERVAL);
+            // Only dials those we are not connected to
+            for peer_id in self.standby.pool() {
+                if !self.moderation.is_blocked(&peer_id) {
+                    self.dial(peer_id);
+                }
+            }
+        }
+        while self.receipt_timer.poll_unpin(cx).is_ready() {
+            self.receipt_timer.reset(receipt::INTERVAL);
+            self.publish_receipts();
+        }
+        while self.transfer_timer.poll_unpin(cx).is_ready() {
+            self.transfer_timer.reset(TRANSFER_INTERVAL);
+            self.expire_transfers();
+        }
+        if !self.held.is_empty() {
+            self.release(cx);
+        }
+        while let Poll::Ready(Some(done)) = self.file_io.poll_next_unpin(cx) {
+            self.file_io_done(done);
+            // Requests and responses are only picked up once `files` is
+            // polled again
+            cx.waker().wake_by_ref();
+        }
+        while let Poll::Ready(Some((channel, page))) = self.serving.poll_next_unpin(cx) {
+            if self.history.send_response(channel, page).is_err() {
+                log::debug!("peer went away before its page of history was read");
+            }
+            cx.waker().wake_by_ref();
+        }
+        if let Some(peer_id) = self.to_dial.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::DialPeer {
+                peer_id,
+                condition: DialPeerCondition::Disconnected,
+            });
+        }
+        match self.events.pop_front() {
+            Some(event) => Poll::Ready(NetworkBehaviourAction::GenerateEvent(event)),
+            None => Poll::Pending,
+        }
+    }
+
+    // Refresh the routing table and re-announce all our channels.
+    fn discover(&mut self) {
+        if self.kademlia.bootstrap().is_err() {
+            log::debug!("no known DHT peers to bootstrap from");
+        }
+        for channel in self.channels.clone() {
+            self.provide(&channel);
+        }
+    }
+
+    // Tell peers on the presence topic about us. Until we told anyone we
+    // joined, staying alive is a join.
+    pub(crate) fn announce(&mut self, status: Status) {
+        let status = match status {
+            Status::Alive if !self.joined => Status::Join,
+            status => status,
+        };
+        let announcement = Announcement {
+            display_name: self.display_name.clone(),
+            status: status as i32,
+        };
+        let data = self.seal(presence::TOPIC, Kind::Presence, message::encode(&announcement));
+        match self.gossipsub.publish(Topic::new(presence::TOPIC), data) {
+            Ok(_) => self.joined |= status == Status::Join,
+            Err(e) => log::debug!("failed to announce presence: {:?}", e),
+        }
+    }
+
+    // Announce that we are in a channel and look for other peers that are.
+    fn provide(&mut self, channel: &str) {
+        let key = provider_key(channel);
+        if let Err(e) = self.kademlia.start_providing(key.clone()) {
+            log::warn!("failed to announce provider record for {}: {:?}", channel, e);
+        }
+        self.kademlia.get_providers(key);
+    }
+
+    // Score a peer by how long we have known its identity.
+    fn rank(&mut self, peer_id: PeerId) {
+        let score = seniority::score(self.seniority.age(peer_id), self.probation);
+        self.gossipsub.set_application_score(&peer_id, score);
+    }
+
+    // Report the next ping round trip to a peer, connecting to it if needed.
+    pub(crate) fn ping(&mut self, peer_id: PeerId) {
+        self.pending_pings.insert(peer_id);
+        self.dial(peer_id);
+    }
+
+    // Check that `peers` support `capability` before relying on it in
+    // `conversation`. Each peer that doesn't is reported once per
+    // conversation, or refused in strict mode.
+    pub(crate) fn check_downgrade(
+        &mut self,
+        conversation: &Conversation,
+        peers: &[PeerId],
+        capability: Capability,
+    ) -> anyhow::Result<()> {
+        let downgrades: Vec<Downgrade> = peers
+            .iter()
+            .filter_map(|peer_id| {
+                capabilities::downgrade(*peer_id, self.capabilities.get(peer_id), capability)
+            })
+            .collect();
+        if self.strict {
+            if let Some(downgrade) = downgrades.first() {
+                bail!("{}, refusing to fall back in strict mode", downgrade);
+            }
+        }
+        for downgrade in downgrades {
+            if self.downgrades.insert((conversation.clone(), downgrade.clone())) {
+                self.events.push_back(NodeEvent::Downgraded {
+                    conversation: conversation.clone(),
+                    downgrade,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    // Peers we know to be in a channel.
+    pub(crate) fn channel_peers(&self, channel: &str) -> Vec<PeerId> {
+        let topic = Topic::new(channel).hash();
+        self.gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic))
+            .map(|(peer_id, _)| *peer_id)
+            .collect()
+    }
+
+    // Wrap a payload to publish on a topic in an envelope, unless someone on
+    // the topic may predate them.
+    pub(crate) fn seal(&self, topic: &str, kind: Kind, payload: Vec<u8>) -> Vec<u8> {
+        match self.bare(topic) {
+            true => payload,
+            false => message::wrap(kind, payload),
+        }
+    }
+
+    // Whether some member of a topic reads payloads bare, without envelopes.
+    fn bare(&self, topic: &str) -> bool {
+        self.lacking(topic, Capability::Envelope)
+    }
+
+    /// Whether some member of a channel only reads chat messages published
+    /// as a `ChatMessage`, not as a `SignedMessage`.
+    pub(crate) fn predates_signed(&self, channel: &str) -> bool {
+        self.lacking(channel, Capability::Signed)
+    }
+
+    // Whether some member of a topic lacks a capability.
+    fn lacking(&self, topic: &str, capability: Capability) -> bool {
+        self.channel_peers(topic).iter().any(|peer_id| {
+            capabilities::downgrade(*peer_id, self.capabilities.get(peer_id), capability)
+                .is_some()
+        })
+    }
+
+    // Publish the receipts gathered since the last round.
+    fn publish_receipts(&mut self) {
+        for (channel, receipt) in self.receipts.take() {
+            // Members without envelopes would take a receipt for a broken
+            // chat message
+            if self.bare(&channel) {
+                log::debug!("not sending receipts on {}, some members lack envelopes", channel);
+                continue;
+            }
+            let control = Control {
+                receipt: Some(receipt),
+            };
+            let data = message::wrap(Kind::Control, message::encode(&control));
+            if let Err(e) = self.gossipsub.publish(Topic::new(channel.as_str()), data) {
+                log::debug!("failed to send receipts on {}: {:?}", channel, e);
+            }
+        }
+    }
+
+    // Start sending a file, progress is reported through events.
+    pub(crate) fn send_file(&mut self, peer_id: PeerId, path: &Path) -> anyhow::Result<()> {
+        let name = transfer::name_of(path)?;
+        let transfer_id = self.next_transfer_id;
+        self.next_transfer_id += 1;
+        let open = Outgoing::open(peer_id, name.clone(), path.to_owned(), transfer_id);
+        self.file_io.push(
+            async move {
+                match open.await {
+                    Ok(transfer) => read_chunk(transfer).await,
+                    Err(error) => FileIo::NotOpened {
+                        peer_id,
+                        name,
+                        error,
+                    },
+                }
+            }
+            .boxed(),
+        );
+        Ok(())
+    }
+
+    // Send the chunk after an acknowledged one, which took `busy` to go
+    // through, unless it has to make way for chat.
+    fn continue_sending(&mut self, transfer: Outgoing, busy: Duration) {
+        let (done, size) = transfer.progress();
+        let delay = self.schedule.delay(busy, self.last_chat);
+        if done < size && delay > Duration::from_secs(0) {
+            self.held.push_back((Instant::now() + delay, transfer));
+            return;
+        }
+        self.send_next_chunk(transfer)
+    }
+
+    // Let held back transfers go on once their turn came, and wake up again
+    // for the next one.
+    fn release(&mut self, cx: &mut Context<'_>) {
+        let now = Instant::now();
+        let (due, held): (VecDeque<_>, VecDeque<_>) =
+            self.held.drain(..).partition(|(at, _)| *at <= now);
+        self.held = held;
+        if !due.is_empty() {
+            // The requests are only picked up once `files` is polled again
+            cx.waker().wake_by_ref();
+        }
+        for (_, transfer) in due {
+            // They already waited for their share
+            self.continue_sending(transfer, Duration::from_secs(0));
+        }
+        if let Some(next) = self.held.iter().map(|(at, _)| *at).min() {
+            self.held_timer.reset(next.saturating_duration_since(now));
+            if self.held_timer.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+    }
+
+    // Ask the best member of `channel` serving history for the page stored
+    // before the message with digest `before`, the newest one if empty.
+    // `None` if no member we know of serves history.
+    pub(crate) fn get_history(
+        &mut self,
+        channel: &str,
+        before: Vec<u8>,
+        catch_up: bool,
+    ) -> Option<RequestId> {
+        let peer_id = self.history_provider(channel, &[])?;
+        let request = HistoryRequest {
+            channel: channel.to_owned(),
+            before: before.clone(),
+            limit: backfill::PAGE_SIZE,
+        };
+        let request_id = self.history.send_request(&peer_id, request);
+        let fetch = Fetch {
+            id: request_id,
+            channel: channel.to_owned(),
+            before,
+            limit: backfill::PAGE_SIZE,
+            tried: vec![peer_id],
+            sent: Instant::now(),
+            catch_up,
+        };
+        self.fetches.insert(request_id, fetch);
+        Some(request_id)
+    }
+
+    // Ask the next best member for a page of history, handing it back if
+    // there is none left to ask.
+    fn retry_history(&mut self, mut fetch: Fetch) -> Result<(), Fetch> {
+        if fetch.tried.len() >= MAX_ATTEMPTS {
+            return Err(fetch);
+        }
+        let peer_id = match self.history_provider(&fetch.channel, &fetch.tried) {
+            Some(peer_id) => peer_id,
+            None => return Err(fetch),
+        };
+        let request = HistoryRequest {
+            channel: fetch.channel.clone(),
+            before: fetch.before.clone(),
+            limit: fetch.limit,
+        };
+        let request_id = self.history.send_request(&peer_id, request);
+        fetch.tried.push(peer_id);
+        fetch.sent = Instant::now();
+        self.fetches.insert(request_id, fetch);
+        Ok(())
+    }
+
+    // The member of `channel` serving history expected to answer the soonest,
+    // other than those `tried` already, if we know one.
+    pub(crate) fn history_provider(&self, channel: &str, tried: &[PeerId]) -> Option<PeerId> {
+        let topic = Topic::new(channel).hash();
+        let candidates = self
+            .gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&topic))
+            .map(|(peer_id, _)| *peer_id)
+            .filter(|peer_id| !self.moderation.is_blocked(peer_id))
+            .filter(|peer_id| {
+                let capabilities = self.capabilities.get(peer_id);
+                capabilities.is_some_and(|c| c.contains(&Capability::ServesHistory))
+            });
+        self.providers.best(candidates, tried, &self.latency)
+    }
+
+    // Ask for the newest page of history of the channels we joined, once a
+    // member serving it shows up.
+    fn catch_up(&mut self) {
+        let ready: Vec<String> = self
+            .catch_up
+            .iter()
+            .filter(|channel| self.history_provider(channel, &[]).is_some())
+            .cloned()
+            .collect();
+        for channel in ready {
+            self.catch_up.remove(&channel);
+            self.get_history(&channel, Vec::new(), true);
+        }
+    }
+
+    // Answer a peer asking for history, reading it away from the swarm.
+    fn serve_history(
+        &mut self,
+        peer_id: PeerId,
+        request: HistoryRequest,
+        channel: ResponseChannel<HistoryResponse>,
+    ) {
+        // Leave blocked peers waiting for an answer that never comes
+        if self.moderation.is_blocked(&peer_id) {
+            return;
+        }
+        let history = match &self.stored {
+            Some(history) => history.clone(),
+            None => return,
+        };
+        let refusal = match self.history_limiter.check(peer_id) {
+            Verdict::Throttle | Verdict::Drop => Some("too many history requests"),
+            // Nor tell what channels we are in
+            _ if !self.channels.contains(&request.channel) => Some("not a member of the channel"),
+            _ => None,
+        };
+        if let Some(error) = refusal {
+            log::debug!("refusing history of {} to {}: {}", request.channel, peer_id, error);
+            if self.history.send_response(channel, backfill::refusal(error)).is_err() {
+                log::debug!("{} went away before we refused its history request", peer_id);
+            }
+            return;
+        }
+        let page = unblock(move || backfill::serve(&history, &request));
+        self.serving.push(page.map(|page| (channel, page)).boxed());
+    }
+
+    // Hand a page of history we asked for to the user.
+    fn receive_history(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        response: HistoryResponse,
+    ) {
+        let fetch = match self.fetches.remove(&request_id) {
+            Some(fetch) => fetch,
+            None => return,
+        };
+        if !response.error.is_empty() {
+            return self.history_failed(peer_id, fetch, response.error);
+        }
+        let more = response.messages.len() == fetch.limit as usize;
+        let bytes = response.messages.iter().map(Vec::len).sum();
+        let elapsed = fetch.sent.elapsed();
+        let messages = match backfill::check(&fetch.channel, fetch.limit, response) {
+            Some(messages) => messages,
+            None => {
+                let error = String::from("sent more messages than asked for");
+                return self.history_failed(peer_id, fetch, error);
+            }
+        };
+        self.providers.answered(peer_id, bytes, elapsed);
+        let heard = |author: Option<PeerId>| {
+            author.is_none_or(|a| !self.moderation.is_blocked(&a) && !self.moderation.is_muted(&a))
+        };
+        let messages = messages.into_iter().filter(|m| heard(m.author())).collect();
+        self.events.push_back(NodeEvent::History {
+            peer_id,
+            request_id: fetch.id,
+            channel: fetch.channel,
+            messages,
+            more,
+        });
+    }
+
+    // Ask the next member for a page of history `peer_id` failed to send,
+    // or report it once there is none left. Catching up is tried again with
+    // the next member to show up instead.
+    fn history_failed(&mut self, peer_id: PeerId, fetch: Fetch, error: String) {
+        log::debug!("{} failed to send history of {}: {}", peer_id, fetch.channel, error);
+        self.providers.failed(peer_id);
+        let fetch = match self.retry_history(fetch) {
+            Ok(()) => return,
+            Err(fetch) => fetch,
+        };
+        if fetch.catch_up {
+            log::debug!("failed to catch up on {} with {}: {}", fetch.channel, peer_id, error);
+            if self.channels.contains(&fetch.channel) {
+                self.catch_up.insert(fetch.channel);
+            }
+            return;
+        }
+        self.events.push_back(NodeEvent::HistoryFailed {
+            peer_id,
+            request_id: fetch.id,
+            channel: fetch.channel,
+            error,
+        });
+    }
+
+    // Record that a chat or direct message went by, see `schedule`.
+    pub(crate) fn chatted(&mut self) {
+        self.last_chat = Some(Instant::now());
+    }
+
+    // Send the next chunk of a file, or report it as sent.
+    fn send_next_chunk(&mut self, transfer: Outgoing) {
+        self.file_io.push(read_chunk(transfer).boxed());
+    }
+
+    // Go on with a file operation that is done.
+    fn file_io_done(&mut self, done: FileIo) {
+        match done {
+            FileIo::NotOpened {
+                peer_id,
+                name,
+                error,
+            } => self.events.push_back(NodeEvent::TransferFailed {
+                peer_id,
+                name,
+                direction: Direction::Sending,
+                error: format!("{:#}", error),
+            }),
+            FileIo::Read {
+                transfer,
+                before,
+                chunk,
+            } => self.chunk_read(transfer, before, chunk),
+            FileIo::Written {
+                key,
+                transfer,
+                before,
+                channel,
+                complete,
+            } => self.chunk_written(key, transfer, before, channel, complete),
+            FileIo::Finished {
+                key: (peer_id, transfer_id),
+                name,
+                channel,
+                path,
+            } => {
+                self.writing.remove(&(peer_id, transfer_id));
+                let ack = match path {
+                    Ok(path) => {
+                        self.events.push_back(NodeEvent::FileReceived {
+                            peer_id,
+                            name,
+                            path,
+                        });
+                        FileAck::default()
+                    }
+                    Err(e) => self.refuse(peer_id, &name, &format!("{:#}", e)),
+                };
+                self.answer(peer_id, channel, ack);
+            }
+        }
+    }
+
+    // Send a chunk that was read, or report the file as sent.
+    fn chunk_read(
+        &mut self,
+        transfer: Outgoing,
+        before: u64,
+        chunk: std::io::Result<Option<FileChunk>>,
+    ) {
+        match chunk {
+            Ok(Some(chunk)) => {
+                let (done, size) = transfer.progress();
+                if transfer::tenths(done, size) > before {
+                    self.events.push_back(NodeEvent::TransferProgress {
+                        peer_id: transfer.peer_id,
+                        name: transfer.name.clone(),
+                        direction: Direction::Sending,
+                        done,
+                        size,
+                    });
+                }
+                let request_id = self.files.send_request(&transfer.peer_id, chunk);
+                self.outgoing.insert(request_id, transfer);
+            }
+            Ok(None) => self.events.push_back(NodeEvent::FileSent {
+                peer_id: transfer.peer_id,
+                name: transfer.name,
+            }),
+            Err(e) => self.events.push_back(NodeEvent::TransferFailed {
+                peer_id: transfer.peer_id,
+                name: transfer.name,
+                direction: Direction::Sending,
+                error: e.to_string(),
+            }),
+        }
+    }
+
+    // Store a received chunk, answering once it is written, or right away
+    // with why the transfer failed if it did.
+    fn receive_chunk(
+        &mut self,
+        peer_id: PeerId,
+        chunk: FileChunk,
+        channel: ResponseChannel<FileAck>,
+    ) {
+        if self.moderation.is_blocked(&peer_id) {
+            let ack = FileAck {
+                error: String::from("not accepting files from you"),
+            };
+            return self.answer(peer_id, channel, ack);
+        }
+        let key = (peer_id, chunk.transfer_id);
+        let transfer = match self.incoming.remove(&key) {
+            Some(transfer) => transfer,
+            None if self.writing.contains(&key) => {
+                let ack = FileAck {
+                    error: String::from("chunk sent before the previous one was acknowledged"),
+                };
+                return self.answer(peer_id, channel, ack);
+            }
+            None => match self.start_receiving(peer_id, &chunk) {
+                Ok(transfer) => transfer,
+                Err(e) => {
+                    let ack = self.refuse(peer_id, &chunk.name, &e);
+                    return self.answer(peer_id, channel, ack);
+                }
+            },
+        };
+        self.writing.insert(key);
+        let before = transfer::tenths(transfer.progress().0, transfer.progress().1);
+        let write = transfer.write(chunk).map(move |(transfer, complete)| FileIo::Written {
+            key,
+            transfer,
+            before,
+            channel,
+            complete,
+        });
+        self.file_io.push(write.boxed());
+    }
+
+    // Take in a new incoming file, if it is welcome.
+    fn start_receiving(&self, peer_id: PeerId, chunk: &FileChunk) -> Result<Incoming, String> {
+        let dir = match &self.download_dir {
+            Some(dir) => dir,
+            None => return Err(String::from("not accepting files")),
+        };
+        if self.incoming.len() + self.writing.len() >= transfer::MAX_INCOMING {
+            return Err(String::from("receiving too many files at once, try again later"));
+        }
+        Incoming::start(dir, peer_id, chunk, self.max_file_size).map_err(|e| format!("{:#}", e))
+    }
+
+    // Go on with a received chunk that was written.
+    fn chunk_written(
+        &mut self,
+        key: (PeerId, u64),
+        transfer: Incoming,
+        before: u64,
+        channel: ResponseChannel<FileAck>,
+        complete: anyhow::Result<bool>,
+    ) {
+        let peer_id = key.0;
+        match complete {
+            Ok(false) => {
+                let (done, size) = transfer.progress();
+                if transfer::tenths(done, size) > before {
+                    self.events.push_back(NodeEvent::TransferProgress {
+                        peer_id,
+                        name: transfer.name.clone(),
+                        direction: Direction::Receiving,
+                        done,
+                        size,
+                    });
+                }
+                self.writing.remove(&key);
+                self.incoming.insert(key, transfer);
+                self.answer(peer_id, channel, FileAck::default());
+            }
+            Ok(true) => {
+                let name = transfer.name.clone();
+                let finish = transfer.finish().map(move |path| FileIo::Finished {
+                    key,
+                    name,
+                    channel,
+                    path,
+                });
+                self.file_io.push(finish.boxed());
+            }
+            Err(e) => {
+                self.writing.remove(&key);
+                let name = transfer.name.clone();
+                transfer.abort();
+                let ack = self.refuse(peer_id, &name, &format!("{:#}", e));
+                self.answer(peer_id, channel, ack);
+            }
+        }
+    }
+
+    // Give up on the files whose sender went quiet.
+    fn expire_transfers(&mut self) {
+        let idle: Vec<_> = self
+            .incoming
+            .iter()
+            .filter(|(_, transfer)| transfer.is_idle())
+            .map(|(key, _)| *key)
+            .collect();
+        for key in idle {
+            let transfer = self.incoming.remove(&key).expect("idle transfer");
+            let name = transfer.name.clone();
+            transfer.abort();
+            self.refuse(key.0, &name, "the sender went quiet");
+        }
+    }
+
+    fn answer(&mut self, peer_id: PeerId, channel: ResponseChannel<FileAck>, ack: FileAck) {
+        if self.files.send_response(channel, ack).is_err() {
+            log::debug!("{} went away before we acknowledged a file chunk", peer_id);
+        }
+    }
+
+    fn refuse(&mut self, peer_id: PeerId, name: &str, error: &str) -> FileAck {
+        self.events.push_back(NodeEvent::TransferFailed {
+            peer_id,
+            name: name.to_owned(),
+            direction: Direction::Receiving,
+            error: error.to_owned(),
+        });
+        FileAck {
+            error: error.to_owned(),
+        }
+    }
+
+    fn dial(&mut self, peer_id: PeerId) {
+        if peer_id != self.local_peer_id && !self.to_dial.contains(&peer_id) {
+            self.to_dial.push_back(peer_id);
+        }
+    }
+
+    // Send the direct messages that waited for a peer to come back.
+    fn deliver(&mut self, peer_id: PeerId) {
+        for letter in self.outbox.take(&peer_id) {
+            log::debug!("sending waiting message to {} again", peer_id);
+            let request_id = self.direct.send_request(&peer_id, letter.message.clone());
+            self.outbox.resent(request_id, letter);
+        }
+    }
+
+    // Check a gossipsub message `id` forwarded by `propagation_source` and
+    // hand it to the user if it is valid. Gives nothing back for a fragment
+    // held until the rest of its message arrives.
+    fn receive(
+        &mut self,
+        id: MessageId,
+        propagation_source: PeerId,
+        message: GossipsubMessage,
+    ) -> Option<MessageAcceptance> {
+        // Nor pass on anything from blocked peers
+        if let Some(source) = &message.source {
+            if self.moderation.is_blocked(source) {
+                return Some(MessageAcceptance::Ignore);
+            }
+        }
+        let presence = message.topic.as_str() == presence::TOPIC;
+        let bare = if presence { Kind::Presence } else { Kind::Chat };
+        // Leave what a newer version sent to those who understand it
+        let (kind, payload) = match message::unwrap(message.data, bare) {
+            Some(unwrapped) => unwrapped,
+            None => return Some(MessageAcceptance::Ignore),
+        };
+        let acceptance = match kind {
+            Kind::Presence if presence => self.receive_announcement(message.source, &payload),
+            Kind::Chat | Kind::Signed if !presence => {
+                return self.receive_chat(
+                    (id, propagation_source),
+                    message.source,
+                    &message.topic,
+                    kind,
+                    &payload,
+                );
+            }
+            Kind::Control if !presence => {
+                self.receive_control(message.source, &message.topic, &payload)
+            }
+            Kind::Control => {
+                log::debug!("skipping control message on {}", message.topic);
+                MessageAcceptance::Ignore
+            }
+            _ => MessageAcceptance::Reject,
+        };
+        Some(acceptance)
+    }
+
+    fn receive_chat(
+        &mut self,
+        held: (MessageId, PeerId),
+        source: Option<PeerId>,
+        topic: &TopicHash,
+        kind: Kind,
+        payload: &[u8],
+    ) -> Option<MessageAcceptance> {
+        let fragment = match Published::decode(kind, payload) {
+            Some(Chat::Whole(m)) => return Some(self.accept_chat(source, topic, m, false)),
+            Some(Chat::Part(fragment)) => fragment,
+            None => return Some(MessageAcceptance::Reject),
+        };
+        let source = match source {
+            Some(source) => source,
+            None => return Some(MessageAcceptance::Reject),
+        };
+        // Check what we can before keeping a piece
+        if fragment.channel != topic.as_str() {
+            log::debug!("dropping fragment tagged {:?} on {}", fragment.channel, topic);
+            return Some(MessageAcceptance::Reject);
+        }
+        if self.throttled(source) {
+            return Some(MessageAcceptance::Ignore);
+        }
+        // Pieces are only passed on along with the whole message they make up,
+        // once it was checked
+        let (bytes, held) = match self.fragments.add(source, fragment, held) {
+            Ok(None) => return None,
+            Ok(Some(whole)) => whole,
+            Err(e) => {
+                log::debug!("dropping message fragment from {}: {}", source, e);
+                return Some(MessageAcceptance::Reject);
+            }
+        };
+        let acceptance = match Published::decode(kind, &bytes) {
+            Some(Chat::Whole(m)) => self.accept_chat(Some(source), topic, m, true),
+            _ => MessageAcceptance::Reject,
+        };
+        for (id, propagation_source) in &held {
+            self.report(id, propagation_source, copy(&acceptance));
+        }
+        None
+    }
+
+    // Check a whole chat message, that took its tokens already if it came
+    // in fragments.
+    fn accept_chat(
+        &mut self,
+        source: Option<PeerId>,
+        topic: &TopicHash,
+        m: Box<Published>,
+        charged: bool,
+    ) -> MessageAcceptance {
+        // The topic is authoritative, don't let a message claim to belong to
+        // another channel
+        if m.channel != topic.as_str() {
+            log::debug!("dropping message tagged {:?} on {}", m.channel, topic);
+            return MessageAcceptance::Reject;
+        }
+        if let Some(owner) = broadcast::owner(&m.channel) {
+            if !broadcast::verify(&m, &owner) {
+                log::debug!("dropping message not signed by the owner of {}", m.channel);
+                return MessageAcceptance::Reject;
+            }
+        }
+        // Only the publisher's own key can vouch for who wrote a message
+        if m.author() != source {
+            log::debug!("dropping message whose author is not its source");
+            return MessageAcceptance::Reject;
+        }
+        // Older versions don't sign messages, only let them through unless
+        // strict, but never let a bad signature through
+        if !m.is_signed() {
+            if self.strict {
+                log::debug!("dropping unsigned message in strict mode");
+                return MessageAcceptance::Ignore;
+            }
+        } else if !m.verify() {
+            log::debug!("dropping message with a bad author signature");
+            return MessageAcceptance::Reject;
+        }
+        if !self.seen.insert(m.digest()) {
+            log::debug!("dropping copy of message {} in {}", m.id(), m.channel);
+            self.metrics.dedup_hit();
+            return MessageAcceptance::Ignore;
+        }
+        self.metrics.dedup_miss();
+        // Don't let a flood through, nor pass it on, but only count each
+        // message once however many peers forward it
+        if let Some(author) = m.author() {
+            if !charged && self.throttled(author) {
+                return MessageAcceptance::Ignore;
+            }
+        }
+        self.chatted();
+        self.metrics.message_received(&m.channel);
+        let gap = match source {
+            Some(source) => {
+                self.names.insert(m.display_name.clone(), source);
+                self.chains
+                    .insert((source, m.channel.clone()), m.digest())
+                    .is_some_and(|last| last != m.prev)
+            }
+            None => false,
+        };
+        let order = match source {
+            Some(source) => self.arrivals.arrive(source, &m),
+            None => Order::InOrder,
+        };
+        let muted = source.is_some_and(|source| self.moderation.is_muted(&source));
+        // Muting stays unnoticed, but a muted peer's messages are never read
+        if self.send_receipts && broadcast::owner(&m.channel).is_none() {
+            self.receipts.got(&m.channel, m.digest(), muted);
+        }
+        // Others may still want muted peers' messages
+        if muted {
+            return MessageAcceptance::Accept;
+        }
+        self.events.push_back(NodeEvent::Message {
+            source,
+            message: m,
+            gap,
+            order,
+        });
+        MessageAcceptance::Accept
+    }
+
+    fn receive_control(
+        &mut self,
+        source: Option<PeerId>,
+        topic: &TopicHash,
+        payload: &[u8],
+    ) -> MessageAcceptance {
+        let control = match Control::decode(payload) {
+            Ok(control) => control,
+            Err(_) => return MessageAcceptance::Reject,
+        };
+        let (source, receipt) = match (source, control.receipt) {
+            (Some(source), Some(receipt)) => (source, receipt),
+            _ => {
+                log::debug!("skipping control message on {}", topic);
+                return MessageAcceptance::Ignore;
+            }
+        };
+        // Listeners of a broadcast channel have nothing to say on it
+        if broadcast::owner(topic.as_str()).is_some() {
+            return MessageAcceptance::Ignore;
+        }
+        let updates = match self.receipts.receive(source, topic.as_str(), &receipt) {
+            Some(updates) => updates,
+            None => return MessageAcceptance::Reject,
+        };
+        for update in updates {
+            self.events.push_back(NodeEvent::Receipt {
+                id: update.id,
+                peer_id: update.peer_id,
+                read: update.read,
+                delivered_to: update.delivered_to,
+                read_by: update.read_by,
+            });
+        }
+        MessageAcceptance::Accept
+    }
+
+    fn receive_announcement(
+        &mut self,
+        source: Option<PeerId>,
+        payload: &[u8],
+    ) -> MessageAcceptance {
+        let decoded = Announcement::decode(payload);
+        let (announcement, source) = match (decoded, source) {
+            (Ok(announcement), Some(source)) => (announcement, source),
+            _ => return MessageAcceptance::Reject,
+        };
+        match Status::from_i32(announcement.status) {
+            Some(Status::Leave) => {
+                if let Some(presence) = self.roster.remove(&source) {
+                    self.events.push_back(NodeEvent::PeerOffline {
+                        peer_id: source,
+                        display_name: presence.display_name,
+                    });
+                }
+            }
+            Some(status) => {
+                self.names.insert(announcement.display_name.clone(), source);
+                if self.roster.seen(source, announcement.display_name.clone()) {
+                    self.events.push_back(NodeEvent::PeerOnline {
+                        peer_id: source,
+                        display_name: announcement.display_name,
+                    });
+                }
+                // Let the newcomer know about us without waiting a round
+                if status == Status::Join {
+                    self.announce(Status::Alive);
+                }
+            }
+            None => return MessageAcceptance::Reject,
+        }
+        MessageAcceptance::Accept
+    }
+
+    // Take a token for a message from `peer_id`, telling whether it is to be
+    // dropped.
+    fn throttled(&mut self, peer_id: PeerId) -> bool {
+        match self.limiter.check(peer_id) {
+            Verdict::Allow => false,
+            Verdict::Resume { dropped } => {
+                self.events.push_back(NodeEvent::Unthrottled { peer_id, dropped });
+                false
+            }
+            Verdict::Throttle => {
+                log::debug!("throttling {}", peer_id);
+                self.events.push_back(NodeEvent::Throttled { peer_id });
+                true
+            }
+            Verdict::Drop => true,
+        }
+    }
+
+    // Tell gossipsub whether to forward a message, if it is waiting for us to.
+    fn report(&mut self, id: &MessageId, source: &PeerId, acceptance: MessageAcceptance) {
+        if !self.validate_messages {
+            return;
+        }
+        // Delivered already, but going no further
+        let acceptance = match acceptance {
+            MessageAcceptance::Accept if self.leaf => MessageAcceptance::Ignore,
+            acceptance => acceptance,
+        };
+        if let Err(e) = self
+            .gossipsub
+            .report_message_validation_result(id, source, acceptance)
+        {
+            log::debug!("failed to report validation of message {}: {:?}", id, e);
+        }
+    }
+}
+
+// The same verdict again, for every fragment of a message.
+fn copy(acceptance: &MessageAcceptance) -> MessageAcceptance {
+    match acceptance {
+        MessageAcceptance::Accept => MessageAcceptance::Accept,
+        MessageAcceptance::Reject => MessageAcceptance::Reject,
+        MessageAcceptance::Ignore => MessageAcceptance::Ignore,
+    }
+}
+
+// A page of history read for a peer, and where to send it.
+type Serving = (ResponseChannel<HistoryResponse>, HistoryResponse);
+
+// A file operation that was done away from the swarm, with what is needed
+// to go on with the transfer.
+enum FileIo {
+    NotOpened {
+        peer_id: PeerId,
+        name: String,
+        error: anyhow::Error,
+    },
+    // The next chunk of a file we send, `before` tenths of it were sent
+    Read {
+        transfer: Outgoing,
+        before: u64,
+        chunk: std::io::Result<Option<FileChunk>>,
+    },
+    // A chunk we received, `before` tenths of the file were received
+    Written {
+        key: (PeerId, u64),
+        transfer: Incoming,
+        before: u64,
+        channel: ResponseChannel<FileAck>,
+        complete: anyhow::Result<bool>,
+    },
+    Finished {
+        key: (PeerId, u64),
+        name: String,
+        channel: ResponseChannel<FileAck>,
+        path: anyhow::Result<PathBuf>,
+    },
+}
+
+// Read the next chunk of a file we send.
+fn read_chunk(transfer: Outgoing) -> impl Future<Output = FileIo> {
+    let before = transfer::tenths(transfer.progress().0, transfer.progress().1);
+    transfer.next_chunk().map(move |(transfer, chunk)| FileIo::Read {
+        transfer,
+        before,
+        chunk,
+    })
+}
+
+// DHT key under which every member of a channel announces itself.
+fn provider_key(channel: &str) -> Key {
+    Key::new(&format!("pingpong-p2p/topic/{}", channel))
+}
+
+impl NetworkBehaviourEventProcess<GossipsubEvent> for MyBehaviour {
+    // Called when `gossipsub` produces an event.
+    fn inject_event(&mut self, event: GossipsubEvent) {
+        match event {
+            GossipsubEvent::Message {
+                propagation_source,
+                message_id,
+                message,
+            } => {
+                let id = message_id.clone();
+                if let Some(acceptance) = self.receive(id, propagation_source, message) {
+                    self.report(&message_id, &propagation_source, acceptance);
+                }
+            }
+            GossipsubEvent::Subscribed { peer_id, topic } => {
+                // Before the next heartbeat can graft it
+                self.rank(peer_id);
+                if topic.as_str() == presence::TOPIC {
+                    // Our join may have gone out before anyone listened
+                    if !self.joined {
+                        self.announce(Status::Join);
+                    }
+                    return;
+                }
+                self.events.push_back(NodeEvent::PeerJoined {
+                    peer_id,
+                    channel: topic.into_string(),
+                });
+                self.catch_up();
+            }
+            GossipsubEvent::Unsubscribed { peer_id, topic } => {
+                if topic.as_str() == presence::TOPIC {
+                    if let Some(presence) = self.roster.remove(&peer_id) {
+                        self.events.push_back(NodeEvent::PeerOffline {
+                            peer_id,
+                            display_name: presence.display_name,
+                        });
+                    }
+                    return;
+                }
+                self.events.push_back(NodeEvent::PeerLeft {
+                    peer_id,
+                    channel: topic.into_string(),
+                })
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
+    // Called when `mdns` produces an event.
+    fn inject_event(&mut self, event: MdnsEvent) {
+        match event {
+            // Gossipsub builds its mesh from connected peers, so connect to
+            // everyone we discover; expired peers drop out once disconnected.
+            MdnsEvent::Discovered(list) => {
+                for (peer, addr) in list {
+                    self.kademlia.add_address(&peer, addr);
+                    self.dial(peer);
+                }
+            }
+            MdnsEvent::Expired(_) => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<KademliaEvent> for MyBehaviour {
+    // Called when `kademlia` produces an event.
+    fn inject_event(&mut self, event: KademliaEvent) {
+        if let KademliaEvent::QueryResult {
+            result: QueryResult::GetProviders(Ok(ok)),
+            ..
+        } = event
+        {
+            for peer in ok.providers {
+                self.dial(peer);
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<DirectMessage, DirectAck>> for MyBehaviour {
+    // Called when `direct` produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<DirectMessage, DirectAck>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                // Leave blocked peers waiting for an answer that never comes
+                if self.moderation.is_blocked(&peer) {
+                    return;
+                }
+                // Acknowledge right away, the message is handed to the user
+                // with the next event
+                if self.direct.send_response(channel, DirectAck {}).is_err() {
+                    log::debug!("{} went away before we acknowledged its message", peer);
+                }
+                // Sent again as our acknowledgement did not make it
+                if !self.received.first(peer, request.id) {
+                    log::debug!("dropped a copy of a direct message from {}", peer);
+                    return;
+                }
+                if self.moderation.is_muted(&peer) {
+                    return;
+                }
+                self.names.insert(request.display_name.clone(), peer);
+                self.chatted();
+                self.standby.messaged(peer);
+                self.events.push_back(NodeEvent::DirectMessage {
+                    peer_id: peer,
+                    message: request,
+                });
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response { request_id, .. },
+            } => self.events.push_back(NodeEvent::Delivered {
+                peer_id: peer,
+                request_id: self.outbox.delivered(request_id),
+            }),
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                let event = match self.outbox.failed(peer, request_id, error) {
+                    Failure::Queued(request_id) => NodeEvent::Queued {
+                        peer_id: peer,
+                        request_id,
+                    },
+                    Failure::Failed(request_id, error) => NodeEvent::NotDelivered {
+                        peer_id: peer,
+                        request_id,
+                        error,
+                    },
+                };
+                self.events.push_back(event);
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                log::debug!("failed to receive direct message from {}: {:?}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<PingEvent> for MyBehaviour {
+    // Called when `ping` produces an event.
+    fn inject_event(&mut self, event: PingEvent) {
+        let peer_id = event.peer;
+        match event.result {
+            Ok(PingSuccess::Ping { rtt }) => {
+                self.latency.record(peer_id, rtt);
+                if self.pending_pings.remove(&peer_id) {
+                    self.events.push_back(NodeEvent::Pong { peer_id, rtt });
+                }
+            }
+            Ok(PingSuccess::Pong) => {}
+            Err(error) => {
+                self.latency.remove(&peer_id);
+                if self.pending_pings.remove(&peer_id) {
+                    let error = error.to_string();
+                    self.events.push_back(NodeEvent::PingFailed { peer_id, error });
+                }
+            }
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<IdentifyEvent> for MyBehaviour {
+    // Called when `identify` produces an event.
+    fn inject_event(&mut self, event: IdentifyEvent) {
+        if let IdentifyEvent::Received {
+            peer_id,
+            info,
+            observed_addr,
+        } = event
+        {
+            let peer_info = PeerInfo {
+                agent_version: info.agent_version.clone(),
+                protocol_version: info.protocol_version.clone(),
+                protocols: info.protocols.clone(),
+                listen_addrs: info.listen_addrs.clone(),
+                observed_addr,
+            };
+            self.peer_info.insert(peer_id, peer_info);
+            if info.protocol_version != PROTOCOL_VERSION {
+                return;
+            }
+            let capabilities = match capabilities::parse_agent_version(&info.agent_version) {
+                Some(capabilities) => capabilities,
+                None => return,
+            };
+            // Connected again, send what waited for it
+            self.deliver(peer_id);
+            // Trust the addresses a peer listens on over the ones we happened
+            // to reach it through
+            for addr in info.listen_addrs {
+                self.kademlia.add_address(&peer_id, addr);
+            }
+            // Identify runs on every connection, only report news
+            if self.capabilities.get(&peer_id) == Some(&capabilities) {
+                return;
+            }
+            self.capabilities.insert(peer_id, capabilities.clone());
+            self.events.push_back(NodeEvent::PeerIdentified {
+                peer_id,
+                agent_version: info.agent_version,
+                capabilities,
+            });
+            self.catch_up();
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<FileChunk, FileAck>> for MyBehaviour {
+    // Called when `files` produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<FileChunk, FileAck>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => {
+                self.receive_chunk(peer, request, channel);
+            }
+            RequestResponseEvent::Message {
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+                ..
+            } => {
+                let transfer = match self.outgoing.remove(&request_id) {
+                    Some(transfer) => transfer,
+                    None => return,
+                };
+                if response.error.is_empty() {
+                    let busy = transfer.busy();
+                    self.continue_sending(transfer, busy);
+                } else {
+                    self.events.push_back(NodeEvent::TransferFailed {
+                        peer_id: transfer.peer_id,
+                        name: transfer.name,
+                        direction: Direction::Sending,
+                        error: response.error,
+                    });
+                }
+            }
+            RequestResponseEvent::OutboundFailure {
+                request_id, error, ..
+            } => {
+                if let Some(transfer) = self.outgoing.remove(&request_id) {
+                    self.events.push_back(NodeEvent::TransferFailed {
+                        peer_id: transfer.peer_id,
+                        name: transfer.name,
+                        direction: Direction::Sending,
+                        error: format!("{:?}", error),
+                    });
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                log::debug!("failed to receive file chunk from {}: {:?}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<RequestResponseEvent<HistoryRequest, HistoryResponse>>
+    for MyBehaviour
+{
+    // Called when `history` produces an event.
+    fn inject_event(&mut self, event: RequestResponseEvent<HistoryRequest, HistoryResponse>) {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Request {
+                        request, channel, ..
+                    },
+            } => self.serve_history(peer, request, channel),
+            RequestResponseEvent::Message {
+                peer,
+                message:
+                    RequestResponseMessage::Response {
+                        request_id,
+                        response,
+                    },
+            } => self.receive_history(peer, request_id, response),
+            RequestResponseEvent::OutboundFailure {
+                peer,
+                request_id,
+                error,
+            } => {
+                if let Some(fetch) = self.fetches.remove(&request_id) {
+                    self.history_failed(peer, fetch, format!("{:?}", error));
+                }
+            }
+            RequestResponseEvent::InboundFailure { peer, error, .. } => {
+                log::debug!("failed to answer history request of {}: {:?}", peer, error)
+            }
+            RequestResponseEvent::ResponseSent { .. } => {}
+        }
+    }
+}
+
+impl NetworkBehaviourEventProcess<Infallible> for MyBehaviour {
+    // `tracker` and `recorder` produce no events.
+    fn inject_event(&mut self, event: Infallible) {
+        match event {}
+    }
+}
+
+impl NetworkBehaviourEventProcess<BootstrapEvent> for MyBehaviour {
+    // Called when `bootstrapper` produces an event.
+    fn inject_event(&mut self, event: BootstrapEvent) {
+        // Look around with whatever we have, mDNS peers at least if none
+        // of the entry points answered
+        self.discover();
+        self.events.push_back(match event {
+            BootstrapEvent::Reached {
+                peer_id,
+                address,
+                elapsed,
+            } => NodeEvent::Bootstrapped {
+                peer_id,
+                address,
+                elapsed,
+            },
+            BootstrapEvent::Failed { tried } => NodeEvent::BootstrapFailed { tried },
+        });
+    }
+}
+
+impl NetworkBehaviourEventProcess<RedialEvent> for MyBehaviour {
+    // Called when `redialer` produces an event.
+    fn inject_event(&mut self, event: RedialEvent) {
+        self.events.push_back(match event {
+            RedialEvent::Scheduled {
+                address,
+                attempt,
+                delay,
+            } => NodeEvent::Redialing {
+                address,
+                attempt,
+                delay,
+            },
+            RedialEvent::GaveUp { address, attempts } => {
+                NodeEvent::Unreachable { address, attempts }
+            }
+        });
+    }
+}
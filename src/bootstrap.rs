@@ -0,0 +1,156 @@
+//! Reaching the network on startup.
+//!
+//! Every entry point we were given, addresses to dial and bootstrap peers
+//! alike, is dialed at once rather than one after another, and the first one
+//! to answer is enough to go on: the DHT is bootstrapped and channel members
+//! are looked for through it right away, without waiting for slow or
+//! unreachable entry points. Those finish or fail on their own, and are left
+//! to the redialer.
+
+use core::task::{Context as TaskContext, Poll};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use libp2p::{
+    core::{connection::ConnectionId, ConnectedPoint},
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, PeerId,
+};
+
+use crate::{redial, Config};
+
+/// How the race to the first entry point went.
+#[derive(Debug)]
+pub(crate) enum BootstrapEvent {
+    /// `address` answered first, `elapsed` after we started dialing.
+    Reached {
+        peer_id: PeerId,
+        address: Multiaddr,
+        elapsed: Duration,
+    },
+    /// None of the `tried` entry points could be reached.
+    Failed { tried: usize },
+}
+
+/// Dials the entry points all at once and tells which one answered first,
+/// without taking part in any protocol.
+pub(crate) struct Bootstrapper {
+    // Entry points not dialed yet
+    to_dial: VecDeque<Multiaddr>,
+    // Entry points we are still waiting to hear from, without peer ids
+    waiting: Vec<Multiaddr>,
+    tried: usize,
+    started: Instant,
+    events: VecDeque<BootstrapEvent>,
+}
+
+impl Bootstrapper {
+    pub(crate) fn new(config: &Config) -> Self {
+        let bootstrap = config.bootstrap.iter().map(|(_, address)| address);
+        let mut waiting: Vec<Multiaddr> = Vec::new();
+        for address in config.dial.iter().chain(bootstrap) {
+            // Transports can't dial addresses ending with a peer id
+            let address = redial::without_peer(address);
+            if !waiting.contains(&address) {
+                waiting.push(address);
+            }
+        }
+        Bootstrapper {
+            to_dial: waiting.iter().cloned().collect(),
+            tried: waiting.len(),
+            waiting,
+            started: Instant::now(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Whether there are entry points to wait for.
+    pub(crate) fn is_racing(&self) -> bool {
+        !self.waiting.is_empty()
+    }
+}
+
+impl NetworkBehaviour for Bootstrapper {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = BootstrapEvent;
+
+    fn new_handler(&mut self) -> DummyProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _: &PeerId) {}
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        let address = match endpoint {
+            ConnectedPoint::Dialer { address } => redial::without_peer(address),
+            ConnectedPoint::Listener { .. } => return,
+        };
+        if self.waiting.contains(&address) {
+            // The race is won, the others don't matter any more
+            self.waiting.clear();
+            self.to_dial.clear();
+            self.events.push_back(BootstrapEvent::Reached {
+                peer_id: *peer_id,
+                address,
+                elapsed: self.started.elapsed(),
+            });
+        }
+    }
+
+    fn inject_addr_reach_failure(&mut self, _: Option<&PeerId>, addr: &Multiaddr, _: &dyn Error) {
+        let address = redial::without_peer(addr);
+        let before = self.waiting.len();
+        self.waiting.retain(|waiting| *waiting != address);
+        if before > 0 && self.waiting.is_empty() && self.to_dial.is_empty() {
+            self.events.push_back(BootstrapEvent::Failed { tried: self.tried });
+        }
+    }
+
+    fn inject_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: <DummyProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut TaskContext<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<
+            <DummyProtocolsHandler as ProtocolsHandler>::InEvent,
+            BootstrapEvent,
+        >,
+    > {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+        // The swarm polls us again right away, so every address is dialed
+        // before any of them can answer
+        if let Some(address) = self.to_dial.pop_front() {
+            log::debug!("dialing entry point {}", address);
+            return Poll::Ready(NetworkBehaviourAction::DialAddress { address });
+        }
+        Poll::Pending
+    }
+}
@@ -0,0 +1,40 @@
+//! Read-only broadcast channels.
+//!
+//! A broadcast channel is named `<name>@<OWNER_PEER_ID>`. Every message
+//! published on it must carry a signature by the owner key, which members
+//! recover from the peer id itself, so no key needs to be distributed
+//! out of band. Any other channel name is an ordinary, open channel.
+
+use libp2p::{identity::PublicKey, PeerId};
+
+use crate::Published;
+
+// Multihash code of the identity hash, used by peer ids that inline their key
+const IDENTITY_HASH: u64 = 0x00;
+
+/// Name of the broadcast channel `name` owned by `owner`.
+pub fn channel_name(name: &str, owner: &PeerId) -> String {
+    format!("{}@{}", name, owner)
+}
+
+/// The key allowed to publish on `channel`, if it is a broadcast channel.
+pub(crate) fn owner(channel: &str) -> Option<PublicKey> {
+    let (_, owner) = channel.rsplit_once('@')?;
+    let owner: PeerId = owner.parse().ok()?;
+    let multihash = owner.as_ref();
+    if multihash.code() != IDENTITY_HASH {
+        return None;
+    }
+    PublicKey::from_protobuf_encoding(multihash.digest()).ok()
+}
+
+/// Whether a message was signed by the channel owner.
+pub(crate) fn verify(message: &Published, owner: &PublicKey) -> bool {
+    if let Some(signed) = message.signed() {
+        return owner.verify(&signed.body, &signed.owner_signature);
+    }
+    let mut unsigned = message.message().clone();
+    unsigned.signature.clear();
+    let signature = std::mem::take(&mut unsigned.owner_signature);
+    owner.verify(&unsigned.to_bytes(), &signature)
+}
@@ -0,0 +1,260 @@
+//! Optional features a node announces to its peers through Identify.
+//!
+//! They ride along in the agent version, e.g.
+//! `pingpong-p2p/0.1.0 (+dm +broadcast +history)`, so a peer can hide what
+//! the other side would not understand instead of finding out on failure.
+
+use std::{collections::BTreeSet, fmt};
+
+use libp2p::PeerId;
+
+use crate::Config;
+
+/// Protocol family announced by every pingpong node.
+pub const PROTOCOL_VERSION: &str = "/pingpong/1.0.0";
+
+const AGENT_NAME: &str = "pingpong-p2p";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Capability {
+    /// Receives direct messages.
+    DirectMessages,
+    /// Understands read-only broadcast channels.
+    Broadcast,
+    /// Stores the messages it sends and receives.
+    History,
+    /// Accepts files.
+    FileTransfer,
+    /// Reads gossipsub payloads wrapped in an envelope.
+    Envelope,
+    /// Reads chat messages published as `SignedMessage`s.
+    Signed,
+    /// Answers requests for the history of its channels.
+    ServesHistory,
+}
+
+pub type Capabilities = BTreeSet<Capability>;
+
+impl Capability {
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::DirectMessages => "dm",
+            Capability::Broadcast => "broadcast",
+            Capability::History => "history",
+            Capability::FileTransfer => "file",
+            Capability::Envelope => "envelope",
+            Capability::Signed => "signed",
+            Capability::ServesHistory => "serve-history",
+        }
+    }
+
+    /// What the capability is about, as shown to users.
+    pub fn description(self) -> &'static str {
+        match self {
+            Capability::DirectMessages => "direct messages",
+            Capability::Broadcast => "broadcast signatures",
+            Capability::History => "message history",
+            Capability::FileTransfer => "file transfer",
+            Capability::Envelope => "message envelopes",
+            Capability::Signed => "signed message bodies",
+            Capability::ServesHistory => "history requests",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dm" => Some(Capability::DirectMessages),
+            "broadcast" => Some(Capability::Broadcast),
+            "history" => Some(Capability::History),
+            "file" => Some(Capability::FileTransfer),
+            "envelope" => Some(Capability::Envelope),
+            "signed" => Some(Capability::Signed),
+            "serve-history" => Some(Capability::ServesHistory),
+            _ => None,
+        }
+    }
+}
+
+/// Where peers talk: a channel, or direct messages and files exchanged with
+/// a single peer.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Channel(String),
+    Direct(PeerId),
+}
+
+impl fmt::Display for Conversation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Conversation::Channel(channel) => f.write_str(channel),
+            Conversation::Direct(peer_id) => write!(f, "direct with {}", peer_id),
+        }
+    }
+}
+
+/// A peer in a conversation that can't be relied on for a capability the
+/// conversation uses.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Downgrade {
+    pub peer_id: PeerId,
+    pub capability: Capability,
+    /// Whether the peer announced its capabilities at all. Peers that did
+    /// not yet, or are not pingpong nodes, may still support it.
+    pub announced: bool,
+}
+
+impl fmt::Display for Downgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.announced {
+            write!(f, "{} does not support {}", self.peer_id, self.capability.description())
+        } else {
+            write!(
+                f,
+                "{} has not announced support for {}",
+                self.peer_id,
+                self.capability.description()
+            )
+        }
+    }
+}
+
+/// Whether a peer with the `announced` capabilities lacks `capability`.
+pub(crate) fn downgrade(
+    peer_id: PeerId,
+    announced: Option<&Capabilities>,
+    capability: Capability,
+) -> Option<Downgrade> {
+    match announced {
+        Some(capabilities) if capabilities.contains(&capability) => None,
+        announced => Some(Downgrade {
+            peer_id,
+            capability,
+            announced: announced.is_some(),
+        }),
+    }
+}
+
+/// What a node built from `config` supports.
+pub(crate) fn of(config: &Config) -> Capabilities {
+    let mut capabilities = Capabilities::new();
+    capabilities.insert(Capability::DirectMessages);
+    capabilities.insert(Capability::Broadcast);
+    capabilities.insert(Capability::Envelope);
+    capabilities.insert(Capability::Signed);
+    if config.history_path.is_some() {
+        capabilities.insert(Capability::History);
+        // Leaves only publish and receive
+        if !config.is_leaf() {
+            capabilities.insert(Capability::ServesHistory);
+        }
+    }
+    if config.download_dir.is_some() {
+        capabilities.insert(Capability::FileTransfer);
+    }
+    capabilities
+}
+
+pub(crate) fn agent_version(capabilities: &Capabilities) -> String {
+    let names: Vec<String> = capabilities
+        .iter()
+        .map(|capability| format!("+{}", capability.name()))
+        .collect();
+    format!(
+        "{}/{} ({})",
+        AGENT_NAME,
+        env!("CARGO_PKG_VERSION"),
+        names.join(" ")
+    )
+}
+
+/// The capabilities announced in a peer's agent version, or `None` if it is
+/// not a pingpong node. Capabilities unknown to this version are skipped.
+pub fn parse_agent_version(agent_version: &str) -> Option<Capabilities> {
+    let rest = agent_version.strip_prefix(AGENT_NAME)?.strip_prefix('/')?;
+    let list = match (rest.find('('), rest.rfind(')')) {
+        (Some(start), Some(end)) if start < end => &rest[start + 1..end],
+        _ => "",
+    };
+    Some(
+        list.split_whitespace()
+            .filter_map(|name| name.strip_prefix('+'))
+            .filter_map(Capability::from_name)
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity::Keypair;
+
+    use super::*;
+
+    fn capabilities(list: &[Capability]) -> Capabilities {
+        list.iter().copied().collect()
+    }
+
+    #[test]
+    fn old_agents_announce_nothing() {
+        for flags in ["", " ()", " (dm"] {
+            let agent_version = format!("pingpong-p2p/0.1.0{}", flags);
+            assert_eq!(parse_agent_version(&agent_version), Some(Capabilities::new()));
+        }
+        // Nor are other nodes pingpong nodes
+        assert_eq!(parse_agent_version("rust-libp2p/0.35.1"), None);
+        assert_eq!(parse_agent_version("pingpong-p2pish/0.1.0 (+dm)"), None);
+    }
+
+    #[test]
+    fn skips_unknown_capabilities() {
+        let agent_version = "pingpong-p2p/9.0.0 (+dm +teleport serve +serve-history)";
+        let announced = parse_agent_version(agent_version);
+        let expected = capabilities(&[Capability::DirectMessages, Capability::ServesHistory]);
+        assert_eq!(announced, Some(expected));
+    }
+
+    #[test]
+    fn announces_what_the_config_enables() {
+        let mut config = Config::new(Keypair::generate_ed25519());
+        let announced = |config: &Config| {
+            parse_agent_version(&agent_version(&of(config))).expect("our own agent version")
+        };
+        assert!(!announced(&config).contains(&Capability::History));
+        assert!(!announced(&config).contains(&Capability::FileTransfer));
+
+        config.history_path = Some("history".into());
+        config.download_dir = Some("downloads".into());
+        let all = announced(&config);
+        let enabled = [Capability::History, Capability::ServesHistory, Capability::FileTransfer];
+        for capability in enabled {
+            assert!(all.contains(&capability), "{:?}", capability);
+        }
+        assert_eq!(all, of(&config));
+        // Leaves keep a history but serve none of it
+        config.leaf = true;
+        let leaf = announced(&config);
+        assert!(leaf.contains(&Capability::History));
+        assert!(!leaf.contains(&Capability::ServesHistory));
+    }
+
+    #[test]
+    fn downgrades_peers_without_the_flag() {
+        let peer_id = PeerId::random();
+        let signed = capabilities(&[Capability::Envelope, Capability::Signed]);
+        assert_eq!(downgrade(peer_id, Some(&signed), Capability::Signed), None);
+
+        let old = capabilities(&[Capability::Envelope]);
+        let downgraded = downgrade(peer_id, Some(&old), Capability::Signed).unwrap();
+        assert!(downgraded.announced);
+        assert_eq!(
+            downgraded.to_string(),
+            format!("{} does not support signed message bodies", peer_id)
+        );
+        // Not identified yet, or not a pingpong node
+        let unknown = downgrade(peer_id, None, Capability::ServesHistory).unwrap();
+        assert!(!unknown.announced);
+        assert_eq!(
+            unknown.to_string(),
+            format!("{} has not announced support for history requests", peer_id)
+        );
+    }
+}
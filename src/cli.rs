@@ -0,0 +1,403 @@
+//! Command line flags and the config file they override.
+
+use std::{
+    fs, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Context};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use pingpong_p2p::{
+    gate::{IpNetwork, Rule},
+    invite::Invite,
+    schedule::Schedule,
+};
+use serde::Deserialize;
+use structopt::StructOpt;
+
+// Commands typed while chatting, and where flags can come from besides the
+// command line.
+const AFTER_HELP: &str = "\
+COMMANDS:
+    /join <CHANNEL>, /leave [CHANNEL], /channels
+    /broadcast <NAME>                  Open a channel only we publish on
+    /msg <PEER> <TEXT>                 Private message, kept until the peer is back
+    /send <PEER> <PATH>                Send a file to a peer running with --accept-files
+    /forward <MESSAGE_ID> <CHANNEL>    Quote a message into another channel
+    /history [N], /star <MESSAGE_ID>, /starred
+    /earlier [CHANNEL]                 Ask a peer for older messages of a channel
+    /status <MESSAGE_ID>               Who got and read one of our messages
+    /who, /ping <PEER>, /latency, /info <PEER>
+    /invite [CHANNEL]                  Print an invite with a QR code
+    /code [PEER|CHANNEL], /decode <WORDS>
+    /block <PEER>, /unblock <PEER>, /mute <PEER>, /unmute <PEER>, /blocked
+
+A <PEER> is a peer id or a display name. Ctrl-C, SIGTERM or closing stdin leaves every channel \
+before exiting.
+
+Every flag can also be set in the config file, using the flag name as key, e.g. `name = \"alice\"` \
+or `channels = [\"chat\", \"dev\"]`, flags given on the command line win. The gossipsub \
+heartbeat and mesh sizes may also come from the PINGPONG_HEARTBEAT_MS, PINGPONG_MESH_N, \
+PINGPONG_MESH_N_LOW and PINGPONG_MESH_N_HIGH environment variables. Unless one of them is set, \
+the mesh sizes follow the number of peers known, the cores and --upload-budget.";
+
+#[derive(StructOpt)]
+#[structopt(about = "Peer-to-peer chat over libp2p", after_help = AFTER_HELP)]
+pub struct Opt {
+    /// Name shown next to our messages [default: anon]
+    #[structopt(long = "name", alias = "alias", value_name = "NAME")]
+    pub display_name: Option<String>,
+    /// Address to listen on, may be repeated [default: /ip4/0.0.0.0/tcp/0]
+    #[structopt(long = "listen", value_name = "MULTIADDR", number_of_values = 1)]
+    pub listen: Vec<Multiaddr>,
+    /// Peer to dial on startup, may be repeated
+    #[structopt(long = "dial", value_name = "MULTIADDR", number_of_values = 1)]
+    pub dial: Vec<Multiaddr>,
+    /// Known DHT peer as <MULTIADDR>/p2p/<PEER_ID>, may be repeated
+    #[structopt(
+        long = "bootstrap",
+        value_name = "MULTIADDR",
+        number_of_values = 1,
+        parse(try_from_str = parse_bootstrap)
+    )]
+    pub bootstrap: Vec<(PeerId, Multiaddr)>,
+    /// Times in a row a lost dialed or bootstrap peer is redialed, 0 to never redial [default: 10]
+    #[structopt(long, value_name = "N")]
+    pub max_redials: Option<u32>,
+    /// Only connect within this network, e.g. 10.0.0.0/8, may be repeated
+    #[structopt(long, value_name = "CIDR", number_of_values = 1)]
+    pub allow_net: Vec<IpNetwork>,
+    /// Only connect over addresses using this protocol, e.g. tcp, may be repeated
+    #[structopt(long, value_name = "NAME", number_of_values = 1)]
+    pub allow_transport: Vec<String>,
+    /// Only connect to this peer, may be repeated
+    #[structopt(long, value_name = "PEER_ID", number_of_values = 1)]
+    pub allow_peer: Vec<PeerId>,
+    /// Never connect to this peer, may be repeated
+    #[structopt(long, value_name = "PEER_ID", number_of_values = 1)]
+    pub deny_peer: Vec<PeerId>,
+    /// Don't tell authors when we got and read their messages
+    #[structopt(long)]
+    pub no_receipts: bool,
+    /// Only use plain TCP, neither dialing nor listening on WebSocket addresses
+    #[structopt(long)]
+    pub no_websocket: bool,
+    /// Let browser peers in: also listen on WebSocket and talk floodsub to peers lacking gossipsub
+    #[structopt(long)]
+    pub browser: bool,
+    /// Refuse connections from peers blocked with /block
+    #[structopt(long)]
+    pub refuse_blocked: bool,
+    /// Connection rule such as "deny 10.0.0.0/8 inbound" or "max 5 per /24", may be repeated
+    #[structopt(long = "rule", value_name = "RULE", number_of_values = 1)]
+    pub rules: Vec<Rule>,
+    /// Channel to join on startup, may be repeated [default: chat]
+    #[structopt(long = "channel", value_name = "NAME", number_of_values = 1)]
+    pub channels: Vec<String>,
+    /// Identity key file [default: ~/.config/pingpong-p2p/identity.key]
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    pub identity: Option<PathBuf>,
+    /// Key owning our broadcast channels [default: the identity key]
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    pub owner_key: Option<PathBuf>,
+    /// Use a throwaway identity and keep nothing on disk
+    #[structopt(long)]
+    pub ephemeral: bool,
+    /// Do not store sent and received messages
+    #[structopt(long)]
+    pub no_history: bool,
+    /// Refuse peers lacking what a conversation needs instead of warning
+    #[structopt(long)]
+    pub strict: bool,
+    /// Only publish and receive, never forwarding others' channel messages
+    #[structopt(long)]
+    pub leaf: bool,
+    /// Accept files peers send us, saved in the download directory
+    #[structopt(long)]
+    pub accept_files: bool,
+    /// Accept files into this directory [default: ~/.local/share/pingpong-p2p/downloads]
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    pub download_dir: Option<PathBuf>,
+    /// Largest file accepted, in MiB [default: 1024]
+    #[structopt(long, value_name = "MIB")]
+    pub max_file_size: Option<u64>,
+    /// How files we send make way for chat, "priority" or "weighted <PERCENT>" [default: priority]
+    #[structopt(long, value_name = "SCHEDULE")]
+    pub schedule: Option<Schedule>,
+    /// How long new peer identities are the first pruned from channel meshes [default: 10m]
+    #[structopt(long, value_name = "DURATION", parse(try_from_str = humantime::parse_duration))]
+    pub probation: Option<Duration>,
+    /// How long received messages are remembered to drop copies of them [default: 1m]
+    #[structopt(long, value_name = "DURATION", parse(try_from_str = humantime::parse_duration))]
+    pub dedup_window: Option<Duration>,
+    /// Most messages remembered to drop copies of them, 0 to keep none [default: 10000]
+    #[structopt(long, value_name = "N")]
+    pub dedup_size: Option<usize>,
+    /// How long a direct message waits for an unreachable peer to come back [default: 10m]
+    #[structopt(long, value_name = "DURATION", parse(try_from_str = humantime::parse_duration))]
+    pub outbox_ttl: Option<Duration>,
+    /// Peers we message most kept connected for the next direct message, 0 for none [default: 3]
+    #[structopt(long, value_name = "N")]
+    pub standby: Option<usize>,
+    /// Channel messages per second accepted from a single peer, 0 for any number [default: 5]
+    #[structopt(long, value_name = "PER_SECOND")]
+    pub rate_limit: Option<f64>,
+    /// Channel messages accepted at once from a single peer [default: 20]
+    #[structopt(long, value_name = "N")]
+    pub rate_burst: Option<u32>,
+    /// Point out messages that arrived out of order with arrows to the ones they follow
+    #[structopt(long)]
+    pub show_order: bool,
+    /// Also show the startup invite as a QR code
+    #[structopt(long)]
+    pub qr: bool,
+    /// Full-screen interface with a message pane, a peer list and an input line
+    #[structopt(long)]
+    pub tui: bool,
+    /// Serve Prometheus metrics over HTTP on this address, e.g. 127.0.0.1:9090
+    #[structopt(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+    /// Log filter such as `info` or `pingpong_p2p=debug` [default: $RUST_LOG]
+    #[structopt(long, value_name = "FILTER")]
+    pub log_level: Option<String>,
+    /// Config file [default: ~/.config/pingpong-p2p/config.toml]
+    #[structopt(long, value_name = "PATH", parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// Gossipsub heartbeat interval in milliseconds
+    #[structopt(long, value_name = "MS", env = "PINGPONG_HEARTBEAT_MS")]
+    pub heartbeat_ms: Option<u64>,
+    /// Target number of gossipsub mesh peers
+    #[structopt(long, value_name = "N", env = "PINGPONG_MESH_N")]
+    pub mesh_n: Option<usize>,
+    /// Fewest gossipsub mesh peers before grafting more
+    #[structopt(long, value_name = "N", env = "PINGPONG_MESH_N_LOW")]
+    pub mesh_n_low: Option<usize>,
+    /// Most gossipsub mesh peers before pruning some
+    #[structopt(long, value_name = "N", env = "PINGPONG_MESH_N_HIGH")]
+    pub mesh_n_high: Option<usize>,
+    /// Upload in KiB/s spent forwarding channel messages, the mesh is sized to fit [default: any]
+    #[structopt(long, value_name = "KIB")]
+    pub upload_budget: Option<u64>,
+    #[structopt(subcommand)]
+    pub command: Option<Subcommand>,
+}
+
+#[derive(StructOpt)]
+pub enum Subcommand {
+    /// Connect to whoever made an invite and join its channel
+    Join {
+        /// Invite string, as printed on startup or by /invite, read from
+        /// stdin when left out so a scanned QR code can be pasted
+        invite: Option<Invite>,
+    },
+    /// Check that signing, storage and the network work on this machine,
+    /// then exit
+    Selftest {
+        /// Skip dialing ourselves over loopback
+        #[structopt(long)]
+        offline: bool,
+    },
+}
+
+// The same options as `Opt`, read from the config file.
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct FileConfig {
+    #[serde(alias = "alias")]
+    name: Option<String>,
+    listen: Vec<String>,
+    dial: Vec<String>,
+    bootstrap: Vec<String>,
+    max_redials: Option<u32>,
+    allow_net: Vec<String>,
+    allow_transport: Vec<String>,
+    allow_peer: Vec<String>,
+    deny_peer: Vec<String>,
+    no_receipts: bool,
+    no_websocket: bool,
+    browser: bool,
+    refuse_blocked: bool,
+    rules: Vec<String>,
+    channels: Vec<String>,
+    identity: Option<PathBuf>,
+    owner_key: Option<PathBuf>,
+    ephemeral: bool,
+    no_history: bool,
+    accept_files: bool,
+    download_dir: Option<PathBuf>,
+    max_file_size: Option<u64>,
+    schedule: Option<String>,
+    strict: bool,
+    leaf: bool,
+    qr: bool,
+    show_order: bool,
+    tui: bool,
+    probation: Option<String>,
+    dedup_window: Option<String>,
+    dedup_size: Option<usize>,
+    outbox_ttl: Option<String>,
+    standby: Option<usize>,
+    rate_limit: Option<f64>,
+    rate_burst: Option<u32>,
+    log_level: Option<String>,
+    metrics_addr: Option<String>,
+    heartbeat_ms: Option<u64>,
+    mesh_n: Option<usize>,
+    mesh_n_low: Option<usize>,
+    mesh_n_high: Option<usize>,
+    upload_budget: Option<u64>,
+}
+
+impl Opt {
+    /// Parse the command line and fill in whatever it leaves unset from the
+    /// config file. The default config file may be missing, one passed with
+    /// `--config` must exist.
+    pub fn load() -> anyhow::Result<Self> {
+        let mut opt = Opt::from_args();
+        let file = match &opt.config {
+            Some(path) => read_file(path)?,
+            None => match default_path() {
+                Some(path) if path.exists() => read_file(&path)?,
+                _ => FileConfig::default(),
+            },
+        };
+        opt.merge(file)?;
+        Ok(opt)
+    }
+
+    fn merge(&mut self, file: FileConfig) -> anyhow::Result<()> {
+        if self.listen.is_empty() {
+            self.listen = parse_all(&file.listen, "address", |addr| Ok(addr.parse()?))?;
+        }
+        if self.dial.is_empty() {
+            self.dial = parse_all(&file.dial, "address", |addr| Ok(addr.parse()?))?;
+        }
+        if self.bootstrap.is_empty() {
+            self.bootstrap = parse_all(&file.bootstrap, "address", parse_bootstrap)?;
+        }
+        self.max_redials = self.max_redials.or(file.max_redials);
+        if self.allow_net.is_empty() {
+            self.allow_net = parse_all(&file.allow_net, "network", |net| net.parse())?;
+        }
+        if self.allow_transport.is_empty() {
+            self.allow_transport = file.allow_transport;
+        }
+        if self.allow_peer.is_empty() {
+            self.allow_peer = parse_all(&file.allow_peer, "peer id", |id| Ok(id.parse()?))?;
+        }
+        if self.deny_peer.is_empty() {
+            self.deny_peer = parse_all(&file.deny_peer, "peer id", |id| Ok(id.parse()?))?;
+        }
+        self.no_receipts |= file.no_receipts;
+        self.no_websocket |= file.no_websocket;
+        self.browser |= file.browser;
+        self.refuse_blocked |= file.refuse_blocked;
+        if self.rules.is_empty() {
+            self.rules = parse_all(&file.rules, "rule", |rule| rule.parse())?;
+        }
+        if self.channels.is_empty() {
+            self.channels = file.channels;
+        }
+        self.display_name = self.display_name.take().or(file.name);
+        self.identity = self.identity.take().or(file.identity);
+        self.owner_key = self.owner_key.take().or(file.owner_key);
+        self.ephemeral |= file.ephemeral;
+        self.no_history |= file.no_history;
+        self.strict |= file.strict;
+        self.leaf |= file.leaf;
+        self.qr |= file.qr;
+        self.show_order |= file.show_order;
+        self.tui |= file.tui;
+        self.accept_files |= file.accept_files;
+        self.download_dir = self.download_dir.take().or(file.download_dir);
+        self.max_file_size = self.max_file_size.or(file.max_file_size);
+        if self.schedule.is_none() {
+            if let Some(schedule) = &file.schedule {
+                self.schedule = Some(schedule.parse()?);
+            }
+        }
+        if self.probation.is_none() {
+            if let Some(probation) = &file.probation {
+                let parsed = humantime::parse_duration(probation)
+                    .with_context(|| format!("invalid probation {} in config", probation))?;
+                self.probation = Some(parsed);
+            }
+        }
+        if self.dedup_window.is_none() {
+            if let Some(window) = &file.dedup_window {
+                let parsed = humantime::parse_duration(window)
+                    .with_context(|| format!("invalid dedup window {} in config", window))?;
+                self.dedup_window = Some(parsed);
+            }
+        }
+        self.dedup_size = self.dedup_size.or(file.dedup_size);
+        if self.outbox_ttl.is_none() {
+            if let Some(ttl) = &file.outbox_ttl {
+                let parsed = humantime::parse_duration(ttl)
+                    .with_context(|| format!("invalid outbox ttl {} in config", ttl))?;
+                self.outbox_ttl = Some(parsed);
+            }
+        }
+        self.standby = self.standby.or(file.standby);
+        self.rate_limit = self.rate_limit.or(file.rate_limit);
+        self.rate_burst = self.rate_burst.or(file.rate_burst);
+        self.log_level = self.log_level.take().or(file.log_level);
+        if self.metrics_addr.is_none() {
+            if let Some(addr) = &file.metrics_addr {
+                let parsed = addr
+                    .parse()
+                    .with_context(|| format!("invalid metrics address {} in config", addr))?;
+                self.metrics_addr = Some(parsed);
+            }
+        }
+        self.heartbeat_ms = self.heartbeat_ms.or(file.heartbeat_ms);
+        self.mesh_n = self.mesh_n.or(file.mesh_n);
+        self.mesh_n_low = self.mesh_n_low.or(file.mesh_n_low);
+        self.mesh_n_high = self.mesh_n_high.or(file.mesh_n_high);
+        self.upload_budget = self.upload_budget.or(file.upload_budget);
+        Ok(())
+    }
+}
+
+/// `$XDG_CONFIG_HOME/pingpong-p2p/config.toml`, falling back to
+/// `~/.config/pingpong-p2p/config.toml`.
+fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("pingpong-p2p").join("config.toml"))
+}
+
+fn read_file(path: &Path) -> anyhow::Result<FileConfig> {
+    let text = fs::read_to_string(path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => anyhow!("config file {} not found", path.display()),
+        _ => anyhow!("failed to read {}: {}", path.display(), e),
+    })?;
+    toml::from_str(&text).with_context(|| format!("invalid config file {}", path.display()))
+}
+
+fn parse_all<T>(
+    values: &[String],
+    what: &str,
+    parse: impl Fn(&str) -> anyhow::Result<T>,
+) -> anyhow::Result<Vec<T>> {
+    values
+        .iter()
+        .map(|value| parse(value).with_context(|| format!("invalid {} {} in config", what, value)))
+        .collect()
+}
+
+// Split `<MULTIADDR>/p2p/<PEER_ID>` into the peer id and its address.
+fn parse_bootstrap(addr: &str) -> anyhow::Result<(PeerId, Multiaddr)> {
+    let mut multiaddr: Multiaddr = addr.parse()?;
+    match multiaddr.pop() {
+        Some(Protocol::P2p(hash)) => {
+            let peer_id = PeerId::from_multihash(hash)
+                .map_err(|_| anyhow!("invalid peer id in bootstrap address {}", addr))?;
+            Ok((peer_id, multiaddr))
+        }
+        _ => bail!("bootstrap address {} must end with /p2p/<PEER_ID>", addr),
+    }
+}
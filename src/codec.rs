@@ -0,0 +1,107 @@
+//! Wire format of our request-response protocols: one length-prefixed,
+//! prost-encoded request per substream, answered by one response.
+
+use std::{io, marker::PhantomData};
+
+use async_trait::async_trait;
+use futures::prelude::*;
+use libp2p::{
+    core::{
+        upgrade::{read_one, write_one},
+        ProtocolName,
+    },
+    request_response::RequestResponseCodec,
+};
+use prost::Message;
+
+use crate::message::{
+    self, DirectAck, DirectMessage, FileAck, FileChunk, HistoryRequest, HistoryResponse,
+};
+
+// Largest message we accept, comfortably above a file chunk or a page of
+// history.
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// Direct messages, answered once they arrived.
+pub(crate) const DIRECT_PROTOCOL: Protocol = Protocol(b"/pingpong/dm/1.0.0");
+/// File chunks, each answered before the next one is sent.
+pub(crate) const FILE_PROTOCOL: Protocol = Protocol(b"/pingpong/file/1.0.0");
+/// Pages of a channel's history, see [`backfill`](crate::backfill).
+pub(crate) const HISTORY_PROTOCOL: Protocol = Protocol(b"/pingpong/history/1.0.0");
+
+pub(crate) type DirectCodec = ProstCodec<DirectMessage, DirectAck>;
+pub(crate) type FileCodec = ProstCodec<FileChunk, FileAck>;
+pub(crate) type HistoryCodec = ProstCodec<HistoryRequest, HistoryResponse>;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Protocol(&'static [u8]);
+
+impl ProtocolName for Protocol {
+    fn protocol_name(&self) -> &[u8] {
+        self.0
+    }
+}
+
+pub(crate) struct ProstCodec<Req, Res>(PhantomData<fn() -> (Req, Res)>);
+
+impl<Req, Res> Default for ProstCodec<Req, Res> {
+    fn default() -> Self {
+        ProstCodec(PhantomData)
+    }
+}
+
+impl<Req, Res> Clone for ProstCodec<Req, Res> {
+    fn clone(&self) -> Self {
+        ProstCodec::default()
+    }
+}
+
+#[async_trait]
+impl<Req, Res> RequestResponseCodec for ProstCodec<Req, Res>
+where
+    Req: Message + Default + 'static,
+    Res: Message + Default + 'static,
+{
+    type Protocol = Protocol;
+    type Request = Req;
+    type Response = Res;
+
+    async fn read_request<T>(&mut self, _: &Protocol, io: &mut T) -> io::Result<Req>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn read_response<T>(&mut self, _: &Protocol, io: &mut T) -> io::Result<Res>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        read_message(io).await
+    }
+
+    async fn write_request<T>(&mut self, _: &Protocol, io: &mut T, req: Req) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_one(io, message::encode(&req)).await
+    }
+
+    async fn write_response<T>(&mut self, _: &Protocol, io: &mut T, res: Res) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_one(io, message::encode(&res)).await
+    }
+}
+
+async fn read_message<M, T>(io: &mut T) -> io::Result<M>
+where
+    M: Message + Default,
+    T: AsyncRead + Unpin + Send,
+{
+    let bytes = read_one(io, MAX_MESSAGE_SIZE)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    M::decode(bytes.as_slice()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
@@ -0,0 +1,242 @@
+//! Slash commands typed at the prompt.
+
+use anyhow::{anyhow, bail};
+
+// How many messages `/history` shows without an explicit count.
+const DEFAULT_HISTORY: usize = 20;
+
+/// A line of user input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input<'a> {
+    /// Text to publish on the active channel.
+    Text(&'a str),
+    Command(Command),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// `/join <channel>`: subscribe to a channel and make it the active one.
+    Join(String),
+    /// `/leave [channel]`: unsubscribe from a channel, the active one by default.
+    Leave(Option<String>),
+    /// `/broadcast <name>`: join the read-only channel we own under that name
+    /// and make it the active one.
+    Broadcast(String),
+    /// `/channels`: list joined channels.
+    Channels,
+    /// `/forward <message-id> <channel>`: republish a received message on
+    /// another channel.
+    Forward { id: String, channel: String },
+    /// `/history [n]`: show the last messages, 20 by default.
+    History(usize),
+    /// `/earlier [channel]`: ask a peer for the messages of a channel, the
+    /// active one by default, from before the oldest one we have.
+    Earlier(Option<String>),
+    /// `/status <message-id>`: show who got and read one of our messages.
+    Status(String),
+    /// `/star <message-id>`: save a received message.
+    Star(String),
+    /// `/starred`: list saved messages.
+    Starred,
+    /// `/ping <peer-id|name>`: measure the round trip time to a peer.
+    Ping(String),
+    /// `/info <peer-id|name>`: show what a peer told about itself through
+    /// Identify.
+    Info(String),
+    /// `/latency`: summarize round trip times of connected peers.
+    Latency,
+    /// `/who`: list peers currently online.
+    Who,
+    /// `/msg <peer-id|name> <text>`: send a direct message to one peer.
+    Msg { to: String, text: String },
+    /// `/send <peer-id|name> <path>`: send a file to one peer.
+    Send { to: String, path: String },
+    /// `/block <peer-id|name>`: drop everything a peer sends.
+    Block(String),
+    /// `/unblock <peer-id|name>`: hear from a blocked peer again.
+    Unblock(String),
+    /// `/mute <peer-id|name>`: hide a peer's messages, while still relaying
+    /// them to others.
+    Mute(String),
+    /// `/unmute <peer-id|name>`: show a muted peer's messages again.
+    Unmute(String),
+    /// `/blocked`: list blocked and muted peers.
+    Blocked,
+    /// `/invite [channel]`: print an invite to a channel, the active one by
+    /// default.
+    Invite(Option<String>),
+    /// `/code [peer-id|name|channel]`: print the word code of a peer or a
+    /// joined channel, ours and the active channel's by default.
+    Code(Option<String>),
+    /// `/decode <words>`: find the peer or channel a word code belongs to.
+    Decode(String),
+}
+
+/// Parse a line of input. Lines starting with `/` are commands, a leading
+/// `//` escapes a literal slash.
+pub fn parse(line: &str) -> anyhow::Result<Input<'_>> {
+    let rest = match line.strip_prefix('/') {
+        Some(rest) if !rest.starts_with('/') => rest,
+        Some(rest) => return Ok(Input::Text(rest)),
+        None => return Ok(Input::Text(line)),
+    };
+    let (name, args) = split_word(rest).unwrap_or_default();
+    let command = match name {
+        "join" => Command::Join(required(first_word(args), "/join <channel>")?),
+        "leave" => Command::Leave(first_word(args).map(String::from)),
+        "broadcast" => Command::Broadcast(required(first_word(args), "/broadcast <name>")?),
+        "channels" => Command::Channels,
+        "forward" => match args.split_whitespace().collect::<Vec<_>>()[..] {
+            [id, channel] => Command::Forward {
+                id: id.to_owned(),
+                channel: channel.to_owned(),
+            },
+            _ => bail!("usage: /forward <message-id> <channel>"),
+        },
+        "history" => match first_word(args) {
+            Some(n) => Command::History(n.parse().map_err(|_| anyhow!("usage: /history [n]"))?),
+            None => Command::History(DEFAULT_HISTORY),
+        },
+        "earlier" => Command::Earlier(first_word(args).map(String::from)),
+        "status" => Command::Status(required(first_word(args), "/status <message-id>")?),
+        "star" => Command::Star(required(first_word(args), "/star <message-id>")?),
+        "starred" => Command::Starred,
+        "ping" => Command::Ping(required(first_word(args), "/ping <peer-id|name>")?),
+        "info" => Command::Info(required(first_word(args), "/info <peer-id|name>")?),
+        "latency" => Command::Latency,
+        "who" => Command::Who,
+        "msg" => match split_word(args) {
+            Some((to, text)) if !text.is_empty() => Command::Msg {
+                to: to.to_owned(),
+                text: text.to_owned(),
+            },
+            _ => bail!("usage: /msg <peer-id|name> <text>"),
+        },
+        "send" => match split_word(args) {
+            Some((to, path)) if !path.is_empty() => Command::Send {
+                to: to.to_owned(),
+                path: path.trim_end().to_owned(),
+            },
+            _ => bail!("usage: /send <peer-id|name> <path>"),
+        },
+        "block" => Command::Block(required(first_word(args), "/block <peer-id|name>")?),
+        "unblock" => Command::Unblock(required(first_word(args), "/unblock <peer-id|name>")?),
+        "mute" => Command::Mute(required(first_word(args), "/mute <peer-id|name>")?),
+        "unmute" => Command::Unmute(required(first_word(args), "/unmute <peer-id|name>")?),
+        "blocked" => Command::Blocked,
+        "invite" => Command::Invite(first_word(args).map(String::from)),
+        "code" => Command::Code(first_word(args).map(String::from)),
+        "decode" => match args.trim() {
+            "" => bail!("usage: /decode <words>"),
+            words => Command::Decode(words.to_owned()),
+        },
+        _ => bail!("unknown command /{}", name),
+    };
+    Ok(Input::Command(command))
+}
+
+// Split off the first word, returning the rest with leading whitespace
+// trimmed so free text keeps its inner spacing.
+fn split_word(s: &str) -> Option<(&str, &str)> {
+    let s = s.trim_start();
+    match s.find(char::is_whitespace) {
+        Some(end) => Some((&s[..end], s[end..].trim_start())),
+        None if s.is_empty() => None,
+        None => Some((s, "")),
+    }
+}
+
+fn first_word(s: &str) -> Option<&str> {
+    split_word(s).map(|(word, _)| word)
+}
+
+fn required(arg: Option<&str>, usage: &str) -> anyhow::Result<String> {
+    arg.map(String::from)
+        .ok_or_else(|| anyhow!("usage: {}", usage))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(line: &str) -> Command {
+        match parse(line).unwrap() {
+            Input::Command(command) => command,
+            Input::Text(text) => panic!("{:?} is text", text),
+        }
+    }
+
+    #[test]
+    fn text() {
+        assert_eq!(parse("hello").unwrap(), Input::Text("hello"));
+        assert_eq!(parse("").unwrap(), Input::Text(""));
+        assert_eq!(parse("//join me").unwrap(), Input::Text("/join me"));
+        // Only a slash right at the start makes a command
+        assert_eq!(parse(" /who").unwrap(), Input::Text(" /who"));
+    }
+
+    #[test]
+    fn commands() {
+        assert_eq!(command("/join dev"), Command::Join(String::from("dev")));
+        assert_eq!(command("/leave"), Command::Leave(None));
+        assert_eq!(command("/leave dev"), Command::Leave(Some(String::from("dev"))));
+        assert_eq!(command("/channels"), Command::Channels);
+        assert_eq!(command("/history"), Command::History(DEFAULT_HISTORY));
+        assert_eq!(command("/history 5"), Command::History(5));
+        assert_eq!(command("/earlier"), Command::Earlier(None));
+        assert_eq!(command("/earlier dev"), Command::Earlier(Some(String::from("dev"))));
+        assert_eq!(
+            command("/forward 1a2b dev"),
+            Command::Forward {
+                id: String::from("1a2b"),
+                channel: String::from("dev")
+            }
+        );
+        assert_eq!(command("/code"), Command::Code(None));
+    }
+
+    #[test]
+    fn free_text_keeps_its_spacing() {
+        assert_eq!(
+            command("/msg  bob  hi   there "),
+            Command::Msg {
+                to: String::from("bob"),
+                text: String::from("hi   there "),
+            }
+        );
+        assert_eq!(
+            command("/send bob ~/my file.txt \n"),
+            Command::Send {
+                to: String::from("bob"),
+                path: String::from("~/my file.txt"),
+            }
+        );
+        assert_eq!(
+            command("/decode  able  baby "),
+            Command::Decode(String::from("able  baby"))
+        );
+    }
+
+    #[test]
+    fn missing_arguments() {
+        for line in [
+            "/join",
+            "/broadcast ",
+            "/forward 1a2b",
+            "/history many",
+            "/msg bob",
+            "/send bob",
+            "/block",
+            "/decode  ",
+        ] {
+            let e = parse(line).unwrap_err().to_string();
+            assert!(e.starts_with("usage: "), "{:?}: {}", line, e);
+        }
+    }
+
+    #[test]
+    fn unknown_command() {
+        assert_eq!(parse("/dance").unwrap_err().to_string(), "unknown command /dance");
+        assert_eq!(parse("/").unwrap_err().to_string(), "unknown command /");
+    }
+}
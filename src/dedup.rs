@@ -0,0 +1,216 @@
+//! Chat messages seen recently, so one arriving twice is only shown once.
+//!
+//! Gossipsub already drops copies of a message it relays, going by source and
+//! sequence number, for as long as [`Config::dedup_window`]. This catches the
+//! same signed message published again, going by its digest, and keeps up to
+//! [`Config::dedup_capacity`] of them so busy channels can't grow it without
+//! bound. How many messages were caught shows in the metrics.
+//!
+//! When kept on disk, what was seen survives a restart, so messages
+//! processed just before it are not shown or forwarded again when they come
+//! around once more.
+//!
+//! [`Config::dedup_window`]: crate::Config::dedup_window
+//! [`Config::dedup_capacity`]: crate::Config::dedup_capacity
+
+use std::{
+    cmp::Reverse,
+    collections::{HashSet, VecDeque},
+    convert::TryInto,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+
+/// How long a message is remembered unless configured otherwise.
+pub const DEFAULT_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many messages are remembered at most unless configured otherwise.
+pub const DEFAULT_CAPACITY: usize = 10_000;
+
+/// Where recently seen messages are kept unless another path is given:
+/// `$XDG_DATA_HOME/pingpong-p2p/seen`, falling back to
+/// `~/.local/share/pingpong-p2p/seen`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|dir| dir.join("pingpong-p2p").join("seen"))
+}
+
+/// Digests of the messages seen within the window, oldest first, kept in
+/// memory only unless opened from a path.
+pub(crate) struct Seen {
+    db: Option<sled::Db>,
+    window: Duration,
+    capacity: usize,
+    digests: HashSet<Vec<u8>>,
+    order: VecDeque<(Instant, Vec<u8>)>,
+}
+
+impl Seen {
+    pub(crate) fn new(window: Duration, capacity: usize) -> Self {
+        Seen {
+            db: None,
+            window,
+            capacity,
+            digests: HashSet::new(),
+            order: VecDeque::new(),
+        }
+    }
+
+    /// Load the messages stored at `path` that are still within the window,
+    /// dropping the others. Entries that fail to decode are dropped too.
+    pub(crate) fn open(path: &Path, window: Duration, capacity: usize) -> anyhow::Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("failed to open seen messages at {}", path.display()))?;
+        Self::load(db, window, capacity)
+    }
+
+    fn load(db: sled::Db, window: Duration, capacity: usize) -> anyhow::Result<Self> {
+        let now = SystemTime::now();
+        let mut entries = Vec::new();
+        for entry in db.iter() {
+            let (key, value) = entry?;
+            let millis = value.as_ref().try_into().ok().map(u64::from_be_bytes);
+            let age = millis.and_then(|millis| {
+                now.duration_since(UNIX_EPOCH + Duration::from_millis(millis)).ok()
+            });
+            match age.filter(|age| *age < window) {
+                Some(age) => entries.push((age, key.to_vec())),
+                None => {
+                    db.remove(key)?;
+                }
+            }
+        }
+        // Oldest first, and only as many as fit
+        entries.sort_by_key(|(age, _)| Reverse(*age));
+        let mut seen = Seen::new(window, capacity);
+        let excess = entries.len().saturating_sub(capacity);
+        for (_, digest) in entries.drain(..excess) {
+            db.remove(digest)?;
+        }
+        let start = Instant::now();
+        for (age, digest) in entries {
+            let at = start.checked_sub(age).unwrap_or(start);
+            seen.digests.insert(digest.clone());
+            seen.order.push_back((at, digest));
+        }
+        seen.db = Some(db);
+        Ok(seen)
+    }
+
+    /// Remember a message by its digest, returning false if it was already
+    /// seen. Nothing is remembered with a capacity of zero.
+    pub(crate) fn insert(&mut self, digest: Vec<u8>) -> bool {
+        if self.capacity == 0 {
+            return true;
+        }
+        let now = Instant::now();
+        while let Some((at, _)) = self.order.front() {
+            if now.duration_since(*at) < self.window {
+                break;
+            }
+            self.forget_oldest();
+        }
+        if !self.digests.insert(digest.clone()) {
+            return false;
+        }
+        if self.order.len() == self.capacity {
+            self.forget_oldest();
+        }
+        if let Some(db) = &self.db {
+            let millis = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            if let Err(e) = db.insert(&digest, &millis.to_be_bytes()) {
+                log::warn!("failed to store seen message: {}", e);
+            }
+        }
+        self.order.push_back((now, digest));
+        true
+    }
+
+    /// Make sure every message remembered so far is on disk.
+    pub(crate) fn flush(&self) -> anyhow::Result<()> {
+        if let Some(db) = &self.db {
+            db.flush()?;
+        }
+        Ok(())
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some((_, digest)) = self.order.pop_front() {
+            if let Some(db) = &self.db {
+                if let Err(e) = db.remove(&digest) {
+                    log::warn!("failed to forget seen message: {}", e);
+                }
+            }
+            self.digests.remove(&digest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn digest(n: u8) -> Vec<u8> {
+        vec![n; 32]
+    }
+
+    #[test]
+    fn drops_copies() {
+        let mut seen = Seen::new(DEFAULT_WINDOW, DEFAULT_CAPACITY);
+        assert!(seen.insert(digest(1)));
+        assert!(seen.insert(digest(2)));
+        assert!(!seen.insert(digest(1)));
+        assert!(!seen.insert(digest(2)));
+    }
+
+    #[test]
+    fn forgets_oldest_beyond_capacity() {
+        let mut seen = Seen::new(DEFAULT_WINDOW, 2);
+        assert!(seen.insert(digest(1)));
+        assert!(seen.insert(digest(2)));
+        assert!(seen.insert(digest(3)));
+        assert_eq!(seen.order.len(), 2);
+        assert!(!seen.insert(digest(3)));
+        assert!(!seen.insert(digest(2)));
+        assert!(seen.insert(digest(1)));
+    }
+
+    #[test]
+    fn forgets_after_window() {
+        let mut seen = Seen::new(Duration::from_millis(50), DEFAULT_CAPACITY);
+        assert!(seen.insert(digest(1)));
+        thread::sleep(Duration::from_millis(100));
+        assert!(seen.insert(digest(2)));
+        assert_eq!(seen.order.len(), 1);
+        assert!(seen.insert(digest(1)));
+    }
+
+    #[test]
+    fn remembers_nothing_without_capacity() {
+        let mut seen = Seen::new(DEFAULT_WINDOW, 0);
+        assert!(seen.insert(digest(1)));
+        assert!(seen.insert(digest(1)));
+    }
+
+    #[test]
+    fn survives_restart() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut seen = Seen::load(db.clone(), DEFAULT_WINDOW, 2).unwrap();
+        for n in 1..=3 {
+            assert!(seen.insert(digest(n)));
+        }
+        drop(seen);
+        let mut seen = Seen::load(db, DEFAULT_WINDOW, 2).unwrap();
+        assert!(!seen.insert(digest(3)));
+        assert!(seen.insert(digest(1)));
+    }
+}
@@ -0,0 +1,156 @@
+//! Sizing the gossipsub mesh to the node and the network.
+//!
+//! Every channel message we get is forwarded to each of our mesh peers, so
+//! the mesh size decides most of what a node spends relaying. With a
+//! [`Config::budget`] set, the mesh sizes are picked when the swarm is built
+//! instead of taken from [`Config::gossipsub`]: about the base two logarithm
+//! of the identities we know, which keeps a random mesh connected, up to the
+//! gossipsub default of six, and no more than the budget pays for. A device
+//! with one core and little upload ends up with a single mesh peer per
+//! channel, receiving and publishing but hardly relaying, and one that cannot
+//! even pay for that one becomes a leaf, see [`Config::leaf`].
+//!
+//! [`Config::budget`]: crate::Config::budget
+//! [`Config::gossipsub`]: crate::Config::gossipsub
+//! [`Config::leaf`]: crate::Config::leaf
+
+use std::thread;
+
+use libp2p::gossipsub::{GossipsubConfig, GossipsubConfigBuilder};
+
+// Mesh peers wanted in a large network, the gossipsub default
+const MAX_MESH_N: usize = 6;
+// Mesh peers wanted however few peers we know, as long as they are affordable
+const MIN_MESH_N: usize = 2;
+// Mesh peers a single core is assumed to keep up with
+const PEERS_PER_CPU: usize = 4;
+// Upload in bytes per second a mesh peer is assumed to cost, a busy channel
+// forwarded to it once
+const UPLOAD_PER_PEER: u64 = 8 * 1024;
+
+/// What a node may spend forwarding channel messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Budget {
+    /// Bytes per second of upload, as much as it takes when unset.
+    pub upload: Option<u64>,
+    pub cpus: usize,
+}
+
+impl Budget {
+    /// All the cores of this machine and the given upload.
+    pub fn measure(upload: Option<u64>) -> Self {
+        let cpus = thread::available_parallelism().map_or(1, |n| n.get());
+        Budget { upload, cpus }
+    }
+
+    /// Whether not even a single mesh peer is affordable, so the node is to
+    /// only publish and receive.
+    pub fn is_leaf(&self) -> bool {
+        self.affordable() == 0
+    }
+
+    // Mesh peers the budget pays for
+    fn affordable(&self) -> usize {
+        let mut affordable = self.cpus * PEERS_PER_CPU;
+        if let Some(upload) = self.upload {
+            affordable = affordable.min((upload / UPLOAD_PER_PEER) as usize);
+        }
+        affordable
+    }
+}
+
+/// Gossipsub mesh sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Degree {
+    pub mesh_n: usize,
+    pub mesh_n_low: usize,
+    pub mesh_n_high: usize,
+    pub mesh_outbound_min: usize,
+}
+
+impl Degree {
+    /// The mesh sizes for a node knowing `known_peers` identities.
+    pub fn new(known_peers: usize, budget: &Budget) -> Self {
+        let log2 = (usize::BITS - known_peers.leading_zeros()) as usize;
+        // Messages only reach us through the mesh, so keep one peer in it
+        let affordable = budget.affordable().max(1);
+        let mesh_n = (log2 + 1).clamp(MIN_MESH_N, MAX_MESH_N).min(affordable);
+        let mesh_n_low = mesh_n - mesh_n / 4;
+        Degree {
+            mesh_n,
+            mesh_n_low,
+            mesh_n_high: (2 * mesh_n).min(affordable).max(mesh_n),
+            // Gossipsub needs fewer than `mesh_n_low` and at most half
+            mesh_outbound_min: 2.min(mesh_n / 2).min(mesh_n_low - 1),
+        }
+    }
+
+    /// `config` with these mesh sizes.
+    pub fn apply(&self, config: &GossipsubConfig) -> GossipsubConfig {
+        GossipsubConfigBuilder::from(config.clone())
+            .mesh_n(self.mesh_n)
+            .mesh_n_low(self.mesh_n_low)
+            .mesh_n_high(self.mesh_n_high)
+            .mesh_outbound_min(self.mesh_outbound_min)
+            .build()
+            .expect("valid mesh sizes")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn budget(upload: Option<u64>, cpus: usize) -> Budget {
+        Budget { upload, cpus }
+    }
+
+    #[test]
+    fn grows_with_the_network() {
+        let unlimited = budget(None, 8);
+        let sizes: Vec<_> =
+            [0, 1, 3, 7, 15, 1000].iter().map(|&n| Degree::new(n, &unlimited).mesh_n).collect();
+        assert_eq!(sizes, vec![2, 2, 3, 4, 5, 6]);
+        // The gossipsub defaults in a large network
+        let defaults = GossipsubConfig::default();
+        let degree = Degree::new(1000, &unlimited);
+        assert_eq!(degree.mesh_n, defaults.mesh_n());
+        assert_eq!(degree.mesh_n_low, defaults.mesh_n_low());
+        assert_eq!(degree.mesh_n_high, defaults.mesh_n_high());
+        assert_eq!(degree.mesh_outbound_min, defaults.mesh_outbound_min());
+    }
+
+    #[test]
+    fn fits_the_budget() {
+        let degree = Degree::new(1000, &budget(None, 1));
+        assert_eq!((degree.mesh_n, degree.mesh_n_high), (4, 4));
+        let degree = Degree::new(1000, &budget(Some(3 * UPLOAD_PER_PEER), 8));
+        assert_eq!((degree.mesh_n, degree.mesh_n_high), (3, 3));
+        let degree = Degree::new(1000, &budget(Some(0), 1));
+        assert_eq!((degree.mesh_n, degree.mesh_n_high), (1, 1));
+    }
+
+    #[test]
+    fn leaves_when_nothing_is_affordable() {
+        assert!(!budget(None, 1).is_leaf());
+        assert!(!budget(Some(UPLOAD_PER_PEER), 1).is_leaf());
+        assert!(budget(Some(UPLOAD_PER_PEER - 1), 8).is_leaf());
+        assert!(budget(None, 0).is_leaf());
+    }
+
+    #[test]
+    fn always_valid() {
+        let config = GossipsubConfig::default();
+        for known_peers in [0, 5, 100, 100_000] {
+            for cpus in 0..4 {
+                for upload in [None, Some(0), Some(UPLOAD_PER_PEER), Some(5 * UPLOAD_PER_PEER)] {
+                    let degree = Degree::new(known_peers, &budget(upload, cpus));
+                    // Panics on sizes gossipsub refuses
+                    let tuned = degree.apply(&config);
+                    assert_eq!(tuned.mesh_n(), degree.mesh_n);
+                    assert!(degree.mesh_n >= 1);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! What can go wrong using a [`Node`].
+//!
+//! Every fallible method of [`Node`] returns an [`Error`] telling what kind
+//! of failure it was, so callers can tell a channel they are not in from a
+//! disk that is full without looking at the message. It reads as the error
+//! it was caused by, which [`Error::into_source`] hands back.
+//!
+//! [`Node`]: crate::Node
+
+use std::fmt;
+
+/// The error an [`Error`] was caused by.
+pub type Source = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+/// A failure of a [`Node`] operation, by kind.
+///
+/// [`Node`]: crate::Node
+#[derive(Debug)]
+pub enum Error {
+    /// The swarm could not be set up or a message could not go out: building
+    /// the transport, listening, discovering peers or publishing.
+    Transport(Source),
+    /// No peer could be reached for what was asked, e.g. publishing on a
+    /// channel no one else joined yet or asking for history no member serves.
+    Dial(Source),
+    /// Something we send or store could not be encoded or read back, e.g. a
+    /// message too large even for fragments or an invalid key file.
+    Decode(Source),
+    /// Reading or writing what the node keeps on disk failed.
+    Storage(Source),
+    /// Signing a message or our transport key failed.
+    Crypto(Source),
+    /// What was asked is not allowed by the config or the node's state, e.g.
+    /// publishing on a channel we are not in or on a read-only one, or an
+    /// invalid gossipsub setting.
+    Config(Source),
+}
+
+impl Error {
+    pub(crate) fn transport(source: impl Into<Source>) -> Self {
+        Error::Transport(source.into())
+    }
+
+    pub(crate) fn dial(source: impl Into<Source>) -> Self {
+        Error::Dial(source.into())
+    }
+
+    pub(crate) fn decode(source: impl Into<Source>) -> Self {
+        Error::Decode(source.into())
+    }
+
+    pub(crate) fn storage(source: impl Into<Source>) -> Self {
+        Error::Storage(source.into())
+    }
+
+    pub(crate) fn crypto(source: impl Into<Source>) -> Self {
+        Error::Crypto(source.into())
+    }
+
+    pub(crate) fn config(source: impl Into<Source>) -> Self {
+        Error::Config(source.into())
+    }
+
+    /// The error this one was caused by.
+    pub fn into_source(self) -> Source {
+        match self {
+            Error::Transport(source)
+            | Error::Dial(source)
+            | Error::Decode(source)
+            | Error::Storage(source)
+            | Error::Crypto(source)
+            | Error::Config(source) => source,
+        }
+    }
+
+    fn source_ref(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+        match self {
+            Error::Transport(source)
+            | Error::Dial(source)
+            | Error::Decode(source)
+            | Error::Storage(source)
+            | Error::Crypto(source)
+            | Error::Config(source) => source.as_ref(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The source tells what failed, the kind is for matching on
+        fmt::Display::fmt(self.source_ref(), f)
+    }
+}
+
+impl std::error::Error for Error {
+    // What caused the source, which this one already reads as
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source_ref().source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+
+    use anyhow::Context;
+
+    use super::*;
+
+    #[test]
+    fn reads_as_its_cause() {
+        let cause = std::fs::read("/nonexistent/pingpong-p2p").context("failed to open history");
+        let error = Error::storage(cause.unwrap_err());
+        assert!(matches!(error, Error::Storage(_)));
+        assert_eq!(error.to_string(), "failed to open history");
+        // Without telling the same thing twice
+        let source = error.source().unwrap();
+        assert!(source.to_string().contains("No such file"), "{}", source);
+        assert!(source.source().is_none());
+        assert_eq!(error.into_source().to_string(), "failed to open history");
+    }
+
+    #[test]
+    fn converts_into_anyhow() {
+        let error = anyhow::Error::from(Error::config("not in channel chat"));
+        assert_eq!(format!("{:#}", error), "not in channel chat");
+        assert!(matches!(error.downcast_ref(), Some(Error::Config(_))));
+    }
+}
@@ -0,0 +1,358 @@
+//! Chat messages too large for a single gossipsub message.
+//!
+//! Such a message is signed and encoded as usual, then its bytes are split
+//! into [`Fragment`]s published one after the other on the channel topic.
+//! Receivers keep the fragments until all of them arrived and handle the
+//! reassembled message as if it was published whole. Whatever is still
+//! incomplete after a minute is dropped.
+//!
+//! Fragments are only passed on once the whole message was checked, along
+//! with it, so peers can't have junk relayed by sending it in pieces.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    message::{self, Fragment, Kind, SignedMessage},
+    ChatMessage, Published,
+};
+
+// Longest encoded message that will be fragmented, and reassembled
+const MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+// How long the fragments of a message may take to all arrive
+const TIMEOUT: Duration = Duration::from_secs(60);
+// Room left in each gossipsub message for the signature, source, sequence
+// number, topic and framing around the fragment data
+const OVERHEAD: usize = 1024;
+// Most fragments a message is split into
+const MAX_FRAGMENTS: usize = 256;
+// Messages being reassembled at once, the oldest one makes way beyond this.
+// With `MAX_MESSAGE_SIZE` that is up to 16 MiB of fragments kept, and along
+// with them the gossipsub messages they came in, until they are reported.
+const MAX_PENDING: usize = 16;
+// Messages being reassembled at once from a single source
+const MAX_PENDING_PER_SOURCE: usize = 2;
+
+/// What to publish for a message, all payloads of the returned kind: the
+/// message itself if it fits in `max_transmit_size`, its fragments otherwise.
+pub(crate) fn split(
+    message: &Published,
+    max_transmit_size: usize,
+) -> anyhow::Result<(Kind, Vec<Vec<u8>>)> {
+    let (kind, bytes) = message.encode();
+    // The topic, and the channel in the fragment and around it
+    let overhead = OVERHEAD + 3 * message.channel.len();
+    if bytes.len() + overhead <= max_transmit_size {
+        return Ok((kind, vec![bytes]));
+    }
+    if bytes.len() > MAX_MESSAGE_SIZE {
+        anyhow::bail!(
+            "message is {} bytes, more than the {} that can be sent",
+            bytes.len(),
+            MAX_MESSAGE_SIZE
+        );
+    }
+    let size = max_transmit_size
+        .checked_sub(overhead)
+        .filter(|size| *size > 0)
+        .ok_or_else(|| anyhow::anyhow!("channel name too long to fragment messages on"))?;
+    let count = bytes.len().div_ceil(size);
+    if count > MAX_FRAGMENTS {
+        anyhow::bail!("message would take more than {} fragments", MAX_FRAGMENTS);
+    }
+    let digest = Sha256::digest(&bytes).to_vec();
+    let fragments = bytes
+        .chunks(size)
+        .enumerate()
+        .map(|(index, data)| {
+            let fragment = Fragment {
+                digest: digest.clone(),
+                index: index as u32,
+                count: count as u32,
+                data: data.to_vec(),
+                channel: message.channel.clone(),
+            };
+            carry(kind, fragment)
+        })
+        .collect();
+    Ok((kind, fragments))
+}
+
+// A payload of `kind` with nothing but a fragment in it.
+fn carry(kind: Kind, fragment: Fragment) -> Vec<u8> {
+    match kind {
+        Kind::Signed => message::encode(&SignedMessage {
+            fragment: Some(fragment),
+            ..SignedMessage::default()
+        }),
+        _ => ChatMessage {
+            channel: fragment.channel.clone(),
+            fragment: Some(fragment),
+            ..ChatMessage::default()
+        }
+        .to_bytes(),
+    }
+}
+
+struct Pending<T> {
+    started: Instant,
+    parts: Vec<Option<Vec<u8>>>,
+    // Bytes received so far
+    size: usize,
+    // What each fragment arrived in
+    held: Vec<T>,
+}
+
+/// Fragments received so far, per source and message, along with a `T`
+/// telling the gossipsub message each one arrived in.
+pub(crate) struct Reassembler<T> {
+    pending: HashMap<(PeerId, Vec<u8>), Pending<T>>,
+}
+
+impl<T> Default for Reassembler<T> {
+    fn default() -> Self {
+        Reassembler {
+            pending: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Reassembler<T> {
+    /// Keep a fragment published by `source` that arrived in `held`,
+    /// returning the whole message once this was the last one missing, with
+    /// what every fragment of it arrived in. Fails if the fragment does not
+    /// fit with the others or the message does not match its digest.
+    #[allow(clippy::type_complexity)]
+    pub(crate) fn add(
+        &mut self,
+        source: PeerId,
+        fragment: Fragment,
+        held: T,
+    ) -> Result<Option<(Vec<u8>, Vec<T>)>, &'static str> {
+        self.expire();
+        let count = fragment.count as usize;
+        if count < 2 || fragment.index >= fragment.count {
+            return Err("fragment out of range");
+        }
+        if count > MAX_FRAGMENTS {
+            return Err("too many fragments");
+        }
+        let key = (source, fragment.digest);
+        if !self.pending.contains_key(&key) {
+            let from_source = self.pending.keys().filter(|(s, _)| *s == source).count();
+            if from_source >= MAX_PENDING_PER_SOURCE {
+                return Err("too many incomplete messages from this source");
+            }
+        }
+        if !self.pending.contains_key(&key) && self.pending.len() >= MAX_PENDING {
+            let oldest = self
+                .pending
+                .iter()
+                .min_by_key(|(_, pending)| pending.started)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                log::debug!("dropping incomplete message from {} to make room", oldest.0);
+                self.pending.remove(&oldest);
+            }
+        }
+        let pending = self.pending.entry(key.clone()).or_insert_with(|| Pending {
+            started: Instant::now(),
+            parts: vec![None; count],
+            size: 0,
+            held: Vec::new(),
+        });
+        if pending.parts.len() != count {
+            return Err("fragment count differs from the others");
+        }
+        let part = &mut pending.parts[fragment.index as usize];
+        if part.is_some() {
+            return Err("duplicate fragment");
+        }
+        pending.size += fragment.data.len();
+        if pending.size > MAX_MESSAGE_SIZE {
+            self.pending.remove(&key);
+            return Err("reassembled message too large");
+        }
+        *part = Some(fragment.data);
+        pending.held.push(held);
+        if pending.parts.iter().any(Option::is_none) {
+            return Ok(None);
+        }
+        let pending = self.pending.remove(&key).expect("pending message");
+        let bytes: Vec<u8> = pending.parts.into_iter().flatten().flatten().collect();
+        if Sha256::digest(&bytes).as_slice() != key.1.as_slice() {
+            return Err("reassembled message does not match its digest");
+        }
+        Ok(Some((bytes, pending.held)))
+    }
+
+    // Forget messages whose fragments took too long.
+    fn expire(&mut self) {
+        self.pending.retain(|(source, _), pending| {
+            let keep = pending.started.elapsed() < TIMEOUT;
+            if !keep {
+                log::debug!("dropping incomplete message from {}", source);
+            }
+            keep
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use libp2p::identity::Keypair;
+
+    use super::*;
+    use crate::message::Chat;
+
+    // Small enough for a few kilobytes to take several fragments
+    const MAX_TRANSMIT_SIZE: usize = 4096;
+
+    fn published(content: &str) -> Published {
+        let keypair = Keypair::generate_ed25519();
+        let message = ChatMessage {
+            content: content.to_string(),
+            channel: String::from("chat"),
+            author: PeerId::from(keypair.public()).to_bytes(),
+            author_key: keypair.public().into_protobuf_encoding(),
+            ..ChatMessage::default()
+        };
+        Published::sign(message, &keypair, None, false).unwrap()
+    }
+
+    fn fragments(message: &Published) -> Vec<Fragment> {
+        let (kind, payloads) = split(message, MAX_TRANSMIT_SIZE).unwrap();
+        payloads
+            .iter()
+            .map(|payload| match Published::decode(kind, payload) {
+                Some(Chat::Part(fragment)) => fragment,
+                _ => panic!("not a fragment"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn small_message_stays_whole() {
+        let message = published("hello");
+        let (kind, payloads) = split(&message, MAX_TRANSMIT_SIZE).unwrap();
+        assert_eq!(payloads, vec![message.encode().1]);
+        assert!(matches!(Published::decode(kind, &payloads[0]), Some(Chat::Whole(_))));
+    }
+
+    #[test]
+    fn reassembles_in_any_order() {
+        let message = published(&"x".repeat(10_000));
+        let mut fragments = fragments(&message);
+        assert!(fragments.len() > 2);
+        assert!(fragments.iter().all(|fragment| fragment.channel == "chat"));
+        fragments.reverse();
+        let source = PeerId::random();
+        let mut reassembler = Reassembler::default();
+        let last = fragments.len() - 1;
+        for (n, fragment) in fragments.into_iter().enumerate() {
+            match reassembler.add(source, fragment, n).unwrap() {
+                Some((bytes, held)) => {
+                    assert_eq!(n, last);
+                    assert_eq!(bytes, message.encode().1);
+                    assert_eq!(held, (0..=last).collect::<Vec<_>>());
+                }
+                None => assert!(n < last),
+            }
+        }
+    }
+
+    #[test]
+    fn keeps_sources_apart() {
+        let message = published(&"x".repeat(10_000));
+        let fragments = fragments(&message);
+        let mut reassembler = Reassembler::default();
+        let (one, other) = (PeerId::random(), PeerId::random());
+        for fragment in &fragments[1..] {
+            assert_eq!(reassembler.add(one, fragment.clone(), ()), Ok(None));
+        }
+        // The rest of it from another source does not complete it
+        assert_eq!(reassembler.add(other, fragments[0].clone(), ()), Ok(None));
+        assert!(reassembler.add(one, fragments[0].clone(), ()).unwrap().is_some());
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let fragments = fragments(&published(&"x".repeat(10_000)));
+        let source = PeerId::random();
+        let mut reassembler = Reassembler::default();
+        assert_eq!(reassembler.add(source, fragments[0].clone(), ()), Ok(None));
+        assert!(reassembler.add(source, fragments[0].clone(), ()).is_err());
+    }
+
+    #[test]
+    fn rejects_what_does_not_fit() {
+        let fragments = fragments(&published(&"x".repeat(10_000)));
+        let source = PeerId::random();
+        let mut reassembler = Reassembler::default();
+        let mut out_of_range = fragments[0].clone();
+        out_of_range.index = out_of_range.count;
+        assert!(reassembler.add(source, out_of_range, ()).is_err());
+        let mut single = fragments[0].clone();
+        single.count = 1;
+        assert!(reassembler.add(source, single, ()).is_err());
+        let mut too_many = fragments[0].clone();
+        too_many.count = MAX_FRAGMENTS as u32 + 1;
+        assert!(reassembler.add(source, too_many, ()).is_err());
+        assert_eq!(reassembler.add(source, fragments[0].clone(), ()), Ok(None));
+        let mut miscounted = fragments[1].clone();
+        miscounted.count += 1;
+        assert!(reassembler.add(source, miscounted, ()).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_message() {
+        let mut fragments = fragments(&published(&"x".repeat(10_000)));
+        fragments[0].data[0] ^= 1;
+        let source = PeerId::random();
+        let mut reassembler = Reassembler::default();
+        let results: Vec<_> = fragments
+            .into_iter()
+            .map(|fragment| reassembler.add(source, fragment, ()))
+            .collect();
+        assert!(results.last().unwrap().is_err());
+    }
+
+    #[test]
+    fn limits_messages_per_source() {
+        let source = PeerId::random();
+        let mut reassembler = Reassembler::default();
+        for n in 0..MAX_PENDING_PER_SOURCE {
+            let fragments = fragments(&published(&n.to_string().repeat(10_000)));
+            assert_eq!(reassembler.add(source, fragments[0].clone(), ()), Ok(None));
+        }
+        let fragments = fragments(&published(&"y".repeat(10_000)));
+        assert!(reassembler.add(source, fragments[0].clone(), ()).is_err());
+        assert_eq!(reassembler.add(PeerId::random(), fragments[0].clone(), ()), Ok(None));
+    }
+
+    #[test]
+    fn limits_messages_at_once() {
+        let mut reassembler = Reassembler::default();
+        let fragments: Vec<_> = (0..=MAX_PENDING)
+            .map(|n| fragments(&published(&n.to_string().repeat(10_000))))
+            .collect();
+        let sources: Vec<_> = fragments.iter().map(|_| PeerId::random()).collect();
+        for (fragments, source) in fragments.iter().zip(&sources) {
+            assert_eq!(reassembler.add(*source, fragments[0].clone(), ()), Ok(None));
+        }
+        assert_eq!(reassembler.pending.len(), MAX_PENDING);
+        // The oldest one made way
+        assert!(!reassembler.pending.keys().any(|(source, _)| *source == sources[0]));
+    }
+
+    #[test]
+    fn refuses_too_large_message() {
+        let message = published(&"x".repeat(MAX_MESSAGE_SIZE));
+        assert!(split(&message, MAX_TRANSMIT_SIZE).is_err());
+    }
+}
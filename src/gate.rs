@@ -0,0 +1,677 @@
+//! Deciding which connections are let in or out.
+//!
+//! A [`ConnectionGater`] is asked before we dial an address, before we start
+//! the handshake on an inbound connection, and once the handshake proved who
+//! the remote peer is but before the swarm gets to use the connection.
+//! [`Policy`] covers the usual cases: allowlisted networks, transports and
+//! peers, and [`Rule`]s against many connections from a single network.
+
+use core::task::{Context as TaskContext, Poll};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::Infallible,
+    error::Error,
+    fmt, io,
+    net::IpAddr,
+    pin::Pin,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{anyhow, bail, Context};
+use futures::{future::{self, BoxFuture}, prelude::*, stream::BoxStream};
+use libp2p::{
+    core::{
+        connection::ConnectionId,
+        either::EitherError,
+        transport::{ListenerEvent, TransportError},
+        ConnectedPoint, Endpoint,
+    },
+    multiaddr::Protocol,
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, PeerId, Transport,
+};
+
+use crate::Config;
+
+/// Allows or denies connections. Every check allows by default.
+pub trait ConnectionGater: Send + Sync {
+    /// Whether to dial `addr`, checked before connecting.
+    fn allow_dial(&self, _addr: &Multiaddr) -> bool {
+        true
+    }
+
+    /// Whether to accept a connection from `remote_addr` on `local_addr`,
+    /// checked before any byte is exchanged.
+    fn allow_accept(&self, _local_addr: &Multiaddr, _remote_addr: &Multiaddr) -> bool {
+        true
+    }
+
+    /// Whether to keep a connection to `peer_id`, checked once the security
+    /// handshake proved its identity.
+    fn allow_peer(&self, _peer_id: &PeerId, _endpoint: &ConnectedPoint) -> bool {
+        true
+    }
+
+    /// Called when a connection the gater allowed is put to use.
+    fn connected(&self, _peer_id: &PeerId, _endpoint: &ConnectedPoint) {}
+
+    /// Called when a connection passed to `connected` closed.
+    fn disconnected(&self, _peer_id: &PeerId, _endpoint: &ConnectedPoint) {}
+
+    /// Called once a connection to or from `addr` allowed by `allow_dial` or
+    /// `allow_accept` is gone, whether it failed before being put to use or
+    /// closed later.
+    fn released(&self, _addr: &Multiaddr) {}
+}
+
+/// The gater from `config`, or one allowing everything.
+pub(crate) fn of(config: &Config) -> Arc<dyn ConnectionGater> {
+    match &config.gater {
+        Some(gater) => gater.clone(),
+        None => Arc::new(Policy::default()),
+    }
+}
+
+/// Error of a connection refused by the [`ConnectionGater`].
+#[derive(Debug)]
+pub struct ConnectionDenied;
+
+impl fmt::Display for ConnectionDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("connection refused by the connection gater")
+    }
+}
+
+impl Error for ConnectionDenied {}
+
+/// A gater allowing what matches every non-empty allowlist, and no denied
+/// peer or rule.
+#[derive(Debug, Default)]
+pub struct Policy {
+    /// Networks addresses must be in. Addresses without an IP, such as DNS
+    /// names, are refused once this is set.
+    pub networks: Vec<IpNetwork>,
+    /// Protocols addresses must use one of, like `tcp`, `ws` or `onion3`.
+    pub transports: Vec<String>,
+    /// Peers we talk to, everyone when empty.
+    pub allowed_peers: HashSet<PeerId>,
+    /// Peers we never talk to.
+    pub denied_peers: HashSet<PeerId>,
+    pub rules: Vec<Rule>,
+    // Connections per remote address from when they are dialed or accepted
+    // until they fail or close, for `Rule::MaxPerSubnet`
+    connections: Mutex<HashMap<IpAddr, usize>>,
+}
+
+impl Policy {
+    /// Whether the policy allows every connection.
+    pub fn is_open(&self) -> bool {
+        self.networks.is_empty()
+            && self.transports.is_empty()
+            && self.allowed_peers.is_empty()
+            && self.denied_peers.is_empty()
+            && self.rules.is_empty()
+    }
+
+    fn allow_address(&self, addr: &Multiaddr, endpoint: Endpoint) -> bool {
+        let ip = ip_of(addr);
+        let in_network = self.networks.is_empty()
+            || ip.is_some_and(|ip| self.networks.iter().any(|network| network.contains(ip)));
+        let on_transport = self.transports.is_empty()
+            || addr
+                .iter()
+                .any(|protocol| self.transports.contains(&protocol_name(&protocol)));
+        if !in_network || !on_transport {
+            return false;
+        }
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return true,
+        };
+        // Count the connection right away, so that those still being set up
+        // are counted against the rules
+        let mut connections = self.connections.lock().expect("connection counts poisoned");
+        if !self.allow_ip(ip, endpoint, &connections) {
+            return false;
+        }
+        *connections.entry(ip).or_default() += 1;
+        true
+    }
+
+    fn allow_ip(
+        &self,
+        ip: IpAddr,
+        endpoint: Endpoint,
+        connections: &HashMap<IpAddr, usize>,
+    ) -> bool {
+        self.rules.iter().all(|rule| match rule {
+            Rule::Deny { network, direction } => {
+                !network.contains(ip) || direction.is_some_and(|direction| direction != endpoint)
+            }
+            Rule::MaxPerSubnet { max, prefix } => {
+                let subnet = IpNetwork::of(ip, *prefix);
+                let open: usize = connections
+                    .iter()
+                    .filter(|(other, _)| subnet.contains(**other))
+                    .map(|(_, count)| count)
+                    .sum();
+                open < *max
+            }
+        })
+    }
+}
+
+impl ConnectionGater for Policy {
+    fn allow_dial(&self, addr: &Multiaddr) -> bool {
+        self.allow_address(addr, Endpoint::Dialer)
+    }
+
+    fn allow_accept(&self, _local_addr: &Multiaddr, remote_addr: &Multiaddr) -> bool {
+        self.allow_address(remote_addr, Endpoint::Listener)
+    }
+
+    fn allow_peer(&self, peer_id: &PeerId, _endpoint: &ConnectedPoint) -> bool {
+        !self.denied_peers.contains(peer_id)
+            && (self.allowed_peers.is_empty() || self.allowed_peers.contains(peer_id))
+    }
+
+    fn released(&self, addr: &Multiaddr) {
+        if let Some(ip) = ip_of(addr) {
+            let mut connections = self.connections.lock().expect("connection counts poisoned");
+            if let Some(count) = connections.get_mut(&ip) {
+                *count -= 1;
+                if *count == 0 {
+                    connections.remove(&ip);
+                }
+            }
+        }
+    }
+}
+
+/// A rule of a [`Policy`], written as `deny <CIDR> [inbound|outbound]` or
+/// `max <N> [connections] per /<PREFIX>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rule {
+    /// Refuse connections with addresses in `network`, only those in one
+    /// direction if set.
+    Deny {
+        network: IpNetwork,
+        direction: Option<Endpoint>,
+    },
+    /// Refuse connections once `max` are open or being set up with addresses
+    /// sharing the first `prefix` bits.
+    MaxPerSubnet { max: usize, prefix: u8 },
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let rule = match words[..] {
+            ["deny", network] => Rule::Deny {
+                network: network.parse()?,
+                direction: None,
+            },
+            ["deny", network, direction] => Rule::Deny {
+                network: network.parse()?,
+                direction: Some(match direction {
+                    "inbound" => Endpoint::Listener,
+                    "outbound" => Endpoint::Dialer,
+                    _ => bail!("expected inbound or outbound in rule {:?}", s),
+                }),
+            },
+            ["max", max, "per", prefix] | ["max", max, "connections", "per", prefix] => {
+                Rule::MaxPerSubnet {
+                    max: max
+                        .parse()
+                        .with_context(|| format!("invalid count in rule {:?}", s))?,
+                    prefix: prefix
+                        .strip_prefix('/')
+                        .and_then(|prefix| prefix.parse().ok())
+                        .filter(|prefix| *prefix <= 128)
+                        .ok_or_else(|| anyhow!("invalid prefix length in rule {:?}", s))?,
+                }
+            }
+            _ => bail!(
+                "invalid rule {:?}, expected `deny <CIDR> [inbound|outbound]` or `max <N> per /<PREFIX>`",
+                s
+            ),
+        };
+        Ok(rule)
+    }
+}
+
+// The IP address in `addr`, if any.
+fn ip_of(addr: &Multiaddr) -> Option<IpAddr> {
+    addr.iter().find_map(|protocol| match protocol {
+        Protocol::Ip4(ip) => Some(IpAddr::V4(ip)),
+        Protocol::Ip6(ip) => Some(IpAddr::V6(ip)),
+        _ => None,
+    })
+}
+
+// `tcp` for `/tcp/4001`.
+fn protocol_name(protocol: &Protocol<'_>) -> String {
+    let text = protocol.to_string();
+    text.trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default()
+        .to_owned()
+}
+
+/// An IP network such as `10.0.0.0/8`. A plain address is a network of
+/// just that address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNetwork {
+    /// The network of the first `prefix` bits of `addr`, all of them if the
+    /// address is shorter.
+    pub fn of(addr: IpAddr, prefix: u8) -> Self {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        IpNetwork {
+            addr,
+            prefix: prefix.min(max),
+        }
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (addr, prefix) = match s.find('/') {
+            Some(slash) => (&s[..slash], Some(&s[slash + 1..])),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().with_context(|| format!("invalid network {}", s))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| anyhow!("invalid prefix length in network {}", s))?,
+            None => max,
+        };
+        Ok(IpNetwork { addr, prefix })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+/// A transport that asks the gater before dialing and before handing out
+/// inbound connections, and tells it once they are gone.
+#[derive(Clone)]
+pub(crate) struct Gated<T> {
+    inner: T,
+    gater: Arc<dyn ConnectionGater>,
+}
+
+impl<T> Gated<T> {
+    pub(crate) fn new(inner: T, gater: Arc<dyn ConnectionGater>) -> Self {
+        Gated { inner, gater }
+    }
+}
+
+type GatedError<T> = EitherError<ConnectionDenied, <T as Transport>::Error>;
+type GatedFuture<T> =
+    BoxFuture<'static, Result<Counted<<T as Transport>::Output>, GatedError<T>>>;
+
+impl<T> Transport for Gated<T>
+where
+    T: Transport,
+    T::Output: Send + 'static,
+    T::Error: Send + 'static,
+    T::Listener: Send + 'static,
+    T::ListenerUpgrade: Send + 'static,
+    T::Dial: Send + 'static,
+{
+    type Output = Counted<T::Output>;
+    type Error = GatedError<T>;
+    type Listener =
+        BoxStream<'static, Result<ListenerEvent<Self::ListenerUpgrade, Self::Error>, Self::Error>>;
+    type ListenerUpgrade = GatedFuture<T>;
+    type Dial = GatedFuture<T>;
+
+    fn listen_on(self, addr: Multiaddr) -> Result<Self::Listener, TransportError<Self::Error>> {
+        let gater = self.gater;
+        let listener = self.inner.listen_on(addr).map_err(|e| e.map(EitherError::B))?;
+        let listener = listener.map_err(EitherError::B).try_filter_map(move |event| {
+            let event = match event {
+                ListenerEvent::Upgrade {
+                    upgrade,
+                    local_addr,
+                    remote_addr,
+                } => {
+                    // Dropping the upgrade closes the connection
+                    if !gater.allow_accept(&local_addr, &remote_addr) {
+                        log::info!("refused connection from {}", remote_addr);
+                        return future::ready(Ok(None));
+                    }
+                    let slot = Slot {
+                        gater: gater.clone(),
+                        addr: remote_addr.clone(),
+                    };
+                    ListenerEvent::Upgrade {
+                        upgrade: counted(upgrade, slot),
+                        local_addr,
+                        remote_addr,
+                    }
+                }
+                ListenerEvent::NewAddress(addr) => ListenerEvent::NewAddress(addr),
+                ListenerEvent::AddressExpired(addr) => ListenerEvent::AddressExpired(addr),
+                ListenerEvent::Error(e) => ListenerEvent::Error(EitherError::B(e)),
+            };
+            future::ready(Ok(Some(event)))
+        });
+        Ok(listener.boxed())
+    }
+
+    fn dial(self, addr: Multiaddr) -> Result<Self::Dial, TransportError<Self::Error>> {
+        if !self.gater.allow_dial(&addr) {
+            log::info!("refused to dial {}", addr);
+            return Err(TransportError::Other(EitherError::A(ConnectionDenied)));
+        }
+        let slot = Slot {
+            gater: self.gater,
+            addr: addr.clone(),
+        };
+        let dial = self.inner.dial(addr).map_err(|e| e.map(EitherError::B))?;
+        Ok(counted(dial, slot))
+    }
+
+    fn address_translation(&self, listen: &Multiaddr, observed: &Multiaddr) -> Option<Multiaddr> {
+        self.inner.address_translation(listen, observed)
+    }
+}
+
+// Holds the connection's place with the gater until dropped, wherever it
+// failed or closed.
+struct Slot {
+    gater: Arc<dyn ConnectionGater>,
+    addr: Multiaddr,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.gater.released(&self.addr);
+    }
+}
+
+// The connection `future` sets up, holding `slot` for as long as it lives.
+fn counted<F, O, E>(
+    future: F,
+    slot: Slot,
+) -> BoxFuture<'static, Result<Counted<O>, EitherError<ConnectionDenied, E>>>
+where
+    F: Future<Output = Result<O, E>> + Send + 'static,
+    O: 'static,
+    E: 'static,
+{
+    future
+        .map_ok(move |inner| Counted { inner, _slot: slot })
+        .map_err(EitherError::B)
+        .boxed()
+}
+
+/// A connection the [`ConnectionGater`] is told about once it is dropped.
+pub(crate) struct Counted<S> {
+    inner: S,
+    _slot: Slot,
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Counted<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Counted<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Tells the gater which connections are open, and that they all closed
+/// once the swarm is dropped.
+pub(crate) struct Tracker {
+    gater: Arc<dyn ConnectionGater>,
+    open: HashMap<ConnectionId, (PeerId, ConnectedPoint)>,
+}
+
+impl Tracker {
+    pub(crate) fn new(gater: Arc<dyn ConnectionGater>) -> Self {
+        Tracker {
+            gater,
+            open: HashMap::new(),
+        }
+    }
+}
+
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        for (peer_id, endpoint) in self.open.values() {
+            self.gater.disconnected(peer_id, endpoint);
+        }
+    }
+}
+
+impl NetworkBehaviour for Tracker {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = Infallible;
+
+    fn new_handler(&mut self) -> DummyProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _: &PeerId) {}
+
+    fn inject_disconnected(&mut self, _: &PeerId) {}
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.gater.connected(peer_id, endpoint);
+        self.open.insert(*id, (*peer_id, endpoint.clone()));
+    }
+
+    fn inject_connection_closed(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        self.gater.disconnected(peer_id, endpoint);
+        self.open.remove(id);
+    }
+
+    fn inject_address_change(
+        &mut self,
+        peer_id: &PeerId,
+        id: &ConnectionId,
+        old: &ConnectedPoint,
+        new: &ConnectedPoint,
+    ) {
+        self.gater.disconnected(peer_id, old);
+        self.gater.connected(peer_id, new);
+        self.open.insert(*id, (*peer_id, new.clone()));
+    }
+
+    fn inject_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: <DummyProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut TaskContext<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<<DummyProtocolsHandler as ProtocolsHandler>::InEvent, Infallible>,
+    > {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_networks() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(network.to_string(), "10.0.0.0/8");
+        assert_eq!("10.1.2.3".parse::<IpNetwork>().unwrap().to_string(), "10.1.2.3/32");
+        assert_eq!("fd00::/8".parse::<IpNetwork>().unwrap().to_string(), "fd00::/8");
+        assert_eq!("::1".parse::<IpNetwork>().unwrap().to_string(), "::1/128");
+        assert!("10.0.0.0/33".parse::<IpNetwork>().is_err());
+        assert!("10.0.0.0/x".parse::<IpNetwork>().is_err());
+        assert!("fd00::/129".parse::<IpNetwork>().is_err());
+        assert!("example.com/8".parse::<IpNetwork>().is_err());
+    }
+
+    #[test]
+    fn networks_contain_their_addresses() {
+        let network: IpNetwork = "10.0.0.0/8".parse().unwrap();
+        assert!(network.contains(ip("10.255.0.1")));
+        assert!(!network.contains(ip("11.0.0.1")));
+        assert!(!network.contains(ip("::ffff:10.0.0.1")));
+        let single: IpNetwork = "192.168.1.2".parse().unwrap();
+        assert!(single.contains(ip("192.168.1.2")));
+        assert!(!single.contains(ip("192.168.1.3")));
+        let everything: IpNetwork = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(ip("203.0.113.7")));
+        let v6: IpNetwork = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        assert!(!v6.contains(ip("fe80::1")));
+        assert!(IpNetwork::of(ip("10.1.2.3"), 64).contains(ip("10.1.2.3")));
+        assert!(!IpNetwork::of(ip("10.1.2.3"), 64).contains(ip("10.1.2.4")));
+    }
+
+    #[test]
+    fn parses_rules() {
+        let network = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(
+            "deny 10.0.0.0/8".parse::<Rule>().unwrap(),
+            Rule::Deny {
+                network,
+                direction: None
+            }
+        );
+        assert_eq!(
+            "deny 10.0.0.0/8 inbound".parse::<Rule>().unwrap(),
+            Rule::Deny {
+                network,
+                direction: Some(Endpoint::Listener)
+            }
+        );
+        assert_eq!(
+            "deny 10.0.0.0/8 outbound".parse::<Rule>().unwrap(),
+            Rule::Deny {
+                network,
+                direction: Some(Endpoint::Dialer)
+            }
+        );
+        let max = Rule::MaxPerSubnet { max: 5, prefix: 24 };
+        assert_eq!("max 5 per /24".parse::<Rule>().unwrap(), max);
+        assert_eq!("max 5 connections per /24".parse::<Rule>().unwrap(), max);
+        for invalid in [
+            "",
+            "allow 10.0.0.0/8",
+            "deny 10.0.0.0/8 sideways",
+            "max five per /24",
+            "max 5 per 24",
+            "max 5 per /129",
+        ] {
+            assert!(invalid.parse::<Rule>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn denies_networks_by_direction() {
+        let policy = Policy {
+            rules: vec!["deny 10.0.0.0/8 inbound".parse().unwrap()],
+            ..Policy::default()
+        };
+        let local = "/ip4/0.0.0.0/tcp/4001".parse().unwrap();
+        let addr = "/ip4/10.0.0.1/tcp/4001".parse().unwrap();
+        assert!(!policy.allow_accept(&local, &addr));
+        assert!(policy.allow_dial(&addr));
+    }
+
+    #[test]
+    fn counts_connections_being_set_up() {
+        let policy = Policy {
+            rules: vec!["max 2 per /24".parse().unwrap()],
+            ..Policy::default()
+        };
+        let local = "/ip4/0.0.0.0/tcp/4001".parse().unwrap();
+        let one = "/ip4/192.0.2.1/tcp/4001".parse().unwrap();
+        let other = "/ip4/192.0.2.2/tcp/4001".parse().unwrap();
+        assert!(policy.allow_dial(&one));
+        assert!(policy.allow_accept(&local, &other));
+        assert!(!policy.allow_dial(&other));
+        assert!(policy.allow_dial(&"/ip4/192.0.3.1/tcp/4001".parse().unwrap()));
+        policy.released(&one);
+        assert!(policy.allow_accept(&local, &one));
+    }
+}
@@ -0,0 +1,271 @@
+//! Driving a [`Node`] from anywhere.
+//!
+//! [`Node::spawn`] moves a node onto a task of its own, which polls it and in
+//! between runs what its [`NodeHandle`]s ask for. Handles are cheap to clone
+//! and only talk to that task over channels, so none of them can hold it up:
+//! a request is carried out or not at all whenever its future is dropped,
+//! only its answer being lost, and a subscriber to [`NodeHandle::events`]
+//! falling more than [`EVENT_BUFFER`] events behind misses the next ones
+//! instead of stalling the swarm. The task shuts the node down on
+//! [`NodeHandle::shutdown`] or once the last handle is dropped.
+
+use std::{
+    panic::{self, AssertUnwindSafe},
+    sync::{Arc, Mutex},
+    task::Poll,
+    time::Duration,
+};
+
+use async_std::task;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+};
+
+use crate::{Error, Node, NodeEvent};
+
+/// Events kept for a subscriber that has not taken them yet.
+pub const EVENT_BUFFER: usize = 256;
+
+// Requests waiting for the node task before asking blocks
+const COMMAND_BUFFER: usize = 64;
+// How long peers are given to hear that we leave, then that we hang up, as
+// the command line client does
+const LINGER: Duration = Duration::from_millis(500);
+const HANG_UP: Duration = Duration::from_millis(100);
+
+type Job = Box<dyn FnOnce(&mut Node) + Send>;
+type Subscribers = Arc<Mutex<Vec<mpsc::Sender<Arc<NodeEvent>>>>>;
+
+enum Command {
+    Call(Job),
+    Shutdown(oneshot::Sender<Result<(), Error>>),
+}
+
+/// A cheap to clone handle to a [`Node`] running on a task of its own, see
+/// [`Node::spawn`].
+#[derive(Clone)]
+pub struct NodeHandle {
+    commands: mpsc::Sender<Command>,
+    subscribers: Subscribers,
+}
+
+impl NodeHandle {
+    /// Run `f` on the node, between two events, and hand back what it
+    /// returns. Fails once the node shut down.
+    pub async fn call<F, R>(&self, f: F) -> Result<R, Error>
+    where
+        F: FnOnce(&mut Node) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (reply, answer) = oneshot::channel();
+        let job: Job = Box::new(move |node| {
+            // Nobody to tell if the caller gave up waiting
+            let _ = reply.send(f(node));
+        });
+        self.commands.clone().send(Command::Call(job)).await.map_err(|_| stopped())?;
+        answer.await.map_err(|_| Error::transport("the node gave no answer"))
+    }
+
+    /// Publish a chat line on a joined channel, see [`Node::publish`].
+    pub async fn send(&self, channel: &str, content: impl Into<String>) -> Result<String, Error> {
+        let channel = channel.to_owned();
+        let content = content.into();
+        self.call(move |node| node.publish(&channel, content)).await?
+    }
+
+    /// Every event of the node from now on, until it shut down. Events are
+    /// dropped rather than waited for once [`EVENT_BUFFER`] of them are
+    /// left unread.
+    pub fn events(&self) -> impl Stream<Item = Arc<NodeEvent>> + Send + Unpin {
+        let (sender, events) = mpsc::channel(EVENT_BUFFER);
+        self.subscribers.lock().expect("subscribers lock").push(sender);
+        events
+    }
+
+    /// Leave every channel, let peers know and hang up, see
+    /// [`Node::shutdown`]. Done once the node is gone, which it keeps going
+    /// towards if this future is dropped. Every handle fails from then on.
+    pub async fn shutdown(&self) -> Result<(), Error> {
+        let (reply, done) = oneshot::channel();
+        if self.commands.clone().send(Command::Shutdown(reply)).await.is_err() {
+            // Gone already
+            return Ok(());
+        }
+        done.await.unwrap_or(Ok(()))
+    }
+}
+
+fn stopped() -> Error {
+    Error::transport("the node has shut down")
+}
+
+pub(crate) fn spawn(node: Node) -> NodeHandle {
+    let (commands, requests) = mpsc::channel(COMMAND_BUFFER);
+    let subscribers = Subscribers::default();
+    task::spawn(drive(node, requests, subscribers.clone()));
+    NodeHandle {
+        commands,
+        subscribers,
+    }
+}
+
+enum Step {
+    Command(Option<Command>),
+    Event(Option<NodeEvent>),
+}
+
+// Poll the node and run what handles ask for until one of them asks to shut
+// down or all of them are gone.
+async fn drive(mut node: Node, mut requests: mpsc::Receiver<Command>, subscribers: Subscribers) {
+    let reply = loop {
+        let step = future::poll_fn(|cx| {
+            if let Poll::Ready(command) = requests.poll_next_unpin(cx) {
+                return Poll::Ready(Step::Command(command));
+            }
+            node.poll_next_unpin(cx).map(Step::Event)
+        })
+        .await;
+        match step {
+            Step::Command(Some(Command::Call(job))) => {
+                // A panicking caller loses its answer, not the node
+                if panic::catch_unwind(AssertUnwindSafe(|| job(&mut node))).is_err() {
+                    log::error!("a node handle call panicked");
+                }
+            }
+            Step::Command(Some(Command::Shutdown(reply))) => break Some(reply),
+            Step::Command(None) => break None,
+            Step::Event(Some(event)) => publish(&subscribers, event),
+            Step::Event(None) => return,
+        }
+    };
+    requests.close();
+    let result = node.shutdown();
+    if let Err(e) = &result {
+        log::warn!("failed to shut down cleanly: {}", e);
+    }
+    linger(&mut node, &subscribers, LINGER).await;
+    node.close();
+    linger(&mut node, &subscribers, HANG_UP).await;
+    if let Some(reply) = reply {
+        let _ = reply.send(result);
+    }
+}
+
+// Hand an event to every subscriber that still listens.
+fn publish(subscribers: &Subscribers, event: NodeEvent) {
+    let event = Arc::new(event);
+    let mut subscribers = subscribers.lock().expect("subscribers lock");
+    subscribers.retain_mut(|subscriber| match subscriber.try_send(event.clone()) {
+        Ok(()) => true,
+        Err(e) if e.is_full() => {
+            log::debug!("dropped an event for a subscriber falling behind");
+            true
+        }
+        Err(_) => false,
+    });
+}
+
+// Keep the node going for a moment.
+async fn linger(node: &mut Node, subscribers: &Subscribers, duration: Duration) {
+    let events = async {
+        while let Some(event) = node.next().await {
+            publish(subscribers, event);
+        }
+    };
+    let _ = async_std::future::timeout(duration, events).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use libp2p::{identity::Keypair, Multiaddr};
+
+    use super::*;
+    use crate::Config;
+
+    fn node(listen: Multiaddr, dial: Vec<Multiaddr>) -> Node {
+        let mut config = Config::new(Keypair::generate_ed25519());
+        config.listen_addrs = vec![listen];
+        config.dial = dial;
+        task::block_on(Node::new(config)).unwrap()
+    }
+
+    fn loopback(port: u16) -> Multiaddr {
+        format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()
+    }
+
+    #[test]
+    fn runs_calls_and_reports_events() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let alice = node(loopback(port), Vec::new());
+        let local_peer_id = *alice.local_peer_id();
+        let alice = alice.spawn();
+        let bob = node(loopback(0), vec![loopback(port)]).spawn();
+        let exchange = async {
+            let peer_id = alice.call(|node| *node.local_peer_id()).await.unwrap();
+            assert_eq!(peer_id, local_peer_id);
+            let error = bob.send("handles", "hello").await.unwrap_err();
+            assert!(matches!(error, Error::Config(_)), "{:?}", error);
+            let mut events = alice.events();
+            for handle in [&alice, &bob] {
+                assert!(handle.call(|node| node.join("handles")).await.unwrap().unwrap());
+            }
+            // Until bob hears alice joined
+            while let Err(error) = bob.send("handles", "hello").await {
+                assert!(matches!(error, Error::Dial(_)), "{:?}", error);
+                task::sleep(Duration::from_millis(100)).await;
+            }
+            while let Some(event) = events.next().await {
+                if let NodeEvent::Message { message, .. } = &*event {
+                    assert_eq!(message.content, "hello");
+                    break;
+                }
+            }
+        };
+        task::block_on(async_std::future::timeout(Duration::from_secs(20), exchange)).unwrap();
+        task::block_on(async {
+            bob.shutdown().await.unwrap();
+            alice.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn survives_dropped_handles_and_futures() {
+        task::block_on(async {
+            let handle = node(loopback(0), Vec::new()).spawn();
+            // Never polled, or given up on halfway
+            drop(handle.clone().call(|node| node.join("never")));
+            let call = handle.call(|node| node.join("dropped"));
+            let _ = async_std::future::timeout(Duration::from_nanos(1), call).await;
+            let _ = handle.call(|_| panic!("caller bug")).await.unwrap_err();
+            // Subscribers that never read only miss events
+            let unread = handle.events();
+            let mut events = handle.events();
+            drop(handle.events());
+            let clone = handle.clone();
+            drop(handle);
+            let channels = clone.call(|node| node.channels().count()).await.unwrap();
+            assert!(channels >= 1);
+            drop(unread);
+
+            // The node shuts down with the last handle
+            drop(clone);
+            let ended = async { while events.next().await.is_some() {} };
+            async_std::future::timeout(Duration::from_secs(5), ended).await.unwrap();
+        });
+    }
+
+    #[test]
+    fn fails_once_shut_down() {
+        task::block_on(async {
+            let handle = node(loopback(0), Vec::new()).spawn();
+            let other = handle.clone();
+            handle.shutdown().await.unwrap();
+            let error = other.call(|node| node.channels().count()).await.unwrap_err();
+            assert!(matches!(error, Error::Transport(_)), "{:?}", error);
+            other.shutdown().await.unwrap();
+        });
+    }
+}
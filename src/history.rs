@@ -0,0 +1,102 @@
+//! Every chat message sent or received, kept on disk across restarts.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::Published;
+
+/// Where history is kept unless another path is given:
+/// `$XDG_DATA_HOME/pingpong-p2p/history`, falling back to
+/// `~/.local/share/pingpong-p2p/history`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|dir| dir.join("pingpong-p2p").join("history"))
+}
+
+/// Messages keyed by the order they were stored in. Clones share the store.
+#[derive(Clone)]
+pub(crate) struct History {
+    db: sled::Db,
+}
+
+impl History {
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("failed to open history at {}", path.display()))?;
+        Ok(History { db })
+    }
+
+    /// Store a message, which sled writes to disk within half a second, or
+    /// once [`History::flush`] is called.
+    pub(crate) fn append(&self, message: &Published) -> anyhow::Result<()> {
+        // Big endian ids sort in insertion order
+        let key = self.db.generate_id()?.to_be_bytes();
+        self.db.insert(key, message.store())?;
+        Ok(())
+    }
+
+    /// Make sure everything stored so far is on disk.
+    pub(crate) fn flush(&self) -> anyhow::Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// The last `n` messages, oldest first. Entries that fail to decode are
+    /// skipped.
+    pub(crate) fn last(&self, n: usize) -> anyhow::Result<Vec<Published>> {
+        let mut messages = Vec::with_capacity(n);
+        for entry in self.db.iter().rev() {
+            if messages.len() == n {
+                break;
+            }
+            let (_, value) = entry?;
+            match Published::load(value.to_vec()) {
+                Some(message) => messages.push(message),
+                None => log::warn!("skipping unreadable history entry"),
+            }
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+
+    /// Up to `n` messages of `channel` stored before the one with digest
+    /// `before`, or the last ones with `before` empty, oldest first. Nothing
+    /// if `before` is not stored. Entries that fail to decode are skipped.
+    pub(crate) fn page(
+        &self,
+        channel: &str,
+        before: &[u8],
+        n: usize,
+    ) -> anyhow::Result<Vec<Published>> {
+        let mut found = before.is_empty();
+        let mut messages = Vec::new();
+        for entry in self.db.iter().rev() {
+            if messages.len() == n {
+                break;
+            }
+            let (_, value) = entry?;
+            let message = match Published::load(value.to_vec()) {
+                Some(message) => message,
+                None => continue,
+            };
+            if !found {
+                found = message.digest() == before;
+            } else if message.channel == channel {
+                messages.push(message);
+            }
+        }
+        messages.reverse();
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+impl History {
+    pub(crate) fn temporary() -> Self {
+        let db = sled::Config::new().temporary(true).open().expect("temporary sled");
+        History { db }
+    }
+}
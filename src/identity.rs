@@ -0,0 +1,58 @@
+//! Persistent node identity.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context;
+use libp2p::identity::{ed25519, Keypair};
+use zeroize::Zeroize;
+
+/// Where the identity key lives unless another path is given:
+/// `$XDG_CONFIG_HOME/pingpong-p2p/identity.key`, falling back to
+/// `~/.config/pingpong-p2p/identity.key`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        .map(|dir| dir.join("pingpong-p2p").join("identity.key"))
+}
+
+/// Load the ed25519 keypair stored at `path`, generating and persisting a new
+/// one if the file does not exist yet.
+pub fn load_or_create(path: &Path) -> anyhow::Result<Keypair> {
+    match fs::read(path) {
+        Ok(mut bytes) => {
+            let keypair = ed25519::Keypair::decode(&mut bytes)
+                .with_context(|| format!("invalid identity key in {}", path.display()));
+            bytes.zeroize();
+            Ok(Keypair::Ed25519(keypair?))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let keypair = ed25519::Keypair::generate();
+            save(path, &keypair)
+                .with_context(|| format!("failed to save identity key to {}", path.display()))?;
+            log::info!("created new identity key at {}", path.display());
+            Ok(Keypair::Ed25519(keypair))
+        }
+        Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+    }
+}
+
+fn save(path: &Path, keypair: &ed25519::Keypair) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    // The file holds the secret key, keep it private to the user
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path)?;
+    let mut bytes = keypair.encode();
+    let result = file.write_all(&bytes).and_then(|_| file.sync_all());
+    bytes.zeroize();
+    result
+}
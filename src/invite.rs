@@ -0,0 +1,231 @@
+//! Invite strings, which let someone connect to us and join a channel in one
+//! step.
+//!
+//! An invite holds our peer id, a few addresses we might be reached at and
+//! the channel to join, encoded as the `Invite` message of `proto/chat.proto`
+//! in base58 behind a `pingpong:` prefix. Addresses others told us they see us
+//! at come first, then public ones, then those only reachable on the local
+//! network or machine.
+//!
+//! An invite can also be shown as a QR code, for a phone to scan. Reading
+//! one back finds the invite in whatever text it is pasted with.
+
+use std::{
+    convert::TryFrom,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use anyhow::{bail, Context};
+use libp2p::{multiaddr::Protocol, Multiaddr, PeerId};
+use prost::Message;
+use qrcode::{render::unicode::Dense1x2, QrCode};
+
+use crate::{message, redial::without_peer};
+
+// Put in front of every invite, so it can be told apart from other strings
+const PREFIX: &str = "pingpong:";
+
+// Addresses put in an invite at most, to keep it short enough to paste
+const MAX_ADDRESSES: usize = 4;
+
+/// Who to connect to and what to join.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Invite {
+    pub peer_id: PeerId,
+    /// Best first, without our peer id.
+    pub addresses: Vec<Multiaddr>,
+    pub channel: String,
+}
+
+impl Invite {
+    /// An invite to `channel`, picking the best of the addresses others see
+    /// us at and those we listen on.
+    pub fn new(
+        peer_id: PeerId,
+        external: impl IntoIterator<Item = Multiaddr>,
+        listening: impl IntoIterator<Item = Multiaddr>,
+        channel: &str,
+    ) -> Self {
+        let mut listening: Vec<_> = listening.into_iter().map(|a| without_peer(&a)).collect();
+        listening.sort_by_key(reach);
+        let mut addresses = Vec::new();
+        let external = external.into_iter().map(|a| without_peer(&a));
+        for address in external.chain(listening) {
+            let usable = reach(&address) != Reach::Nowhere;
+            if usable && !addresses.contains(&address) && addresses.len() < MAX_ADDRESSES {
+                addresses.push(address);
+            }
+        }
+        Invite {
+            peer_id,
+            addresses,
+            channel: channel.to_owned(),
+        }
+    }
+
+    /// The invite as a QR code drawn with half blocks, two rows per line.
+    /// It is drawn for terminals with a dark background, light modules as
+    /// blocks, which is what scanners expect.
+    pub fn qr_code(&self) -> anyhow::Result<String> {
+        let code = QrCode::new(self.to_string()).context("invite too long for a QR code")?;
+        Ok(code
+            .render::<Dense1x2>()
+            .dark_color(Dense1x2::Light)
+            .light_color(Dense1x2::Dark)
+            .build())
+    }
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let invite = message::Invite {
+            peer_id: self.peer_id.to_bytes(),
+            addresses: self.addresses.iter().map(|address| address.to_vec()).collect(),
+            channel: self.channel.clone(),
+        };
+        write!(f, "{}{}", PREFIX, bs58::encode(message::encode(&invite)).into_string())
+    }
+}
+
+impl FromStr for Invite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        // Take the invite out of a pasted line or a scanned text
+        let data = s
+            .find(PREFIX)
+            .map(|start| &s[start + PREFIX.len()..])
+            .map(|data| data.split(|c: char| !c.is_ascii_alphanumeric()).next().unwrap_or(""))
+            .and_then(|data| bs58::decode(data).into_vec().ok())
+            .context("not an invite")?;
+        let invite = message::Invite::decode(&*data).context("not an invite")?;
+        let peer_id = PeerId::from_bytes(&invite.peer_id).context("invalid peer id in invite")?;
+        let addresses = invite
+            .addresses
+            .into_iter()
+            .map(Multiaddr::try_from)
+            .collect::<Result<Vec<_>, _>>()
+            .context("invalid address in invite")?;
+        if addresses.is_empty() {
+            bail!("invite has no addresses");
+        }
+        if invite.channel.is_empty() {
+            bail!("invite has no channel");
+        }
+        Ok(Invite {
+            peer_id,
+            addresses,
+            channel: invite.channel,
+        })
+    }
+}
+
+// Who could reach us at an address, most first.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum Reach {
+    Anyone,
+    Network,
+    Machine,
+    // Only good for listening, like 0.0.0.0
+    Nowhere,
+}
+
+fn reach(address: &Multiaddr) -> Reach {
+    let ip = match address.iter().next() {
+        Some(Protocol::Ip4(ip)) => IpAddr::V4(ip),
+        Some(Protocol::Ip6(ip)) => IpAddr::V6(ip),
+        // Names are resolved by whoever dials them
+        _ => return Reach::Anyone,
+    };
+    match ip {
+        ip if ip.is_unspecified() => Reach::Nowhere,
+        ip if ip.is_loopback() => Reach::Machine,
+        IpAddr::V4(ip) if is_local_v4(ip) => Reach::Network,
+        IpAddr::V6(ip) if is_local_v6(ip) => Reach::Network,
+        _ => Reach::Anyone,
+    }
+}
+
+fn is_local_v4(ip: Ipv4Addr) -> bool {
+    // 100.64.0.0/10 is used by carrier-grade NAT
+    let shared = ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64;
+    ip.is_private() || ip.is_link_local() || shared
+}
+
+fn is_local_v6(ip: Ipv6Addr) -> bool {
+    // Unique local fc00::/7 and link-local fe80::/10
+    let first = ip.segments()[0];
+    first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invite() -> Invite {
+        Invite {
+            peer_id: PeerId::random(),
+            addresses: vec![
+                "/ip4/203.0.113.7/tcp/4001".parse().unwrap(),
+                "/dns4/example.com/tcp/443/wss".parse().unwrap(),
+            ],
+            channel: String::from("chat"),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let invite = invite();
+        let text = invite.to_string();
+        assert!(text.starts_with(PREFIX));
+        assert_eq!(text.parse::<Invite>().unwrap(), invite);
+    }
+
+    #[test]
+    fn found_in_pasted_text() {
+        let invite = invite();
+        let text = format!("join us: {}. see you!", invite);
+        assert_eq!(text.parse::<Invite>().unwrap(), invite);
+    }
+
+    #[test]
+    fn rejects_other_strings() {
+        assert!("".parse::<Invite>().is_err());
+        assert!("hello".parse::<Invite>().is_err());
+        assert!("pingpong:0OIl".parse::<Invite>().is_err());
+        let mut empty = invite();
+        empty.addresses.clear();
+        assert!(empty.to_string().parse::<Invite>().is_err());
+        let mut nowhere = invite();
+        nowhere.channel.clear();
+        assert!(nowhere.to_string().parse::<Invite>().is_err());
+    }
+
+    #[test]
+    fn picks_reachable_addresses() {
+        let peer_id = PeerId::random();
+        let external = vec![format!("/ip4/198.51.100.1/tcp/4001/p2p/{}", peer_id)
+            .parse()
+            .unwrap()];
+        let listening = vec![
+            "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+            "/ip4/0.0.0.0/tcp/4001".parse().unwrap(),
+            "/ip4/192.168.1.2/tcp/4001".parse().unwrap(),
+            "/ip4/203.0.113.7/tcp/4001".parse().unwrap(),
+            "/ip4/198.51.100.1/tcp/4001".parse().unwrap(),
+        ];
+        let invite = Invite::new(peer_id, external, listening, "chat");
+        let addresses: Vec<Multiaddr> = [
+            "/ip4/198.51.100.1/tcp/4001",
+            "/ip4/203.0.113.7/tcp/4001",
+            "/ip4/192.168.1.2/tcp/4001",
+            "/ip4/127.0.0.1/tcp/4001",
+        ]
+        .iter()
+        .map(|address| address.parse().unwrap())
+        .collect();
+        assert_eq!(invite.addresses, addresses);
+    }
+}
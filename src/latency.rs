@@ -0,0 +1,59 @@
+//! Round-trip times measured by the ping protocol.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use libp2p::PeerId;
+
+// How many of the latest samples are kept per peer.
+const MAX_SAMPLES: usize = 20;
+
+/// Keeps the latest RTT samples of each peer.
+#[derive(Debug, Default)]
+pub struct LatencyTracker {
+    samples: HashMap<PeerId, VecDeque<Duration>>,
+}
+
+/// Summary of the samples kept for one peer.
+#[derive(Debug, Clone, Copy)]
+pub struct LatencyStats {
+    pub min: Duration,
+    pub avg: Duration,
+    pub max: Duration,
+    pub samples: usize,
+}
+
+impl LatencyTracker {
+    pub fn record(&mut self, peer_id: PeerId, rtt: Duration) {
+        let samples = self.samples.entry(peer_id).or_default();
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(rtt);
+    }
+
+    /// Forget a peer, e.g. once it stopped answering pings.
+    pub fn remove(&mut self, peer_id: &PeerId) {
+        self.samples.remove(peer_id);
+    }
+
+    pub fn stats(&self, peer_id: &PeerId) -> Option<LatencyStats> {
+        let samples = self.samples.get(peer_id)?;
+        let min = *samples.iter().min()?;
+        let max = *samples.iter().max()?;
+        let avg = samples.iter().sum::<Duration>() / samples.len() as u32;
+        Some(LatencyStats {
+            min,
+            avg,
+            max,
+            samples: samples.len(),
+        })
+    }
+
+    /// Every peer with at least one sample.
+    pub fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.samples.keys()
+    }
+}
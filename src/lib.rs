@@ -0,0 +1,995 @@
+//! A small peer-to-peer chat node built on libp2p.
+//!
+//! Peers find each other through mDNS and a Kademlia DHT and exchange signed
+//! [`ChatMessage`]s over gossipsub, one topic per channel, or send each other
+//! [`DirectMessage`]s over a request-response protocol. A [`Node`] is created
+//! from a [`Config`], publishes with [`Node::publish`] and is polled as a
+//! [`Stream`] of [`NodeEvent`]s, or moved onto a task of its own and driven
+//! through [`NodeHandle`]s. What it fails at is told by kind, see [`Error`].
+//!
+//! Everything sent over the wire is defined in `proto/chat.proto`. What is
+//! published over gossipsub is wrapped in an envelope telling its kind and
+//! format version, once every peer on the topic announced it reads them.
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use futures::prelude::*;
+use libp2p::{
+    bandwidth::BandwidthLogging,
+    core::{
+        muxing::StreamMuxerBox,
+        network::ConnectionLimits,
+        transport::{Boxed, OptionalTransport},
+        upgrade,
+        upgrade::SelectUpgrade,
+    },
+    dns::DnsConfig,
+    gossipsub::{error::PublishError, GossipsubConfig, GossipsubConfigBuilder, IdentTopic as Topic},
+    identity::Keypair,
+    mplex::MplexConfig,
+    multiaddr::Protocol,
+    noise::{self, NoiseConfig, X25519Spec},
+    request_response::{OutboundFailure, RequestId},
+    swarm::SwarmBuilder,
+    tcp::TcpConfig,
+    websocket::WsConfig,
+    yamux::YamuxConfig,
+    Multiaddr, PeerId, Swarm, Transport,
+};
+
+pub mod backfill;
+mod behaviour;
+mod bootstrap;
+pub mod broadcast;
+pub mod capabilities;
+mod codec;
+pub mod command;
+pub mod dedup;
+pub mod degree;
+pub mod error;
+mod fragment;
+pub mod gate;
+pub mod handle;
+pub mod history;
+pub mod identity;
+pub mod invite;
+mod latency;
+mod message;
+pub mod metrics;
+pub mod moderation;
+pub mod order;
+pub mod outbox;
+pub mod presence;
+mod providers;
+pub mod quick;
+pub mod ratelimit;
+pub mod receipt;
+pub mod redial;
+pub mod schedule;
+pub mod selftest;
+pub mod seniority;
+pub mod standby;
+pub mod starred;
+pub mod transfer;
+pub mod words;
+
+use behaviour::{Carried, MyBehaviour};
+use capabilities::{Capabilities, Capability, Conversation, Downgrade};
+use dedup::Seen;
+use degree::Budget;
+pub use error::Error;
+use gate::{ConnectionDenied, ConnectionGater, Gated};
+pub use handle::NodeHandle;
+use history::History;
+use invite::Invite;
+pub use latency::{LatencyStats, LatencyTracker};
+pub use message::{ChatMessage, DirectMessage, Forwarded, Fragment, Published};
+use message::Status;
+use metrics::Metrics;
+use moderation::Moderation;
+use order::Order;
+use presence::Roster;
+use receipt::Delivery;
+use schedule::Schedule;
+use seniority::Seniority;
+use starred::Starred;
+use transfer::Direction;
+
+// How many messages are kept in memory to be forwarded and starred.
+const RECENT_MESSAGES: usize = 1000;
+// Most connections kept open with a single identity.
+const MAX_CONNECTIONS_PER_PEER: u32 = 2;
+
+/// Everything needed to start a [`Node`].
+#[derive(Clone)]
+pub struct Config {
+    /// Identity of the node, also used to sign published messages.
+    pub keypair: Keypair,
+    /// Key owning our broadcast channels, the identity key when unset.
+    pub owner_key: Option<Keypair>,
+    /// Name shown to other peers next to our messages. Peers identify us by
+    /// our peer id, so it can change without breaking anything.
+    pub display_name: String,
+    /// Channels to join on startup.
+    pub channels: Vec<String>,
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Peers to dial on startup.
+    pub dial: Vec<Multiaddr>,
+    /// Known DHT peers used to find others outside the local network.
+    pub bootstrap: Vec<(PeerId, Multiaddr)>,
+    /// How many times in a row a lost dialed or bootstrap peer is dialed
+    /// again before giving up on it, zero to never redial. See [`redial`].
+    pub max_redials: u32,
+    /// Gossipsub settings. Messages are only forwarded after we validated
+    /// them if `validate_messages` is set, otherwise forged broadcast
+    /// messages are dropped locally but still relayed.
+    pub gossipsub: GossipsubConfig,
+    /// What the node may spend forwarding channel messages. When set, the
+    /// mesh sizes in `gossipsub` are replaced with ones fitting it and the
+    /// number of peers we know, see [`degree`].
+    pub budget: Option<Budget>,
+    /// Only publish and receive: never forward other peers' channel
+    /// messages to anyone, nor serve them our history. Also the case when
+    /// `budget` pays for nothing.
+    pub leaf: bool,
+    /// How long received messages are remembered so copies of them are
+    /// dropped, see [`dedup`]. Gossipsub keeps its own record of relayed
+    /// messages for `gossipsub.duplicate_cache_time()`, best set to the same.
+    pub dedup_window: Duration,
+    /// Most messages remembered at once, zero to not remember any.
+    pub dedup_capacity: usize,
+    /// Channel messages per second a single peer may publish before the
+    /// rest are dropped, any number when zero. See [`ratelimit`].
+    pub message_rate: f64,
+    /// Channel messages a peer may publish at once, on top of the rate.
+    pub message_burst: u32,
+    /// Directory the messages remembered for `dedup_window` are kept in, so
+    /// they are still dropped after a restart. Kept in memory only when unset.
+    pub seen_path: Option<PathBuf>,
+    /// File starred messages are saved to, kept in memory only when unset.
+    pub starred_path: Option<PathBuf>,
+    /// Directory every sent and received message is stored in, and served
+    /// from to the members of our channels, see [`backfill`]. Messages are
+    /// not persisted when unset.
+    pub history_path: Option<PathBuf>,
+    /// Directory received files are written to, files are refused when unset.
+    pub download_dir: Option<PathBuf>,
+    /// Largest file accepted from a peer, in bytes.
+    pub max_file_size: u64,
+    /// How files we send make way for chat.
+    pub schedule: Schedule,
+    /// Directory recording when each peer identity was first seen, kept in
+    /// memory only when unset.
+    pub peers_path: Option<PathBuf>,
+    /// How long a newly seen identity is trusted less than others to forward
+    /// channel messages, see [`seniority`].
+    pub probation: Duration,
+    /// How long a direct message that could not be delivered waits for its
+    /// recipient to come back, zero to give up right away. See [`outbox`].
+    pub outbox_ttl: Duration,
+    /// How many of the peers we exchange the most direct messages with are
+    /// kept connected, see [`standby`].
+    pub standby: usize,
+    /// Refuse to talk to peers lacking a capability the conversation relies
+    /// on, instead of warning once and carrying on without it.
+    pub strict: bool,
+    /// File the blocked and muted peers are kept in, kept in memory only
+    /// when unset.
+    pub moderation_path: Option<PathBuf>,
+    /// Refuse connections from blocked peers, instead of only dropping what
+    /// they send.
+    pub refuse_blocked: bool,
+    /// Tell authors when we got and read their messages, see [`receipt`].
+    pub receipts: bool,
+    /// Dial and listen on WebSocket addresses as well as plain TCP ones,
+    /// which is how browsers connect.
+    pub websocket: bool,
+    /// Decides which connections are allowed, all of them when unset.
+    pub gater: Option<Arc<dyn ConnectionGater>>,
+}
+
+impl Config {
+    /// Default settings: named "anon" in the "chat" channel, listening on all
+    /// interfaces on an OS-assigned TCP port.
+    pub fn new(keypair: Keypair) -> Self {
+        Config {
+            keypair,
+            owner_key: None,
+            display_name: String::from("anon"),
+            channels: vec![String::from("chat")],
+            listen_addrs: vec!["/ip4/0.0.0.0/tcp/0".parse().expect("valid multiaddr")],
+            dial: Vec::new(),
+            bootstrap: Vec::new(),
+            max_redials: redial::DEFAULT_MAX_REDIALS,
+            gossipsub: GossipsubConfigBuilder::default()
+                .validate_messages()
+                .build()
+                .expect("valid gossipsub config"),
+            budget: None,
+            leaf: false,
+            dedup_window: dedup::DEFAULT_WINDOW,
+            dedup_capacity: dedup::DEFAULT_CAPACITY,
+            message_rate: ratelimit::DEFAULT_RATE,
+            message_burst: ratelimit::DEFAULT_BURST,
+            seen_path: None,
+            starred_path: None,
+            history_path: None,
+            download_dir: None,
+            max_file_size: transfer::DEFAULT_MAX_FILE_SIZE,
+            schedule: Schedule::default(),
+            peers_path: None,
+            probation: seniority::DEFAULT_PROBATION,
+            outbox_ttl: outbox::DEFAULT_TTL,
+            standby: standby::DEFAULT_SIZE,
+            strict: false,
+            moderation_path: None,
+            refuse_blocked: false,
+            receipts: true,
+            websocket: true,
+            gater: None,
+        }
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        PeerId::from(self.keypair.public())
+    }
+
+    /// Peer id of the key owning our broadcast channels.
+    pub fn owner_peer_id(&self) -> PeerId {
+        PeerId::from(self.owner_key().public())
+    }
+
+    /// Whether the node only publishes and receives, see [`Config::leaf`].
+    pub fn is_leaf(&self) -> bool {
+        self.leaf || self.budget.is_some_and(|budget| budget.is_leaf())
+    }
+
+    fn owner_key(&self) -> &Keypair {
+        self.owner_key.as_ref().unwrap_or(&self.keypair)
+    }
+}
+
+/// Something that happened on the network.
+#[derive(Debug)]
+pub enum NodeEvent {
+    /// A chat message was received on one of our channels.
+    Message {
+        source: Option<PeerId>,
+        message: Box<Published>,
+        /// Whether earlier messages from this author on this channel were
+        /// never received.
+        gap: bool,
+        /// How it arrived compared to the author's other messages.
+        order: Order,
+    },
+    /// A peer sent us a direct message.
+    DirectMessage {
+        peer_id: PeerId,
+        message: DirectMessage,
+    },
+    /// A direct message we sent was acknowledged by its recipient.
+    Delivered {
+        peer_id: PeerId,
+        request_id: RequestId,
+    },
+    /// A direct message we sent could not be delivered.
+    NotDelivered {
+        peer_id: PeerId,
+        request_id: RequestId,
+        error: OutboundFailure,
+    },
+    /// A direct message we sent could not be delivered for now, and will be
+    /// sent again once its recipient is back.
+    Queued {
+        peer_id: PeerId,
+        request_id: RequestId,
+    },
+    /// A direct message waited longer than [`Config::outbox_ttl`] for its
+    /// recipient and was dropped.
+    Expired {
+        peer_id: PeerId,
+        request_id: RequestId,
+    },
+    /// A file transfer moved on, reported about every tenth of the file.
+    TransferProgress {
+        peer_id: PeerId,
+        name: String,
+        direction: Direction,
+        done: u64,
+        size: u64,
+    },
+    /// A file we sent was received and verified.
+    FileSent { peer_id: PeerId, name: String },
+    /// A file was received, verified and saved to `path`.
+    FileReceived {
+        peer_id: PeerId,
+        name: String,
+        path: PathBuf,
+    },
+    /// A file transfer was given up on.
+    TransferFailed {
+        peer_id: PeerId,
+        name: String,
+        direction: Direction,
+        error: String,
+    },
+    /// A page of a channel's history, asked for on joining it or with
+    /// [`Node::get_history`], oldest first. Messages we already have are
+    /// left out, and `more` tells whether earlier ones may still be had.
+    History {
+        peer_id: PeerId,
+        request_id: RequestId,
+        channel: String,
+        messages: Vec<Published>,
+        more: bool,
+    },
+    /// A page of history asked for with [`Node::get_history`] could not be
+    /// had.
+    HistoryFailed {
+        peer_id: PeerId,
+        request_id: RequestId,
+        channel: String,
+        error: String,
+    },
+    /// A peer in a conversation lacks a capability it relies on, reported
+    /// once per conversation and peer. Refused instead with [`Config::strict`].
+    Downgraded {
+        conversation: Conversation,
+        downgrade: Downgrade,
+    },
+    /// A peer answered the ping asked for with [`Node::ping`].
+    Pong { peer_id: PeerId, rtt: Duration },
+    /// A ping asked for with [`Node::ping`] failed.
+    PingFailed { peer_id: PeerId, error: String },
+    /// A pingpong peer told us which version it runs and what it supports.
+    PeerIdentified {
+        peer_id: PeerId,
+        agent_version: String,
+        capabilities: Capabilities,
+    },
+    /// A peer joined one of our channels.
+    PeerJoined { peer_id: PeerId, channel: String },
+    /// A peer left one of our channels.
+    PeerLeft { peer_id: PeerId, channel: String },
+    /// A peer announced it is online.
+    PeerOnline {
+        peer_id: PeerId,
+        display_name: String,
+    },
+    /// A peer said goodbye, left or stopped announcing itself.
+    PeerOffline {
+        peer_id: PeerId,
+        display_name: String,
+    },
+    /// The first of the dialed and bootstrap peers answered, `elapsed` after
+    /// we started dialing them all at once.
+    Bootstrapped {
+        peer_id: PeerId,
+        address: Multiaddr,
+        elapsed: Duration,
+    },
+    /// None of the `tried` dialed and bootstrap peers could be reached on
+    /// startup.
+    BootstrapFailed { tried: usize },
+    /// A dialed or bootstrap peer was lost or could not be reached, and
+    /// will be dialed again after `delay`.
+    Redialing {
+        address: Multiaddr,
+        attempt: u32,
+        delay: Duration,
+    },
+    /// A dialed or bootstrap peer could not be reached after
+    /// [`Config::max_redials`] attempts and is no longer redialed.
+    Unreachable { address: Multiaddr, attempts: u32 },
+    /// A peer published more than [`Config::message_rate`] allows, its
+    /// channel messages are dropped until it slows down.
+    Throttled { peer_id: PeerId },
+    /// A throttled peer slowed down, after `dropped` of its messages were
+    /// dropped.
+    Unthrottled { peer_id: PeerId, dropped: u32 },
+    /// `peer_id` got or, when `read` is set, read our message `id`, which
+    /// has now reached `delivered_to` peers and was read by `read_by`.
+    Receipt {
+        id: String,
+        peer_id: PeerId,
+        read: bool,
+        delivered_to: usize,
+        read_by: usize,
+    },
+    /// The node is reachable at a new address.
+    Listening(Multiaddr),
+}
+
+/// What a peer told about itself through Identify, the last time it did.
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub agent_version: String,
+    pub protocol_version: String,
+    pub protocols: Vec<String>,
+    pub listen_addrs: Vec<Multiaddr>,
+    /// The address the peer sees us at.
+    pub observed_addr: Multiaddr,
+}
+
+/// A running chat node.
+pub struct Node {
+    config: Config,
+    swarm: Swarm<MyBehaviour>,
+    // Hash of the last message we published on each channel
+    last_sent: HashMap<String, Vec<u8>>,
+    // Listen addresses already reported as `NodeEvent::Listening`
+    listeners: HashSet<Multiaddr>,
+    // Last messages sent or received, oldest first
+    recent: VecDeque<Published>,
+    // Digest of the oldest message of each channel got from its history
+    earliest: HashMap<String, Vec<u8>>,
+    history: Option<History>,
+    starred: Starred,
+    moderation: Moderation,
+    metrics: Arc<Metrics>,
+}
+
+impl Node {
+    /// Build the swarm, start listening and dial the configured peers.
+    pub async fn new(config: Config) -> Result<Self, Error> {
+        let starred = match &config.starred_path {
+            Some(path) => Starred::open(path).map_err(Error::storage)?,
+            None => Starred::default(),
+        };
+        let history = match &config.history_path {
+            Some(path) => Some(History::open(path).map_err(Error::storage)?),
+            None => None,
+        };
+        let recent = match &history {
+            Some(history) => history.last(RECENT_MESSAGES).map_err(Error::storage)?.into(),
+            None => VecDeque::new(),
+        };
+        // Pick up the message chains where the last run left them
+        let local_peer_id = config.local_peer_id();
+        let mut last_sent = HashMap::new();
+        let mut chains = HashMap::new();
+        for message in &recent {
+            if let Some(author) = message.author() {
+                if author == local_peer_id {
+                    last_sent.insert(message.channel.clone(), message.digest());
+                } else {
+                    chains.insert((author, message.channel.clone()), message.digest());
+                }
+            }
+        }
+        let seniority = match &config.peers_path {
+            Some(path) => Seniority::open(path).map_err(Error::storage)?,
+            None => Seniority::default(),
+        };
+        let seen = match &config.seen_path {
+            Some(path) => Seen::open(path, config.dedup_window, config.dedup_capacity)
+                .map_err(Error::storage)?,
+            None => Seen::new(config.dedup_window, config.dedup_capacity),
+        };
+        let moderation = match &config.moderation_path {
+            Some(path) => Moderation::open(path).map_err(Error::storage)?,
+            None => Moderation::default(),
+        };
+        let metrics = Arc::new(Metrics::default());
+        let carried = Carried {
+            chains,
+            seniority,
+            seen,
+        };
+        let swarm = build_swarm(
+            &config,
+            &config.channels,
+            carried,
+            history.clone(),
+            &moderation,
+            &metrics,
+        )
+        .await?;
+        Ok(Node {
+            config,
+            swarm,
+            last_sent,
+            listeners: HashSet::new(),
+            recent,
+            earliest: HashMap::new(),
+            history,
+            starred,
+            moderation,
+            metrics,
+        })
+    }
+
+    pub fn local_peer_id(&self) -> &PeerId {
+        Swarm::local_peer_id(&self.swarm)
+    }
+
+    /// Peer id of the key owning our broadcast channels, see [`broadcast`].
+    pub fn owner_peer_id(&self) -> PeerId {
+        self.config.owner_peer_id()
+    }
+
+    /// Publish a chat line on a joined channel, returning the id of the
+    /// message. Lines too large for a single gossipsub message go out in
+    /// fragments, up to a MiB once encoded.
+    ///
+    /// This fails with [`Error::Dial`] until at least one other peer has
+    /// joined the channel, and with [`Error::Config`] on broadcast channels
+    /// we do not own.
+    pub fn publish(&mut self, channel: &str, content: impl Into<String>) -> Result<String, Error> {
+        self.send(channel, content.into(), None)
+    }
+
+    /// The last `n` messages sent or received, oldest first. Without a
+    /// history store only the ones still kept in memory are available.
+    pub fn history(&self, n: usize) -> Result<Vec<Published>, Error> {
+        match &self.history {
+            Some(history) => history.last(n).map_err(Error::storage),
+            None => {
+                let skip = self.recent.len().saturating_sub(n);
+                Ok(self.recent.iter().skip(skip).cloned().collect())
+            }
+        }
+    }
+
+    /// Republish a recent message on another channel, keeping its
+    /// original author, channel and time.
+    pub fn forward(&mut self, id: &str, channel: &str) -> Result<(), Error> {
+        let original = self.find_recent(id)?;
+        // Forwarding a forward points back to where the content came from
+        let provenance = original.forwarded.clone().unwrap_or_else(|| Forwarded {
+            display_name: original.display_name.clone(),
+            channel: original.channel.clone(),
+            timestamp: original.timestamp,
+            author: original.author.clone(),
+        });
+        let content = original.content.clone();
+        self.send(channel, content, Some(provenance))?;
+        Ok(())
+    }
+
+    /// Save a recent message, returning false if it already was.
+    pub fn star(&mut self, id: &str) -> Result<bool, Error> {
+        let message = self.find_recent(id)?.clone();
+        self.starred.add(message).map_err(Error::storage)
+    }
+
+    /// Messages saved with [`Node::star`], oldest first.
+    pub fn starred(&self) -> &[Published] {
+        self.starred.messages()
+    }
+
+    fn find_recent(&self, id: &str) -> Result<&Published, Error> {
+        self.recent
+            .iter()
+            .rev()
+            .find(|m| m.id() == id)
+            .ok_or_else(|| Error::config(format!("no recent message {}", id)))
+    }
+
+    fn send(
+        &mut self,
+        channel: &str,
+        content: String,
+        forwarded: Option<Forwarded>,
+    ) -> Result<String, Error> {
+        if !self.swarm.channels.contains(channel) {
+            return Err(Error::config(format!("not in channel {}", channel)));
+        }
+        let msg = ChatMessage {
+            display_name: self.config.display_name.clone(),
+            content,
+            prev: self.last_sent.get(channel).cloned().unwrap_or_default(),
+            channel: channel.to_owned(),
+            owner_signature: Vec::new(),
+            timestamp: message::now(),
+            forwarded,
+            author: self.local_peer_id().to_bytes(),
+            author_key: self.config.keypair.public().into_protobuf_encoding(),
+            signature: Vec::new(),
+            fragment: None,
+        };
+        let mut owner_key = None;
+        if let Some(owner) = broadcast::owner(channel) {
+            if self.config.owner_key().public() != owner {
+                return Err(Error::config(format!("channel {} is read-only", channel)));
+            }
+            // Members that don't check owner signatures accept forgeries
+            let peers = self.swarm.channel_peers(channel);
+            self.swarm
+                .check_downgrade(
+                    &Conversation::Channel(channel.to_owned()),
+                    &peers,
+                    Capability::Broadcast,
+                )
+                .map_err(Error::config)?;
+            owner_key = Some(self.config.owner_key());
+        }
+        let legacy = self.swarm.predates_signed(channel);
+        let msg = Published::sign(msg, &self.config.keypair, owner_key, legacy)
+            .map_err(Error::crypto)?;
+        // Too large to publish at once, receivers put the pieces back together
+        let max_transmit_size = self.config.gossipsub.max_transmit_size();
+        let (kind, payloads) = fragment::split(&msg, max_transmit_size).map_err(Error::decode)?;
+        for data in payloads {
+            let data = self.swarm.seal(channel, kind, data);
+            let published = self.swarm.gossipsub.publish(Topic::new(channel), data);
+            match published {
+                Ok(_) => {}
+                Err(e @ PublishError::InsufficientPeers) => {
+                    return Err(Error::dial(format!("failed to publish: {:?}", e)))
+                }
+                Err(e) => return Err(Error::transport(format!("failed to publish: {:?}", e))),
+            }
+        }
+        self.swarm.chatted();
+        self.swarm.seen.insert(msg.digest());
+        self.metrics.message_sent(channel);
+        self.last_sent.insert(msg.channel.clone(), msg.digest());
+        self.swarm.receipts.track(msg.digest(), channel);
+        let id = msg.id();
+        self.remember(msg);
+        Ok(id)
+    }
+
+    // Keep a sent or received message for forwarding, starring and history.
+    fn remember(&mut self, message: Published) {
+        if let Some(history) = &self.history {
+            if let Err(e) = history.append(&message) {
+                log::warn!("failed to store message in history: {:#}", e);
+            }
+        }
+        if self.recent.len() == RECENT_MESSAGES {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(message);
+    }
+
+    /// Send a direct message to a single peer, dialing it if needed.
+    ///
+    /// The outcome is reported as [`NodeEvent::Delivered`] or
+    /// [`NodeEvent::NotDelivered`] with the returned id. If the peer can't
+    /// be reached the message waits for it, reported as [`NodeEvent::Queued`]
+    /// and [`NodeEvent::Expired`] if it never comes back. Fails in strict
+    /// mode if the peer has not announced that it accepts direct messages.
+    pub fn send_direct(
+        &mut self,
+        peer_id: &PeerId,
+        content: impl Into<String>,
+    ) -> Result<RequestId, Error> {
+        self.swarm
+            .check_downgrade(
+                &Conversation::Direct(*peer_id),
+                &[*peer_id],
+                Capability::DirectMessages,
+            )
+            .map_err(Error::config)?;
+        let msg = DirectMessage {
+            display_name: self.config.display_name.clone(),
+            content: content.into(),
+            id: self.swarm.outbox.next_id(),
+        };
+        self.swarm.chatted();
+        self.swarm.standby.messaged(*peer_id);
+        let request_id = self.swarm.direct.send_request(peer_id, msg.clone());
+        self.swarm.outbox.sent(request_id, msg);
+        Ok(request_id)
+    }
+
+    /// Send a file to a peer in chunks. Progress and the outcome are reported
+    /// as [`NodeEvent::TransferProgress`], [`NodeEvent::FileSent`] and
+    /// [`NodeEvent::TransferFailed`].
+    pub fn send_file(&mut self, peer_id: PeerId, path: &Path) -> Result<(), Error> {
+        self.swarm
+            .check_downgrade(
+                &Conversation::Direct(peer_id),
+                &[peer_id],
+                Capability::FileTransfer,
+            )
+            .map_err(Error::config)?;
+        self.swarm.send_file(peer_id, path).map_err(Error::config)
+    }
+
+    /// What a peer announced it supports, once it was identified.
+    pub fn capabilities(&self, peer_id: &PeerId) -> Option<&Capabilities> {
+        self.swarm.capabilities.get(peer_id)
+    }
+
+    /// What a peer told about itself, once it was identified, pingpong node
+    /// or not.
+    pub fn peer_info(&self, peer_id: &PeerId) -> Option<&PeerInfo> {
+        self.swarm.peer_info.get(peer_id)
+    }
+
+    /// Who acknowledged our recent message `id`, see [`receipt`].
+    pub fn receipts(&self, id: &str) -> Option<&Delivery> {
+        self.swarm.receipts.delivery(id)
+    }
+
+    /// Tell the authors of the messages got so far, on any channel, that we
+    /// read them.
+    pub fn mark_read(&mut self) {
+        if self.config.receipts {
+            self.swarm.receipts.read();
+        }
+    }
+
+    /// An invite others can join `channel` and connect to us with.
+    pub fn invite(&self, channel: &str) -> Invite {
+        let external = Swarm::external_addresses(&self.swarm).map(|record| record.addr.clone());
+        let listening = Swarm::listeners(&self.swarm).cloned();
+        Invite::new(*self.local_peer_id(), external, listening, channel)
+    }
+
+    /// Resolve a peer id, or the display name a peer last used, to a peer id.
+    pub fn resolve(&self, peer: &str) -> Option<PeerId> {
+        peer.parse()
+            .ok()
+            .or_else(|| self.swarm.names.get(peer).copied())
+    }
+
+    /// Ask the member of `channel` serving history expected to answer the
+    /// soonest, and others if it fails, for the page of messages stored
+    /// before the one with digest `before`, the newest page when `None`. The
+    /// answer comes as [`NodeEvent::History`] or [`NodeEvent::HistoryFailed`]
+    /// with the returned id. Fails with [`Error::Dial`] if no member we know
+    /// of serves history. See [`backfill`].
+    pub fn get_history(
+        &mut self,
+        channel: &str,
+        before: Option<Vec<u8>>,
+    ) -> Result<RequestId, Error> {
+        self.swarm
+            .get_history(channel, before.unwrap_or_default(), false)
+            .ok_or_else(|| Error::dial(format!("no member of {} serves history", channel)))
+    }
+
+    /// Ask for the page of history of `channel` before the oldest message of
+    /// it we have, see [`Node::get_history`].
+    pub fn earlier(&mut self, channel: &str) -> Result<RequestId, Error> {
+        let oldest = match self.earliest.get(channel) {
+            Some(digest) => Some(digest.clone()),
+            None => self.recent.iter().find(|m| m.channel == channel).map(Published::digest),
+        };
+        self.get_history(channel, oldest)
+    }
+
+    /// Ask for the next round trip time to a peer, reported as
+    /// [`NodeEvent::Pong`]. Peers are pinged periodically, so the answer may
+    /// take up to one ping interval unless we first have to connect.
+    pub fn ping(&mut self, peer_id: PeerId) {
+        self.swarm.ping(peer_id)
+    }
+
+    /// Peers currently online, see [`presence`].
+    pub fn roster(&self) -> &Roster {
+        &self.swarm.roster
+    }
+
+    /// Tell peers we are going offline. The announcement is only sent while
+    /// the node is polled.
+    pub fn announce_leave(&mut self) {
+        self.swarm.announce(Status::Leave);
+    }
+
+    /// Get ready to stop: stop redialing, say goodbye, leave every channel
+    /// and write out what is stored on disk. Peers only hear about it while the node is
+    /// polled, after which [`Node::close`] hangs up.
+    pub fn shutdown(&mut self) -> Result<(), Error> {
+        self.swarm.redialer.stop();
+        self.announce_leave();
+        let channels: Vec<String> = self.channels().map(String::from).collect();
+        for channel in channels {
+            self.leave(&channel)?;
+        }
+        if let Some(history) = &self.history {
+            history.flush().map_err(Error::storage)?;
+        }
+        self.swarm.seen.flush().map_err(Error::storage)?;
+        self.swarm.seniority.flush().map_err(Error::storage)
+    }
+
+    /// Close every connection and refuse new ones, for good.
+    pub fn close(&mut self) {
+        let peers: Vec<PeerId> = self.swarm.recorder.peers().copied().collect();
+        for peer_id in peers {
+            Swarm::ban_peer_id(&mut self.swarm, peer_id);
+        }
+    }
+
+    /// Drop everything a peer sends, and hang up on it with
+    /// [`Config::refuse_blocked`]. Returns false if it already was blocked.
+    pub fn block(&mut self, peer_id: PeerId) -> Result<bool, Error> {
+        let blocked = self.moderation.block(peer_id).map_err(Error::storage)?;
+        if self.config.refuse_blocked {
+            Swarm::ban_peer_id(&mut self.swarm, peer_id);
+        }
+        Ok(blocked)
+    }
+
+    /// Hear from a blocked peer again, returning false if it was not blocked.
+    pub fn unblock(&mut self, peer_id: &PeerId) -> Result<bool, Error> {
+        Swarm::unban_peer_id(&mut self.swarm, *peer_id);
+        self.moderation.unblock(peer_id).map_err(Error::storage)
+    }
+
+    /// The blocked and muted peers, see [`moderation`].
+    pub fn moderation(&self) -> &Moderation {
+        &self.moderation
+    }
+
+    /// Counters describing what the node is doing, see [`metrics::serve`].
+    pub fn metrics(&self) -> Arc<Metrics> {
+        self.metrics.clone()
+    }
+
+    /// Round trip statistics of every connected peer we have pinged.
+    pub fn latency(&self) -> impl Iterator<Item = (&PeerId, LatencyStats)> {
+        let swarm = &self.swarm;
+        swarm.latency.peers().filter_map(move |peer_id| {
+            if !Swarm::is_connected(swarm, peer_id) {
+                return None;
+            }
+            Some((peer_id, swarm.latency.stats(peer_id)?))
+        })
+    }
+
+    /// Join a channel, returning false if we already were in it.
+    pub fn join(&mut self, channel: &str) -> Result<bool, Error> {
+        self.swarm.join(channel).map_err(Error::config)
+    }
+
+    /// Leave a channel, returning false if we were not in it.
+    pub fn leave(&mut self, channel: &str) -> Result<bool, Error> {
+        self.swarm.leave(channel).map_err(Error::transport)
+    }
+
+    /// The channels we are in, in alphabetical order.
+    pub fn channels(&self) -> impl Iterator<Item = &str> {
+        self.swarm.channels.iter().map(String::as_str)
+    }
+
+    /// Tear down the swarm and build a new one from the same config, keeping
+    /// the identity, joined channels, message chains and known peers.
+    pub async fn restart(&mut self) -> Result<(), Error> {
+        let channels: Vec<String> = self.channels().map(String::from).collect();
+        let chains = std::mem::take(&mut self.swarm.chains);
+        let seniority = std::mem::take(&mut self.swarm.seniority);
+        let seen = Seen::new(self.config.dedup_window, self.config.dedup_capacity);
+        let seen = std::mem::replace(&mut self.swarm.seen, seen);
+        let carried = Carried {
+            chains,
+            seniority,
+            seen,
+        };
+        self.swarm = build_swarm(
+            &self.config,
+            &channels,
+            carried,
+            self.history.clone(),
+            &self.moderation,
+            &self.metrics,
+        )
+        .await?;
+        self.listeners.clear();
+        Ok(())
+    }
+
+    /// Move the node onto a task of its own, driven through the returned
+    /// handle and its clones, see [`handle`].
+    pub fn spawn(self) -> NodeHandle {
+        handle::spawn(self)
+    }
+}
+
+impl Stream for Node {
+    type Item = NodeEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<NodeEvent>> {
+        let this = &mut *self;
+        if let Poll::Ready(mut event) = this.swarm.poll_next_unpin(cx) {
+            match &mut event {
+                Some(NodeEvent::Message { message, .. }) => {
+                    this.remember(Published::clone(message));
+                }
+                Some(NodeEvent::History {
+                    channel, messages, ..
+                }) => {
+                    if let Some(oldest) = messages.first() {
+                        this.earliest.insert(channel.clone(), oldest.digest());
+                    }
+                    let recent = &this.recent;
+                    messages.retain(|m| !recent.iter().any(|r| r.digest() == m.digest()));
+                }
+                _ => {}
+            }
+            return Poll::Ready(event);
+        }
+        let local_peer_id = *Swarm::local_peer_id(&this.swarm);
+        for addr in Swarm::listeners(&this.swarm) {
+            if this.listeners.insert(addr.clone()) {
+                let addr = addr.clone().with(Protocol::P2p(local_peer_id.into()));
+                return Poll::Ready(Some(NodeEvent::Listening(addr)));
+            }
+        }
+        Poll::Pending
+    }
+}
+
+// Create a Swarm to manage peers and events
+async fn build_swarm(
+    config: &Config,
+    channels: &[String],
+    carried: Carried,
+    history: Option<History>,
+    moderation: &Moderation,
+    metrics: &Arc<Metrics>,
+) -> Result<Swarm<MyBehaviour>, Error> {
+    let transport = build_transport(config, moderation, metrics)?;
+    let behaviour = MyBehaviour::new(
+        config,
+        channels,
+        carried,
+        history,
+        moderation.clone(),
+        metrics.clone(),
+    )
+    .await?;
+    // A single identity gets no more say by opening more connections
+    let limits =
+        ConnectionLimits::default().with_max_established_per_peer(Some(MAX_CONNECTIONS_PER_PEER));
+    let mut swarm = SwarmBuilder::new(transport, behaviour, config.local_peer_id())
+        .connection_limits(limits)
+        .build();
+    for addr in &config.listen_addrs {
+        Swarm::listen_on(&mut swarm, addr.clone()).map_err(Error::transport)?;
+    }
+    Ok(swarm)
+}
+
+// Set up an encrypted DNS-enabled TCP and WebSocket Transport over the Mplex
+// and Yamux protocols, asking the gater before connections are used and
+// counting the bytes that go through
+fn build_transport(
+    config: &Config,
+    moderation: &Moderation,
+    metrics: &Metrics,
+) -> Result<Boxed<(PeerId, StreamMuxerBox)>, Error> {
+    let tcp = TcpConfig::new().nodelay(true);
+    let dns = DnsConfig::new(tcp).map_err(Error::transport)?;
+    let ws = match config.websocket {
+        true => OptionalTransport::some(WsConfig::new(dns.clone())),
+        false => OptionalTransport::none(),
+    };
+    let (transport, sinks) = BandwidthLogging::new(dns.or_transport(ws));
+    metrics.add_bandwidth(sinks);
+    let noise_keys = noise::Keypair::<X25519Spec>::new()
+        .into_authentic(&config.keypair)
+        .map_err(|e| Error::crypto(format!("failed to sign noise key: {}", e)))?;
+    let gater = moderation::gater(config, moderation);
+    let transport = Gated::new(transport, gater.clone())
+        .upgrade(upgrade::Version::V1)
+        .authenticate(NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(SelectUpgrade::new(YamuxConfig::default(), MplexConfig::default()))
+        .timeout(Duration::from_secs(20))
+        .and_then(move |(peer_id, muxer), endpoint| {
+            let allowed = gater.allow_peer(&peer_id, &endpoint);
+            if !allowed {
+                log::info!("refused connection with {}", peer_id);
+            }
+            future::ready(match allowed {
+                true => Ok((peer_id, StreamMuxerBox::new(muxer))),
+                false => Err(ConnectionDenied),
+            })
+        })
+        .boxed();
+    Ok(transport)
+}
@@ -1,151 +1,978 @@
 use core::task::{Context, Poll};
-use std::fmt;
+use std::{
+    any::Any,
+    iter,
+    panic::{self, AssertUnwindSafe},
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
-use async_std::io;
-use futures::prelude::*;
+use anyhow::{anyhow, bail, Context as _};
+use async_std::{io, net::TcpListener, task};
+use futures::{io::Lines, prelude::*};
 use libp2p::{
-    floodsub::{self, Floodsub, FloodsubEvent},
-    identity,
-    mdns::{Mdns, MdnsEvent},
-    swarm::NetworkBehaviourEventProcess,
-    Multiaddr, NetworkBehaviour, PeerId, Swarm,
+    gossipsub::{GossipsubConfig, GossipsubConfigBuilder, ValidationMode},
+    identity::Keypair,
+    multiaddr::Protocol,
+    Multiaddr, PeerId,
+};
+use pingpong_p2p::{
+    broadcast,
+    capabilities::Capability,
+    command::{self, Command, Input},
+    dedup,
+    degree::Budget,
+    gate::Policy,
+    history, identity,
+    invite::Invite,
+    metrics, moderation,
+    order::Order,
+    selftest, seniority, starred,
+    transfer::{self, Direction},
+    words, Config, Node, NodeEvent, Published,
+};
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    low_level::signal_name,
 };
-use prost::Message;
+use signal_hook_async_std::Signals;
+
+mod cli;
+mod tui;
+
+use cli::{Opt, Subcommand};
+use tui::{PaneLogger, Tui};
+
+// How long to wait before rebuilding the swarm after it panicked.
+const RESTART_DELAY: Duration = Duration::from_secs(1);
+// How many stored messages are printed on startup.
+const REPLAY: usize = 20;
+// How long to keep running after stdin closed, so our goodbye goes out.
+const LINGER: Duration = Duration::from_millis(500);
+// How long to give connections to close once we hung up.
+const HANG_UP: Duration = Duration::from_millis(100);
+// How long to wait for a listen address to put in the startup invite, and for
+// more to follow the first one.
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(2);
+const LISTEN_SETTLE: Duration = Duration::from_millis(200);
+// Where browser peers are let in unless we already listen on WebSocket.
+const BROWSER_LISTEN: &str = "/ip4/0.0.0.0/tcp/0/ws";
 
 // Run this example by following these steps:
-// $ cargo run -- alias
+// $ cargo run -- --name alice
 // on another terminal run:
-// $ cargo run -- bob <OTHER_PEER_IP>
-// now start exchange messages.
+// $ cargo run -- --name bob --dial <OTHER_PEER_MULTIADDR>
+// or `cargo run -- join <INVITE>` with the invite alice printed.
+// now start exchange messages. `--help` lists every option and command.
 #[async_std::main]
 async fn main() -> anyhow::Result<()> {
-    env_logger::init();
-    // Create a random PeerId
-    let local_key = identity::Keypair::generate_ed25519();
-    let local_peer_id = PeerId::from(local_key.public());
-    println!("Local peer id: {:?}", local_peer_id);
-
-    // Set up a an encrypted DNS-enabled TCP Transport over the Mplex and Yamux protocols
-    let transport = libp2p::build_development_transport(local_key)?;
-
-    // Create a Floodsub topic
-    let floodsub_topic = floodsub::Topic::new("chat");
-
-    // Create a Swarm to manage peers and events
-    let mut swarm = {
-        let mdns = Mdns::new().await?;
-        let mut behaviour = MyBehaviour {
-            floodsub: Floodsub::new(local_peer_id),
-            mdns,
-            ignored_member: false,
-        };
+    let opt = Opt::load()?;
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(filter) = &opt.log_level {
+        logger.parse_filters(filter);
+    }
+    let invite = match &opt.command {
+        Some(Subcommand::Join { invite: Some(invite) }) => Some(invite.clone()),
+        Some(Subcommand::Join { invite: None }) => Some(read_invite()?),
+        Some(Subcommand::Selftest { offline }) => {
+            logger.init();
+            return self_test(!offline).await;
+        }
+        None => None,
+    };
+    // Lines from the keyboard or stdin, and where output goes
+    let mut console = if opt.tui {
+        let logs = PaneLogger::init(logger.build())?;
+        // The default hook would print over the interface
+        panic::set_hook(Box::new(|info| log::error!("{}", info)));
+        Console::Tui(Tui::new(logs)?)
+    } else {
+        logger.init();
+        Console::Plain(io::BufReader::new(io::stdin()).lines())
+    };
 
-        behaviour.floodsub.subscribe(floodsub_topic.clone());
-        Swarm::new(transport, behaviour, local_peer_id)
+    let local_key = if opt.ephemeral {
+        // Create a random PeerId
+        Keypair::generate_ed25519()
+    } else {
+        let path = opt
+            .identity
+            .clone()
+            .or_else(identity::default_path)
+            .context("no config directory found, pass --identity <PATH> or --ephemeral")?;
+        identity::load_or_create(&path)?
     };
-    let mut args = std::env::args().skip(1);
-    let alias = args.next().unwrap_or_else(|| String::from("anon"));
-    // Reach out to another node if specified
-    if let Some(to_dial) = args.next() {
-        let addr: Multiaddr = to_dial.parse()?;
-        Swarm::dial_addr(&mut swarm, addr)?;
-        println!("Dialed {:?}", to_dial)
-    }
-
-    // Read full lines from stdin
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-
-    // Listen on all interfaces and whatever port the OS assigns
-    Swarm::listen_on(&mut swarm, "/ip4/0.0.0.0/tcp/0".parse()?)?;
-    // Kick it off
-    let mut listening = false;
-    async_std::task::block_on(future::poll_fn(move |cx: &mut Context<'_>| {
-        loop {
-            match stdin.try_poll_next_unpin(cx)? {
-                Poll::Ready(Some(line)) => {
-                    let msg = ChatMessage {
-                        from: alias.clone(),
-                        content: line,
-                    };
-                    let mut bytes = Vec::new();
-                    msg.encode(&mut bytes).expect("failed to encode msg");
-                    swarm.floodsub.publish(floodsub_topic.clone(), bytes)
+
+    let mut config = Config::new(local_key);
+    if !opt.ephemeral {
+        config.starred_path = starred::default_path();
+        if !opt.no_history {
+            config.history_path = history::default_path();
+        }
+        if opt.accept_files {
+            config.download_dir = transfer::default_download_dir();
+        }
+        config.peers_path = seniority::default_path();
+        config.seen_path = dedup::default_path();
+        config.moderation_path = moderation::default_path();
+    }
+    if let Some(probation) = opt.probation {
+        config.probation = probation;
+    }
+    if let Some(window) = opt.dedup_window {
+        config.dedup_window = window;
+    }
+    if let Some(n) = opt.dedup_size {
+        config.dedup_capacity = n;
+    }
+    if let Some(rate) = opt.rate_limit {
+        config.message_rate = rate;
+    }
+    if let Some(n) = opt.rate_burst {
+        config.message_burst = n;
+    }
+    if let Some(ttl) = opt.outbox_ttl {
+        config.outbox_ttl = ttl;
+    }
+    if let Some(n) = opt.standby {
+        config.standby = n;
+    }
+    if let Some(dir) = &opt.download_dir {
+        config.download_dir = Some(dir.clone());
+    }
+    if let Some(mib) = opt.max_file_size {
+        config.max_file_size = mib.saturating_mul(1024 * 1024);
+    }
+    if let Some(schedule) = opt.schedule {
+        config.schedule = schedule;
+    }
+    if let Some(path) = &opt.owner_key {
+        config.owner_key = Some(identity::load_or_create(path)?);
+    }
+    if let Some(name) = &opt.display_name {
+        config.display_name = name.clone();
+    }
+    if !opt.listen.is_empty() {
+        config.listen_addrs = opt.listen.clone();
+    }
+    config.receipts = !opt.no_receipts;
+    config.websocket = !opt.no_websocket;
+    if opt.browser {
+        if opt.no_websocket {
+            bail!("--browser needs WebSocket, drop --no-websocket");
+        }
+        // Browsers can't dial plain TCP
+        if !config.listen_addrs.iter().any(is_websocket) {
+            config.listen_addrs.push(BROWSER_LISTEN.parse()?);
+        }
+    }
+    if !opt.channels.is_empty() {
+        config.channels = opt.channels.clone();
+    }
+    config.dial = opt.dial.clone();
+    config.bootstrap = opt.bootstrap.clone();
+    // Whoever invited us is a DHT peer like any other, dialed by its peer id
+    if let Some(invite) = &invite {
+        let addresses = invite.addresses.iter().cloned();
+        config.bootstrap.extend(addresses.map(|address| (invite.peer_id, address)));
+        config.channels.retain(|channel| *channel != invite.channel);
+        config.channels.insert(0, invite.channel.clone());
+    }
+    if let Some(n) = opt.max_redials {
+        config.max_redials = n;
+    }
+    config.strict = opt.strict;
+    config.leaf = opt.leaf;
+    config.refuse_blocked = opt.refuse_blocked;
+    let mut policy = Policy::default();
+    policy.networks = opt.allow_net.clone();
+    policy.transports = opt.allow_transport.clone();
+    policy.allowed_peers = opt.allow_peer.iter().copied().collect();
+    policy.denied_peers = opt.deny_peer.iter().copied().collect();
+    policy.rules = opt.rules.clone();
+    if !policy.is_open() {
+        config.gater = Some(Arc::new(policy));
+    }
+    config.gossipsub = gossipsub_config(&opt)?;
+    // Mesh sizes given by hand are kept as they are
+    if opt.mesh_n.is_none() && opt.mesh_n_low.is_none() && opt.mesh_n_high.is_none() {
+        let upload = opt.upload_budget.map(|kib| kib.saturating_mul(1024));
+        config.budget = Some(Budget::measure(upload));
+    }
+
+    let mut node = Node::new(config.clone()).await?;
+    console.print(&format!("Local peer id: {:?}", node.local_peer_id()));
+    if let Some(addr) = opt.metrics_addr {
+        let listener = TcpListener::bind(addr)
+            .await
+            .with_context(|| format!("failed to serve metrics on {}", addr))?;
+        let metrics = node.metrics();
+        task::spawn(metrics::serve(listener, metrics));
+        console.print(&format!("Metrics on http://{}/metrics", addr));
+    }
+    for addr in &config.dial {
+        console.print(&format!("Dialed {:?}", addr));
+    }
+    for message in node.history(REPLAY)? {
+        print_stored(&mut console, &message);
+    }
+
+    // The channel plain text lines are published on
+    let mut active = config.channels.first().cloned();
+    if let Some(channel) = &active {
+        wait_listening(&mut node, &mut console, opt.show_order, LISTEN_TIMEOUT).await;
+        print_invite(&mut console, &node.invite(channel), opt.qr);
+    }
+    // Shut down cleanly instead of being killed
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+
+    // Supervise the node: a panic while driving it (e.g. in a behaviour
+    // handler) only tears down the swarm, which is then rebuilt with the
+    // same identity and message chains.
+    loop {
+        let run = run(&mut node, &mut console, &mut signals, &mut active, opt.show_order);
+        match AssertUnwindSafe(run).catch_unwind().await {
+            Ok(result) => {
+                if let Err(e) = node.shutdown() {
+                    console.print(&format!("!! {:#}", e));
                 }
-                Poll::Ready(None) => panic!("Stdin closed"),
-                Poll::Pending => break,
+                linger(&mut node, &mut console, opt.show_order, LINGER).await;
+                node.close();
+                linger(&mut node, &mut console, opt.show_order, HANG_UP).await;
+                return result;
+            }
+            Err(panic) => {
+                log::error!(
+                    "swarm panicked: {}; restarting in {:?}",
+                    panic_message(&*panic),
+                    RESTART_DELAY
+                );
+                task::sleep(RESTART_DELAY).await;
+                node.restart().await?;
             }
         }
-        loop {
-            match swarm.poll_next_unpin(cx) {
-                Poll::Ready(Some(event)) => println!("{:?}", event),
-                Poll::Ready(None) => return Poll::Ready(Ok(())),
-                Poll::Pending => {
-                    if !listening {
-                        for addr in Swarm::listeners(&swarm) {
-                            println!("Listening on {:?}", addr);
-                            listening = true;
-                        }
-                    }
-                    break;
-                }
+    }
+}
+
+// Run the self-test, telling how each check went.
+async fn self_test(loopback: bool) -> anyhow::Result<()> {
+    let checks = selftest::run(loopback).await;
+    let mut failed = 0;
+    for check in &checks {
+        match &check.outcome {
+            Ok(detail) => println!("ok    {} ({})", check.name, detail),
+            Err(e) => {
+                failed += 1;
+                println!("FAIL  {}: {:#}", check.name, e);
             }
         }
-        Poll::Pending
-    }))
+    }
+    if failed > 0 {
+        bail!("{} of {} checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+// Keep printing node events for a moment.
+async fn linger(node: &mut Node, console: &mut Console, show_order: bool, duration: Duration) {
+    let events = async {
+        while let Some(event) = node.next().await {
+            print_event(console, event, show_order);
+        }
+    };
+    let _ = async_std::future::timeout(duration, events).await;
+}
+
+// Keep printing node events until we listen somewhere, or for at most
+// `timeout`, then a little longer for the other interfaces to come up.
+async fn wait_listening(
+    node: &mut Node,
+    console: &mut Console,
+    show_order: bool,
+    timeout: Duration,
+) {
+    let events = async {
+        while let Some(event) = node.next().await {
+            let listening = matches!(event, NodeEvent::Listening(_));
+            print_event(console, event, show_order);
+            if listening {
+                break;
+            }
+        }
+    };
+    let _ = async_std::future::timeout(timeout, events).await;
+    linger(node, console, show_order, LISTEN_SETTLE).await;
 }
 
-#[derive(prost::Message, Clone)]
-struct ChatMessage {
-    #[prost(string, tag = 1)]
-    pub from: String,
-    #[prost(string, tag = 2)]
-    pub content: String,
+// Whether an address is a WebSocket one, secure or not.
+fn is_websocket(address: &Multiaddr) -> bool {
+    address
+        .iter()
+        .any(|protocol| matches!(protocol, Protocol::Ws(_) | Protocol::Wss(_)))
 }
 
-impl fmt::Display for ChatMessage {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}: {}", self.from, self.content)
+// Ask for an invite on stdin, before it is used for chat.
+fn read_invite() -> anyhow::Result<Invite> {
+    println!("Paste an invite, or what scanning its QR code gave:");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    line.parse()
+}
+
+fn print_invite(console: &mut Console, invite: &Invite, qr: bool) {
+    console.print(&format!(
+        "Invite others to {} with: {} join {}",
+        invite.channel,
+        env!("CARGO_PKG_NAME"),
+        invite
+    ));
+    if qr {
+        match invite.qr_code() {
+            Ok(code) => console.print(&code),
+            Err(e) => console.print(&format!("!! {:#}", e)),
+        }
     }
 }
 
-#[derive(NetworkBehaviour)]
-struct MyBehaviour {
-    floodsub: Floodsub,
-    mdns: Mdns,
+// Gossipsub settings, with the dedup window, heartbeat and mesh sizes
+// overridden when set.
+fn gossipsub_config(opt: &Opt) -> anyhow::Result<GossipsubConfig> {
+    let mut builder = GossipsubConfigBuilder::default();
+    // Only accept messages signed by their source peer, and only forward
+    // them once we checked them
+    builder
+        .validation_mode(ValidationMode::Strict)
+        .validate_messages();
+    if let Some(window) = opt.dedup_window {
+        builder.duplicate_cache_time(window);
+    }
+    if let Some(ms) = opt.heartbeat_ms {
+        builder.heartbeat_interval(Duration::from_millis(ms));
+    }
+    // JS peers may only speak floodsub, which gossipsub can serve too
+    if opt.browser {
+        builder.support_floodsub();
+    }
+    if let Some(n) = opt.mesh_n {
+        builder.mesh_n(n);
+    }
+    if let Some(n) = opt.mesh_n_low {
+        builder.mesh_n_low(n);
+    }
+    if let Some(n) = opt.mesh_n_high {
+        builder.mesh_n_high(n);
+    }
+    builder
+        .build()
+        .map_err(|e| anyhow!("invalid gossipsub config: {}", e))
+}
 
-    // Struct fields which do not implement NetworkBehaviour need to be ignored
-    #[behaviour(ignore)]
-    #[allow(dead_code)]
-    ignored_member: bool,
+// Where lines are read from and output goes: stdin and stdout, or the
+// terminal UI with `--tui`.
+enum Console {
+    Plain(Lines<io::BufReader<io::Stdin>>),
+    Tui(Tui),
 }
 
-impl NetworkBehaviourEventProcess<FloodsubEvent> for MyBehaviour {
-    // Called when `floodsub` produces an event.
-    fn inject_event(&mut self, message: FloodsubEvent) {
-        if let FloodsubEvent::Message(message) = message {
-            if let Ok(m) = ChatMessage::decode(message.data.as_slice()) {
-                println!("<< {}", m);
-            }
+impl Console {
+    fn print(&mut self, text: &str) {
+        match self {
+            Console::Plain(_) => println!("{}", text),
+            Console::Tui(tui) => tui.print(text),
+        }
+    }
+
+    fn poll_line(&mut self, cx: &mut Context<'_>) -> Poll<Option<io::Result<String>>> {
+        match self {
+            Console::Plain(stdin) => stdin.poll_next_unpin(cx),
+            Console::Tui(tui) => tui.poll_next_unpin(cx),
+        }
+    }
+
+    // Show what was typed, which the interface clears from the input line.
+    fn echo(&mut self, line: &str, active: Option<&str>) {
+        if let Console::Tui(tui) = self {
+            tui.print(&format!(">> [{}] {}", active.unwrap_or("-"), line));
         }
     }
+
+    // Tie the line just echoed to the message it was published as.
+    fn sent(&mut self, id: &str) {
+        if let Console::Tui(tui) = self {
+            tui.sent(id);
+        }
+    }
+
+    // Show how far one of our messages got after it, only in the interface,
+    // stdout has `/status` for that.
+    fn mark(&mut self, id: &str, mark: String) {
+        if let Console::Tui(tui) = self {
+            tui.mark(id, mark);
+        }
+    }
+
+    // Bring the interface up to date, stdout needs nothing.
+    fn refresh(&mut self, node: &Node, active: Option<&str>) -> io::Result<()> {
+        let tui = match self {
+            Console::Plain(_) => return Ok(()),
+            Console::Tui(tui) => tui,
+        };
+        let mut online: Vec<_> = node.roster().iter().collect();
+        online.sort_by(|(_, a), (_, b)| a.display_name.cmp(&b.display_name));
+        let online: Vec<String> = online
+            .into_iter()
+            .map(|(peer_id, presence)| {
+                format!("{} {}", presence.display_name, author_tag(Some(peer_id)))
+            })
+            .collect();
+        tui.draw(active.unwrap_or("not in any channel"), &online)
+    }
+}
+
+// Handle input lines and print node events until the input is closed or
+// we are told to stop.
+async fn run(
+    node: &mut Node,
+    console: &mut Console,
+    signals: &mut Signals,
+    active: &mut Option<String>,
+    show_order: bool,
+) -> anyhow::Result<()> {
+    future::poll_fn(move |cx: &mut Context<'_>| {
+        if let Poll::Ready(Some(signal)) = signals.poll_next_unpin(cx) {
+            let name = signal_name(signal).unwrap_or("signal");
+            console.print(&format!("Got {}, shutting down", name));
+            return Poll::Ready(Ok(()));
+        }
+        loop {
+            match console.poll_line(cx)? {
+                Poll::Ready(Some(line)) => {
+                    console.echo(&line, active.as_deref());
+                    // Whatever was shown before has been seen
+                    node.mark_read();
+                    if let Err(e) = handle_line(node, console, active, &line) {
+                        console.print(&format!("!! {}", e));
+                    }
+                }
+                Poll::Ready(None) => {
+                    console.print("Input closed, shutting down");
+                    return Poll::Ready(Ok(()));
+                }
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            match node.poll_next_unpin(cx) {
+                Poll::Ready(Some(event)) => print_event(console, event, show_order),
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => break,
+            }
+        }
+        console.refresh(node, active.as_deref())?;
+        Poll::Pending
+    })
+    .await
 }
 
-impl NetworkBehaviourEventProcess<MdnsEvent> for MyBehaviour {
-    // Called when `mdns` produces an event.
-    fn inject_event(&mut self, event: MdnsEvent) {
-        match event {
-            MdnsEvent::Discovered(list) => {
-                for (peer, _) in list {
-                    self.floodsub.add_node_to_partial_view(peer);
+fn handle_line(
+    node: &mut Node,
+    console: &mut Console,
+    active: &mut Option<String>,
+    line: &str,
+) -> anyhow::Result<()> {
+    match command::parse(line)? {
+        Input::Text(text) => {
+            let channel = active
+                .as_deref()
+                .context("not in any channel, /join one first")?;
+            let id = node.publish(channel, text)?;
+            console.sent(&id);
+        }
+        Input::Command(Command::Join(channel)) => {
+            node.join(&channel)?;
+            console.print(&format!("-- chatting in {}", channel));
+            *active = Some(channel);
+        }
+        Input::Command(Command::Broadcast(name)) => {
+            let channel = broadcast::channel_name(&name, &node.owner_peer_id());
+            node.join(&channel)?;
+            console.print(&format!("-- broadcasting in {}", channel));
+            *active = Some(channel);
+        }
+        Input::Command(Command::Leave(channel)) => {
+            let channel = channel
+                .or_else(|| active.clone())
+                .context("not in any channel")?;
+            if !node.leave(&channel)? {
+                bail!("not in channel {}", channel);
+            }
+            console.print(&format!("-- left {}", channel));
+            if active.as_deref() == Some(channel.as_str()) {
+                *active = node.channels().next().map(String::from);
+                if let Some(channel) = active {
+                    console.print(&format!("-- chatting in {}", channel));
                 }
             }
-            MdnsEvent::Expired(list) => {
-                for (peer, _) in list {
-                    if !self.mdns.has_node(&peer) {
-                        self.floodsub.remove_node_from_partial_view(&peer);
+        }
+        Input::Command(Command::Channels) => {
+            for channel in node.channels() {
+                let marker = if active.as_deref() == Some(channel) { '*' } else { ' ' };
+                console.print(&format!("{} {}", marker, channel));
+            }
+        }
+        Input::Command(Command::Forward { id, channel }) => node.forward(&id, &channel)?,
+        Input::Command(Command::Status(id)) => {
+            let delivery = node
+                .receipts(&id)
+                .with_context(|| format!("no recent message of ours {}", id))?;
+            console.print(&format!(
+                "-- #{} in {} got by {}, read by {}",
+                id,
+                delivery.channel,
+                delivery.delivered.len(),
+                delivery.read.len()
+            ));
+            for peer_id in &delivery.delivered {
+                let state = match delivery.read.contains(peer_id) {
+                    true => "read",
+                    false => "got",
+                };
+                let who = match node.roster().get(peer_id) {
+                    Some(presence) => {
+                        format!("{} {}", presence.display_name, author_tag(Some(peer_id)))
                     }
+                    None => peer_id.to_string(),
+                };
+                console.print(&format!("{} by {}", state, who));
+            }
+        }
+        Input::Command(Command::Star(id)) => {
+            if !node.star(&id)? {
+                console.print(&format!("-- #{} already starred", id));
+            }
+        }
+        Input::Command(Command::History(n)) => {
+            for message in node.history(n)? {
+                print_stored(console, &message);
+            }
+        }
+        Input::Command(Command::Starred) => {
+            for message in node.starred() {
+                print_stored(console, message);
+            }
+        }
+        Input::Command(Command::Ping(peer)) => {
+            let peer_id = node
+                .resolve(&peer)
+                .with_context(|| format!("unknown peer {}", peer))?;
+            node.ping(peer_id);
+        }
+        Input::Command(Command::Info(peer)) => {
+            let peer_id = node
+                .resolve(&peer)
+                .with_context(|| format!("unknown peer {}", peer))?;
+            let info = node
+                .peer_info(&peer_id)
+                .with_context(|| format!("nothing known about {} yet", peer))?;
+            console.print(&format!("-- {}", peer_id));
+            console.print(&format!("agent {}", info.agent_version));
+            console.print(&format!("protocol {}", info.protocol_version));
+            for protocol in &info.protocols {
+                console.print(&format!("supports {}", protocol));
+            }
+            for address in &info.listen_addrs {
+                console.print(&format!("listens on {}", address));
+            }
+            console.print(&format!("sees us at {}", info.observed_addr));
+        }
+        Input::Command(Command::Latency) => {
+            for (peer_id, stats) in node.latency() {
+                console.print(&format!(
+                    "{} min {:?} avg {:?} max {:?} ({} samples)",
+                    peer_id, stats.min, stats.avg, stats.max, stats.samples
+                ));
+            }
+        }
+        Input::Command(Command::Who) => {
+            let mut online: Vec<_> = node.roster().iter().collect();
+            online.sort_by(|(_, a), (_, b)| a.display_name.cmp(&b.display_name));
+            if online.is_empty() {
+                console.print("-- nobody else is online");
+            }
+            for (peer_id, presence) in online {
+                console.print(&format!(
+                    "{} {} (seen {}s ago)",
+                    presence.display_name,
+                    peer_id,
+                    presence.last_seen.elapsed().as_secs()
+                ));
+            }
+        }
+        Input::Command(Command::Msg { to, text }) => {
+            let peer_id = node
+                .resolve(&to)
+                .with_context(|| format!("unknown peer {}", to))?;
+            if let Some(capabilities) = node.capabilities(&peer_id) {
+                if !capabilities.contains(&Capability::DirectMessages) {
+                    bail!("{} does not accept direct messages", to);
+                }
+            }
+            node.send_direct(&peer_id, text)?;
+        }
+        Input::Command(Command::Send { to, path }) => {
+            let peer_id = node
+                .resolve(&to)
+                .with_context(|| format!("unknown peer {}", to))?;
+            if let Some(capabilities) = node.capabilities(&peer_id) {
+                if !capabilities.contains(&Capability::FileTransfer) {
+                    bail!("{} does not accept files", to);
                 }
             }
+            node.send_file(peer_id, Path::new(&path))?;
+            console.print(&format!("-- sending {} to {}", path, peer_id));
+        }
+        Input::Command(Command::Block(peer)) => {
+            let peer_id = node
+                .resolve(&peer)
+                .with_context(|| format!("unknown peer {}", peer))?;
+            match node.block(peer_id)? {
+                true => console.print(&format!("-- blocked {}", peer_id)),
+                false => console.print(&format!("-- {} already blocked", peer_id)),
+            }
+        }
+        Input::Command(Command::Unblock(peer)) => {
+            let peer_id = node
+                .resolve(&peer)
+                .with_context(|| format!("unknown peer {}", peer))?;
+            match node.unblock(&peer_id)? {
+                true => console.print(&format!("-- unblocked {}", peer_id)),
+                false => console.print(&format!("-- {} was not blocked", peer_id)),
+            }
+        }
+        Input::Command(Command::Mute(peer)) => {
+            let peer_id = node
+                .resolve(&peer)
+                .with_context(|| format!("unknown peer {}", peer))?;
+            match node.moderation().mute(peer_id)? {
+                true => console.print(&format!("-- muted {}", peer_id)),
+                false => console.print(&format!("-- {} already muted", peer_id)),
+            }
+        }
+        Input::Command(Command::Unmute(peer)) => {
+            let peer_id = node
+                .resolve(&peer)
+                .with_context(|| format!("unknown peer {}", peer))?;
+            match node.moderation().unmute(&peer_id)? {
+                true => console.print(&format!("-- unmuted {}", peer_id)),
+                false => console.print(&format!("-- {} was not muted", peer_id)),
+            }
+        }
+        Input::Command(Command::Earlier(channel)) => {
+            let channel = channel
+                .or_else(|| active.clone())
+                .context("not in any channel, /earlier <channel>")?;
+            node.earlier(&channel)?;
+        }
+        Input::Command(Command::Invite(channel)) => {
+            let channel = channel
+                .or_else(|| active.clone())
+                .context("not in any channel, /invite <channel>")?;
+            print_invite(console, &node.invite(&channel), true);
+        }
+        Input::Command(Command::Code(None)) => {
+            let code = words::peer_code(node.local_peer_id());
+            console.print(&format!("-- you: {}", code));
+            if let Some(channel) = active.as_deref() {
+                console.print(&format!("-- {}: {}", channel, words::channel_code(channel)));
+            }
+        }
+        Input::Command(Command::Code(Some(name))) => {
+            // Channels may be named like peers, joined ones win
+            if node.channels().any(|channel| channel == name) {
+                console.print(&format!("-- {}: {}", name, words::channel_code(&name)));
+            } else {
+                let peer_id = node
+                    .resolve(&name)
+                    .with_context(|| format!("unknown peer or channel {}", name))?;
+                console.print(&format!("-- {}: {}", peer_id, words::peer_code(&peer_id)));
+            }
+        }
+        Input::Command(Command::Decode(code)) => {
+            let fingerprint = words::decode(&code)?;
+            let hex: String = fingerprint.iter().map(|byte| format!("{:02x}", byte)).collect();
+            let peers = node.roster().iter().map(|(peer_id, _)| *peer_id);
+            let peer = iter::once(*node.local_peer_id())
+                .chain(peers)
+                .find(|peer_id| words::peer_fingerprint(peer_id) == fingerprint);
+            let channel = node
+                .channels()
+                .find(|channel| words::channel_fingerprint(channel) == fingerprint);
+            match (peer, channel) {
+                (Some(peer_id), _) => console.print(&format!("-- {} is {}", hex, peer_id)),
+                (None, Some(channel)) => console.print(&format!("-- {} is {}", hex, channel)),
+                (None, None) => console.print(&format!(
+                    "-- {} is no peer online nor channel we are in",
+                    hex
+                )),
+            }
+        }
+        Input::Command(Command::Blocked) => {
+            let (blocked, muted) = node.moderation().lists();
+            for peer_id in blocked {
+                console.print(&format!("-- blocked {}", peer_id));
+            }
+            for peer_id in muted {
+                console.print(&format!("-- muted {}", peer_id));
+            }
         }
     }
+    Ok(())
+}
+
+// Print an event, pointing out messages that arrived out of order with
+// `show_order`.
+fn print_event(console: &mut Console, event: NodeEvent, show_order: bool) {
+    match event {
+        NodeEvent::Message {
+            source,
+            message,
+            gap,
+            order,
+        } => {
+            let author = author_tag(source.as_ref());
+            if gap && !show_order {
+                let channel = &message.channel;
+                console.print(&format!("!! missed messages from {} in {}", author, channel));
+            }
+            let author = signed_tag(&message, author);
+            console.print(&format!(
+                "<< [{}] #{} {} {}",
+                message.channel,
+                message.id(),
+                author,
+                message
+            ));
+            if show_order {
+                match order {
+                    Order::InOrder => {}
+                    Order::After {
+                        prev,
+                        received: false,
+                    } => console.print(&format!("   ↳ after #{}, not received yet", prev)),
+                    Order::After {
+                        prev,
+                        received: true,
+                    } => console.print(&format!("   ↳ after #{}, received earlier", prev)),
+                    Order::Late { next } => {
+                        console.print(&format!("   ↱ late, comes before #{}", next))
+                    }
+                }
+            }
+        }
+        NodeEvent::DirectMessage { peer_id, message } => {
+            console.print(&format!("<< (direct) {} {}", author_tag(Some(&peer_id)), message))
+        }
+        NodeEvent::Delivered { peer_id, .. } => {
+            console.print(&format!("-- {} got your message", peer_id))
+        }
+        NodeEvent::NotDelivered { peer_id, error, .. } => {
+            console.print(&format!("!! message to {} not delivered: {:?}", peer_id, error))
+        }
+        NodeEvent::Queued { peer_id, .. } => console.print(&format!(
+            "-- {} is unreachable, your message will be sent when it is back",
+            peer_id
+        )),
+        NodeEvent::Expired { peer_id, .. } => console.print(&format!(
+            "!! message to {} dropped, it did not come back in time",
+            peer_id
+        )),
+        NodeEvent::TransferProgress {
+            peer_id,
+            name,
+            direction,
+            done,
+            size,
+        } => {
+            let verb = match direction {
+                Direction::Sending => "sent",
+                Direction::Receiving => "received",
+            };
+            let percent = (done * 100).checked_div(size).unwrap_or(100);
+            console.print(&format!(
+                "-- {} {}% of {} ({}/{} bytes) with {}",
+                verb, percent, name, done, size, peer_id
+            ))
+        }
+        NodeEvent::FileSent { peer_id, name } => {
+            console.print(&format!("-- {} got {}", peer_id, name))
+        }
+        NodeEvent::FileReceived {
+            peer_id,
+            name,
+            path,
+        } => console.print(&format!(
+            "<< (file) {} sent {}, saved to {}",
+            author_tag(Some(&peer_id)),
+            name,
+            path.display()
+        )),
+        NodeEvent::TransferFailed {
+            peer_id,
+            name,
+            direction,
+            error,
+        } => {
+            let what = match direction {
+                Direction::Sending => "sending",
+                Direction::Receiving => "receiving",
+            };
+            console.print(&format!("!! {} {} with {} failed: {}", what, name, peer_id, error))
+        }
+        NodeEvent::History {
+            peer_id,
+            channel,
+            messages,
+            more,
+            ..
+        } => {
+            if messages.is_empty() {
+                console.print(&format!("-- nothing earlier in {} from {}", channel, peer_id));
+                return;
+            }
+            console.print(&format!(
+                "-- {} earlier messages of {} from {}",
+                messages.len(),
+                channel,
+                peer_id
+            ));
+            for message in &messages {
+                print_stored(console, message);
+            }
+            if more {
+                console.print(&format!("-- more with /earlier {}", channel));
+            }
+        }
+        NodeEvent::HistoryFailed {
+            peer_id,
+            channel,
+            error,
+            ..
+        } => console.print(&format!(
+            "!! could not get history of {} from {}: {}",
+            channel, peer_id, error
+        )),
+        NodeEvent::Downgraded {
+            conversation,
+            downgrade,
+        } => console.print(&format!("!! [{}] warning: {}", conversation, downgrade)),
+        NodeEvent::Pong { peer_id, rtt } => {
+            console.print(&format!("-- pong from {} in {:?}", peer_id, rtt))
+        }
+        NodeEvent::PingFailed { peer_id, error } => {
+            console.print(&format!("!! ping to {} failed: {}", peer_id, error))
+        }
+        NodeEvent::PeerIdentified {
+            peer_id,
+            agent_version,
+            ..
+        } => log::info!("{} runs {}", peer_id, agent_version),
+        NodeEvent::PeerJoined { peer_id, channel } => {
+            console.print(&format!("-- {} joined {}", peer_id, channel))
+        }
+        NodeEvent::PeerLeft { peer_id, channel } => {
+            console.print(&format!("-- {} left {}", peer_id, channel))
+        }
+        NodeEvent::PeerOnline {
+            peer_id,
+            display_name,
+        } => {
+            let author = author_tag(Some(&peer_id));
+            console.print(&format!("-- {} {} is online", display_name, author))
+        }
+        NodeEvent::PeerOffline {
+            peer_id,
+            display_name,
+        } => {
+            let author = author_tag(Some(&peer_id));
+            console.print(&format!("-- {} {} went offline", display_name, author))
+        }
+        NodeEvent::Bootstrapped {
+            peer_id,
+            address,
+            elapsed,
+        } => console.print(&format!(
+            "-- reached the network through {} at {} in {}ms",
+            peer_id,
+            address,
+            elapsed.as_millis()
+        )),
+        NodeEvent::BootstrapFailed { tried } => console.print(&format!(
+            "!! could not reach any of the {} peers to dial and bootstrap from",
+            tried
+        )),
+        NodeEvent::Redialing {
+            address,
+            attempt,
+            delay,
+        } => console.print(&format!(
+            "-- redialing {} in {} (attempt {})",
+            address,
+            humantime::format_duration(delay),
+            attempt
+        )),
+        NodeEvent::Unreachable { address, attempts } => console.print(&format!(
+            "!! giving up on {} after {} attempts",
+            address, attempts
+        )),
+        NodeEvent::Throttled { peer_id } => console.print(&format!(
+            "!! {} is sending too fast, dropping its messages",
+            peer_id
+        )),
+        NodeEvent::Unthrottled { peer_id, dropped } => console.print(&format!(
+            "!! dropped {} messages from {} while it was sending too fast",
+            dropped, peer_id
+        )),
+        NodeEvent::Receipt {
+            id,
+            delivered_to,
+            read_by,
+            ..
+        } => console.mark(&id, format!("(got by {}, read by {})", delivered_to, read_by)),
+        NodeEvent::Listening(addr) => console.print(&format!("Listening on {:?}", addr)),
+    }
+}
+
+// Print a message kept from earlier, in either history or the starred ones.
+fn print_stored(console: &mut Console, message: &Published) {
+    let author = signed_tag(message, author_tag(message.author().as_ref()));
+    console.print(&format!("   [{}] #{} {} {}", message.channel, message.id(), author, message));
+}
+
+// Display names can be reused or changed, so show a bit of the peer id that
+// actually identifies the author next to them.
+fn author_tag(peer_id: Option<&PeerId>) -> String {
+    match peer_id {
+        Some(peer_id) => {
+            let id = peer_id.to_base58();
+            format!("~{}", &id[id.len().saturating_sub(6)..])
+        }
+        None => String::from("~unknown"),
+    }
+}
+
+// Flag messages that don't carry a valid signature by their author.
+fn signed_tag(message: &Published, author: String) -> String {
+    match message.verify() {
+        true => author,
+        false => format!("{} (unsigned)", author),
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    panic
+        .downcast_ref::<&str>()
+        .copied()
+        .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+        .unwrap_or("unknown panic payload")
 }
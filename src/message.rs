@@ -0,0 +1,414 @@
+//! The messages nodes exchange, generated from `proto/chat.proto`.
+
+use std::{
+    fmt,
+    ops::Deref,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{
+    identity::{Keypair, PublicKey},
+    PeerId,
+};
+use prost::Message;
+use sha2::{Digest, Sha256};
+
+include!(concat!(env!("OUT_DIR"), "/pingpong.rs"));
+
+impl ChatMessage {
+    /// The author, unless the message predates the `author` field.
+    pub fn author(&self) -> Option<PeerId> {
+        PeerId::from_bytes(&self.author).ok()
+    }
+
+    pub(crate) fn to_bytes(&self) -> Vec<u8> {
+        encode(self)
+    }
+}
+
+/// A chat message as it was published, along with the exact bytes its
+/// digest and signatures are taken over.
+///
+/// Published as a `SignedMessage` where every member reads those, or as a
+/// `ChatMessage` carrying its own signatures for members predating them.
+#[derive(Debug, Clone)]
+pub struct Published {
+    message: ChatMessage,
+    // `None` if published as a `ChatMessage`
+    signed: Option<SignedMessage>,
+    digest: Vec<u8>,
+}
+
+/// A chat payload: a whole message, or one of the fragments of a message
+/// too large to publish at once.
+pub(crate) enum Chat {
+    Whole(Box<Published>),
+    Part(Fragment),
+}
+
+impl Published {
+    /// Sign a message as its author, after everything else, including
+    /// `author_key`, was filled in, and by the channel owner with `owner` on
+    /// broadcast channels. With `legacy` set it is published as a
+    /// `ChatMessage`.
+    pub(crate) fn sign(
+        mut message: ChatMessage,
+        keypair: &Keypair,
+        owner: Option<&Keypair>,
+        legacy: bool,
+    ) -> anyhow::Result<Self> {
+        message.owner_signature.clear();
+        message.signature.clear();
+        if legacy {
+            // The owner signs first, the author signs over both
+            if let Some(owner) = owner {
+                message.owner_signature = owner.sign(&message.to_bytes())?;
+            }
+            message.signature = keypair.sign(&message.to_bytes())?;
+            let digest = Sha256::digest(&message.to_bytes()).to_vec();
+            return Ok(Published {
+                message,
+                signed: None,
+                digest,
+            });
+        }
+        let body = message.to_bytes();
+        let signed = SignedMessage {
+            signature: keypair.sign(&body)?,
+            owner_signature: match owner {
+                Some(owner) => owner.sign(&body)?,
+                None => Vec::new(),
+            },
+            body,
+            fragment: None,
+        };
+        Ok(Published {
+            digest: Sha256::digest(&signed.body).to_vec(),
+            message,
+            signed: Some(signed),
+        })
+    }
+
+    /// Read a chat payload published as `kind`, `None` if it is malformed.
+    pub(crate) fn decode(kind: Kind, payload: &[u8]) -> Option<Chat> {
+        match kind {
+            Kind::Chat => {
+                let mut message = ChatMessage::decode(payload).ok()?;
+                if let Some(mut fragment) = message.fragment.take() {
+                    fragment.channel = message.channel;
+                    return Some(Chat::Part(fragment));
+                }
+                Some(Chat::Whole(Box::new(Published {
+                    message,
+                    signed: None,
+                    digest: Sha256::digest(payload).to_vec(),
+                })))
+            }
+            Kind::Signed => {
+                let mut signed = SignedMessage::decode(payload).ok()?;
+                if let Some(fragment) = signed.fragment.take() {
+                    return Some(Chat::Part(fragment));
+                }
+                let message = ChatMessage::decode(signed.body.as_slice()).ok()?;
+                if message.fragment.is_some() {
+                    return None;
+                }
+                Some(Chat::Whole(Box::new(Published {
+                    digest: Sha256::digest(&signed.body).to_vec(),
+                    message,
+                    signed: Some(signed),
+                })))
+            }
+            _ => None,
+        }
+    }
+
+    /// The payload and its kind to publish the message as.
+    pub(crate) fn encode(&self) -> (Kind, Vec<u8>) {
+        match &self.signed {
+            Some(signed) => (Kind::Signed, encode(signed)),
+            None => (Kind::Chat, self.message.to_bytes()),
+        }
+    }
+
+    /// The message in an envelope, as kept on disk.
+    pub(crate) fn store(&self) -> Vec<u8> {
+        let (kind, payload) = self.encode();
+        wrap(kind, payload)
+    }
+
+    /// A message kept with [`Published::store`], or as a bare
+    /// `ChatMessage` by older versions.
+    pub(crate) fn load(bytes: Vec<u8>) -> Option<Self> {
+        let (kind, payload) = unwrap(bytes, Kind::Chat)?;
+        match Published::decode(kind, &payload)? {
+            Chat::Whole(message) => Some(*message),
+            Chat::Part(_) => None,
+        }
+    }
+
+    pub fn message(&self) -> &ChatMessage {
+        &self.message
+    }
+
+    /// Short identifier shown next to the message and used to refer to it.
+    pub fn id(&self) -> String {
+        short_id(&self.digest)
+    }
+
+    /// Hash of the signed bytes, carried as `prev` by the author's next
+    /// message.
+    pub fn digest(&self) -> Vec<u8> {
+        self.digest.clone()
+    }
+
+    /// Whether the message carries an author signature at all. Messages
+    /// from versions before signing was added carry none.
+    pub fn is_signed(&self) -> bool {
+        match &self.signed {
+            Some(signed) => !signed.signature.is_empty(),
+            None => !self.message.signature.is_empty(),
+        }
+    }
+
+    /// Whether the message carries a valid signature by a key matching its
+    /// author.
+    pub fn verify(&self) -> bool {
+        let key = match PublicKey::from_protobuf_encoding(&self.message.author_key) {
+            Ok(key) => key,
+            Err(_) => return false,
+        };
+        if self.message.author() != Some(PeerId::from(key.clone())) {
+            return false;
+        }
+        match &self.signed {
+            Some(signed) => key.verify(&signed.body, &signed.signature),
+            None => {
+                let mut unsigned = self.message.clone();
+                let signature = std::mem::take(&mut unsigned.signature);
+                key.verify(&unsigned.to_bytes(), &signature)
+            }
+        }
+    }
+
+    /// How the message was signed, `None` if published as a `ChatMessage`.
+    pub(crate) fn signed(&self) -> Option<&SignedMessage> {
+        self.signed.as_ref()
+    }
+}
+
+impl Deref for Published {
+    type Target = ChatMessage;
+
+    fn deref(&self) -> &ChatMessage {
+        &self.message
+    }
+}
+
+/// Version of the envelope format this node writes and reads.
+pub(crate) const ENVELOPE_VERSION: u32 = 1;
+
+/// Wrap an encoded payload of the given kind for publishing.
+pub(crate) fn wrap(kind: Kind, payload: Vec<u8>) -> Vec<u8> {
+    encode(&Envelope {
+        version: ENVELOPE_VERSION,
+        kind: kind as i32,
+        payload,
+    })
+}
+
+/// What a gossipsub message carries, taken to be of the `bare` kind if it
+/// is not in an envelope. Returns `None` if it can't be read by this
+/// version: a newer envelope format, or a kind it does not know.
+pub(crate) fn unwrap(data: Vec<u8>, bare: Kind) -> Option<(Kind, Vec<u8>)> {
+    // Some bare payloads decode as an envelope too, such as an announcement
+    // without a name, but never with a version
+    let envelope = match Envelope::decode(data.as_slice()) {
+        Ok(envelope) if envelope.version > 0 => envelope,
+        _ => return Some((bare, data)),
+    };
+    if envelope.version > ENVELOPE_VERSION {
+        return None;
+    }
+    match Kind::from_i32(envelope.kind) {
+        Some(Kind::Unknown) | None => None,
+        Some(kind) => Some((kind, envelope.payload)),
+    }
+}
+
+// The id of the message with this digest, see `Published::id`.
+pub(crate) fn short_id(digest: &[u8]) -> String {
+    digest.iter().take(4).map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn encode(msg: &impl Message) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(msg.encoded_len());
+    msg.encode(&mut bytes).expect("failed to encode msg");
+    bytes
+}
+
+pub(crate) fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+impl fmt::Display for ChatMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.forwarded {
+            Some(original) => {
+                let at = UNIX_EPOCH + Duration::from_secs(original.timestamp);
+                write!(
+                    f,
+                    "{} forwarded from {} in {} at {}:\n    > {}",
+                    self.display_name,
+                    original.display_name,
+                    original.channel,
+                    humantime::format_rfc3339_seconds(at),
+                    self.content
+                )
+            }
+            None => write!(f, "{}: {}", self.display_name, self.content),
+        }
+    }
+}
+
+impl fmt::Display for Published {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl fmt::Display for DirectMessage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.display_name, self.content)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Field 99, which no version knows, holding the varint 1
+    const UNKNOWN_FIELD: [u8; 3] = [0x98, 0x06, 0x01];
+
+    fn chat(keypair: &Keypair) -> ChatMessage {
+        ChatMessage {
+            display_name: String::from("alice"),
+            content: String::from("hello"),
+            channel: String::from("chat"),
+            author: PeerId::from(keypair.public()).to_bytes(),
+            author_key: keypair.public().into_protobuf_encoding(),
+            ..ChatMessage::default()
+        }
+    }
+
+    fn whole(kind: Kind, payload: &[u8]) -> Published {
+        match Published::decode(kind, payload) {
+            Some(Chat::Whole(message)) => *message,
+            _ => panic!("not a whole message"),
+        }
+    }
+
+    #[test]
+    fn signed_round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        for legacy in [false, true] {
+            let sent = Published::sign(chat(&keypair), &keypair, None, legacy).unwrap();
+            let (kind, payload) = sent.encode();
+            assert_eq!(kind, if legacy { Kind::Chat } else { Kind::Signed });
+            let received = whole(kind, &payload);
+            assert!(received.verify());
+            assert_eq!(received.digest(), sent.digest());
+            assert_eq!(received.content, "hello");
+        }
+    }
+
+    #[test]
+    fn unknown_field_still_verifies() {
+        let keypair = Keypair::generate_ed25519();
+        let mut body = chat(&keypair).to_bytes();
+        body.extend_from_slice(&UNKNOWN_FIELD);
+        let signed = SignedMessage {
+            signature: keypair.sign(&body).unwrap(),
+            body: body.clone(),
+            ..SignedMessage::default()
+        };
+        let payload = encode(&signed);
+        let received = whole(Kind::Signed, &payload);
+        assert!(received.verify());
+        assert_eq!(received.digest(), Sha256::digest(&body).to_vec());
+        // Passed on and stored as the author published it
+        assert_eq!(received.encode(), (Kind::Signed, payload));
+    }
+
+    #[test]
+    fn tampered_body_fails_to_verify() {
+        let keypair = Keypair::generate_ed25519();
+        let sent = Published::sign(chat(&keypair), &keypair, None, false).unwrap();
+        let mut signed = sent.signed().unwrap().clone();
+        let mut message = ChatMessage::decode(signed.body.as_slice()).unwrap();
+        message.content = String::from("goodbye");
+        signed.body = message.to_bytes();
+        assert!(!whole(Kind::Signed, &encode(&signed)).verify());
+    }
+
+    #[test]
+    fn signed_by_another_key_fails_to_verify() {
+        let keypair = Keypair::generate_ed25519();
+        let other = Keypair::generate_ed25519();
+        let sent = Published::sign(chat(&keypair), &other, None, false).unwrap();
+        assert!(!sent.verify());
+    }
+
+    #[test]
+    fn store_and_load() {
+        let keypair = Keypair::generate_ed25519();
+        let sent = Published::sign(chat(&keypair), &keypair, None, false).unwrap();
+        let loaded = Published::load(sent.store()).unwrap();
+        assert_eq!(loaded.digest(), sent.digest());
+        // Kept bare by versions predating envelopes on disk
+        let legacy = Published::sign(chat(&keypair), &keypair, None, true).unwrap();
+        let loaded = Published::load(legacy.message().to_bytes()).unwrap();
+        assert_eq!(loaded.digest(), legacy.digest());
+        assert!(loaded.verify());
+    }
+
+    #[test]
+    fn unwrap_envelope() {
+        let data = wrap(Kind::Control, vec![1, 2, 3]);
+        assert_eq!(unwrap(data, Kind::Chat), Some((Kind::Control, vec![1, 2, 3])));
+    }
+
+    #[test]
+    fn unwrap_bare() {
+        let bytes = chat(&Keypair::generate_ed25519()).to_bytes();
+        assert_eq!(unwrap(bytes.clone(), Kind::Chat), Some((Kind::Chat, bytes)));
+        // Decodes as an envelope with a kind but no version
+        let announcement = encode(&Announcement {
+            display_name: String::new(),
+            status: Status::Leave as i32,
+        });
+        assert!(Envelope::decode(announcement.as_slice()).is_ok());
+        assert_eq!(
+            unwrap(announcement.clone(), Kind::Presence),
+            Some((Kind::Presence, announcement))
+        );
+    }
+
+    #[test]
+    fn unwrap_unreadable() {
+        let newer = encode(&Envelope {
+            version: ENVELOPE_VERSION + 1,
+            kind: Kind::Chat as i32,
+            payload: Vec::new(),
+        });
+        assert_eq!(unwrap(newer, Kind::Chat), None);
+        let unknown = encode(&Envelope {
+            version: ENVELOPE_VERSION,
+            kind: 42,
+            payload: Vec::new(),
+        });
+        assert_eq!(unwrap(unknown, Kind::Chat), None);
+    }
+}
@@ -0,0 +1,416 @@
+//! Counters describing what a node is doing, served over HTTP in the
+//! Prometheus text format by [`serve`].
+
+use core::task::{Context as TaskContext, Poll};
+use std::{
+    collections::{BTreeMap, HashSet},
+    convert::Infallible,
+    error::Error,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use async_std::{
+    io::{self, prelude::*},
+    net::{TcpListener, TcpStream},
+    task,
+};
+use futures::StreamExt;
+use libp2p::{
+    bandwidth::BandwidthSinks,
+    core::{connection::ConnectionId, ConnectedPoint},
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, PeerId,
+};
+
+// Longest request head we read, longer ones are refused.
+const MAX_REQUEST: usize = 8 * 1024;
+// How long a client may take to send the request head.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+// How long to wait after failing to accept a connection before the next.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// Counters shared by a node and whoever reports them. They live as long as
+/// the node, across restarts of its swarm.
+#[derive(Default)]
+pub struct Metrics {
+    messages_sent: Mutex<BTreeMap<String, u64>>,
+    messages_received: Mutex<BTreeMap<String, u64>>,
+    connected_peers: AtomicU64,
+    connections: AtomicU64,
+    dial_failures: AtomicU64,
+    dedup_hits: AtomicU64,
+    dedup_misses: AtomicU64,
+    // One pair of byte counters per transport built, a restart builds a new one
+    bandwidth: Mutex<Vec<Arc<BandwidthSinks>>>,
+}
+
+impl Metrics {
+    pub(crate) fn message_sent(&self, channel: &str) {
+        count(&self.messages_sent, channel);
+    }
+
+    pub(crate) fn message_received(&self, channel: &str) {
+        count(&self.messages_received, channel);
+    }
+
+    pub(crate) fn dedup_hit(&self) {
+        self.dedup_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dedup_miss(&self) {
+        self.dedup_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_bandwidth(&self, sinks: Arc<BandwidthSinks>) {
+        self.bandwidth.lock().expect("metrics lock poisoned").push(sinks);
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        let per_channel = [
+            (
+                "pingpong_messages_sent_total",
+                "Chat messages published, per channel.",
+                &self.messages_sent,
+            ),
+            (
+                "pingpong_messages_received_total",
+                "Valid chat messages received, per channel.",
+                &self.messages_received,
+            ),
+        ];
+        for (name, help, counts) in per_channel.iter() {
+            header(&mut out, name, help, "counter");
+            for (channel, n) in counts.lock().expect("metrics lock poisoned").iter() {
+                let _ = writeln!(out, "{}{{channel=\"{}\"}} {}", name, escape(channel), n);
+            }
+        }
+        let (inbound, outbound) = self
+            .bandwidth
+            .lock()
+            .expect("metrics lock poisoned")
+            .iter()
+            .fold((0, 0), |(inbound, outbound), sinks| {
+                (inbound + sinks.total_inbound(), outbound + sinks.total_outbound())
+            });
+        let single = [
+            (
+                "pingpong_connected_peers",
+                "Peers with at least one open connection.",
+                "gauge",
+                self.connected_peers.load(Ordering::Relaxed),
+            ),
+            (
+                "pingpong_connections",
+                "Open connections.",
+                "gauge",
+                self.connections.load(Ordering::Relaxed),
+            ),
+            (
+                "pingpong_dial_failures_total",
+                "Failed attempts to connect to a peer address.",
+                "counter",
+                self.dial_failures.load(Ordering::Relaxed),
+            ),
+            (
+                "pingpong_dedup_hits_total",
+                "Chat messages dropped as copies of one seen lately.",
+                "counter",
+                self.dedup_hits.load(Ordering::Relaxed),
+            ),
+            (
+                "pingpong_dedup_misses_total",
+                "Chat messages not seen before within the dedup window.",
+                "counter",
+                self.dedup_misses.load(Ordering::Relaxed),
+            ),
+            (
+                "pingpong_inbound_bytes_total",
+                "Bytes received over all connections.",
+                "counter",
+                inbound,
+            ),
+            (
+                "pingpong_outbound_bytes_total",
+                "Bytes sent over all connections.",
+                "counter",
+                outbound,
+            ),
+        ];
+        for (name, help, kind, value) in single.iter() {
+            header(&mut out, name, help, kind);
+            let _ = writeln!(out, "{} {}", name, value);
+        }
+        out
+    }
+}
+
+fn count(counts: &Mutex<BTreeMap<String, u64>>, channel: &str) {
+    let mut counts = counts.lock().expect("metrics lock poisoned");
+    match counts.get_mut(channel) {
+        Some(n) => *n += 1,
+        None => {
+            counts.insert(channel.to_owned(), 1);
+        }
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+// Escape a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Answer `GET /metrics` on `listener` with the current metrics, for as
+/// long as the listener is open. A connection that cannot be accepted is
+/// logged and skipped.
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) {
+    let mut incoming = listener.incoming();
+    while let Some(stream) = incoming.next().await {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("failed to accept metrics connection: {}", e);
+                // Out of file descriptors, say, which takes a moment to change
+                task::sleep(ACCEPT_BACKOFF).await;
+                continue;
+            }
+        };
+        let metrics = metrics.clone();
+        task::spawn(async move {
+            if let Err(e) = respond(stream, &metrics).await {
+                log::debug!("failed to answer metrics request: {}", e);
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> io::Result<()> {
+    // Don't let a slow client hold on to the connection
+    let head = io::timeout(READ_TIMEOUT, read_head(&mut stream)).await?;
+    let (status, content_type, body) = match head {
+        Some(head) => answer(&String::from_utf8_lossy(&head), metrics),
+        None => (
+            "431 Request Header Fields Too Large",
+            "text/plain",
+            String::from("request too large\n"),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.flush().await
+}
+
+// The status, content type and body answering a request head.
+fn answer(head: &str, metrics: &Metrics) -> (&'static str, &'static str, String) {
+    let mut request_line = head.lines().next().unwrap_or("").split_whitespace();
+    match (request_line.next(), request_line.next()) {
+        (Some("GET"), Some("/metrics")) => (
+            "200 OK",
+            "text/plain; version=0.0.4",
+            metrics.render(),
+        ),
+        (Some("GET"), _) => ("404 Not Found", "text/plain", String::from("not found\n")),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            String::from("method not allowed\n"),
+        ),
+    }
+}
+
+// Only the request line matters, but read the whole head so the client isn't
+// cut off while still sending it. Gives nothing back for a head longer than
+// `MAX_REQUEST`.
+async fn read_head(stream: &mut TcpStream) -> io::Result<Option<Vec<u8>>> {
+    let mut head = Vec::new();
+    let mut buf = [0; 1024];
+    while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        if head.len() + n > MAX_REQUEST {
+            return Ok(None);
+        }
+        head.extend_from_slice(&buf[..n]);
+    }
+    Ok(Some(head))
+}
+
+/// Counts connections and dial failures as the swarm reports them, without
+/// taking part in any protocol.
+pub(crate) struct Recorder {
+    metrics: Arc<Metrics>,
+    open: u64,
+    peers: HashSet<PeerId>,
+}
+
+impl Recorder {
+    pub(crate) fn new(metrics: Arc<Metrics>) -> Self {
+        Recorder {
+            metrics,
+            open: 0,
+            peers: HashSet::new(),
+        }
+    }
+
+    /// Peers with at least one open connection.
+    pub(crate) fn peers(&self) -> impl Iterator<Item = &PeerId> {
+        self.peers.iter()
+    }
+}
+
+impl Drop for Recorder {
+    // The swarm goes away with its connections
+    fn drop(&mut self) {
+        self.metrics.connections.fetch_sub(self.open, Ordering::Relaxed);
+        let peers = self.peers.len() as u64;
+        self.metrics.connected_peers.fetch_sub(peers, Ordering::Relaxed);
+    }
+}
+
+impl NetworkBehaviour for Recorder {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = Infallible;
+
+    fn new_handler(&mut self) -> DummyProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, peer_id: &PeerId) {
+        if self.peers.insert(*peer_id) {
+            self.metrics.connected_peers.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        if self.peers.remove(peer_id) {
+            self.metrics.connected_peers.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    fn inject_connection_established(&mut self, _: &PeerId, _: &ConnectionId, _: &ConnectedPoint) {
+        self.open += 1;
+        self.metrics.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn inject_connection_closed(&mut self, _: &PeerId, _: &ConnectionId, _: &ConnectedPoint) {
+        self.open -= 1;
+        self.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    fn inject_addr_reach_failure(&mut self, _: Option<&PeerId>, _: &Multiaddr, _: &dyn Error) {
+        self.metrics.dial_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn inject_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: <DummyProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        _: &mut TaskContext<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<<DummyProtocolsHandler as ProtocolsHandler>::InEvent, Infallible>,
+    > {
+        Poll::Pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Both ends of a loopback connection.
+    async fn connection() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+        (client, server)
+    }
+
+    #[test]
+    fn answers_by_method_and_path() {
+        let metrics = Metrics::default();
+        metrics.message_sent("chat");
+        let (status, content_type, body) = answer("GET /metrics HTTP/1.1\r\n\r\n", &metrics);
+        assert_eq!((status, content_type), ("200 OK", "text/plain; version=0.0.4"));
+        assert!(body.contains("pingpong_messages_sent_total{channel=\"chat\"} 1\n"), "{}", body);
+
+        for head in ["GET / HTTP/1.1\r\n\r\n", "GET /metrics/ HTTP/1.1\r\n\r\n", "GET"] {
+            assert_eq!(answer(head, &metrics).0, "404 Not Found", "{:?}", head);
+        }
+        for head in ["POST /metrics HTTP/1.1\r\n\r\n", "get /metrics HTTP/1.1\r\n\r\n", ""] {
+            assert_eq!(answer(head, &metrics).0, "405 Method Not Allowed", "{:?}", head);
+        }
+    }
+
+    #[test]
+    fn refuses_heads_above_the_limit() {
+        task::block_on(async {
+            let (mut client, mut server) = connection().await;
+            let head = b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n";
+            client.write_all(head).await.unwrap();
+            assert_eq!(read_head(&mut server).await.unwrap().as_deref(), Some(&head[..]));
+
+            let (mut client, mut server) = connection().await;
+            let filler = format!("X-Filler: {}\r\n", "a".repeat(MAX_REQUEST));
+            let head = format!("GET /metrics HTTP/1.1\r\n{}\r\n", filler);
+            // Read concurrently, the head may not fit in the socket buffers
+            let writing = task::spawn(async move {
+                let _ = client.write_all(head.as_bytes()).await;
+            });
+            assert_eq!(read_head(&mut server).await.unwrap(), None);
+            drop(server);
+            writing.await;
+        });
+    }
+
+    #[test]
+    fn serves_metrics() {
+        task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap();
+            task::spawn(serve(listener, Arc::new(Metrics::default())));
+            let mut client = TcpStream::connect(address).await.unwrap();
+            client.write_all(b"GET /metrics HTTP/1.0\r\n\r\n").await.unwrap();
+            let mut response = String::new();
+            client.read_to_string(&mut response).await.unwrap();
+            assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+            assert!(response.contains("pingpong_connections 0\n"), "{}", response);
+        });
+    }
+}
@@ -0,0 +1,269 @@
+//! Peers we don't want to hear from.
+//!
+//! Messages and files from a blocked peer are dropped before anything shows
+//! them, and we stop relaying its channel messages. A muted peer's channel
+//! messages are still relayed to others, only hidden from us, as are its
+//! direct messages. With [`Config::refuse_blocked`] the connection gater
+//! also turns blocked peers away, so they can't connect to us at all.
+//!
+//! The lists are kept in a small text file, one `block <PEER_ID>` or
+//! `mute <PEER_ID>` line per peer. Blank lines and those starting with `#`
+//! are skipped, so it can be edited by hand.
+//!
+//! [`Config::refuse_blocked`]: crate::Config::refuse_blocked
+
+use std::{
+    collections::BTreeSet,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use anyhow::{bail, Context};
+use libp2p::{core::ConnectedPoint, Multiaddr, PeerId};
+
+use crate::{
+    gate::{self, ConnectionGater},
+    Config,
+};
+
+/// Where the lists are kept unless another path is given:
+/// `$XDG_DATA_HOME/pingpong-p2p/moderation`, falling back to
+/// `~/.local/share/pingpong-p2p/moderation`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|dir| dir.join("pingpong-p2p").join("moderation"))
+}
+
+#[derive(Debug, Default)]
+struct Lists {
+    blocked: BTreeSet<PeerId>,
+    muted: BTreeSet<PeerId>,
+}
+
+/// The blocked and muted peers, shared by the node and its connection gater.
+#[derive(Debug, Clone, Default)]
+pub struct Moderation {
+    // File rewritten on every change, if persisted
+    path: Option<PathBuf>,
+    lists: Arc<Mutex<Lists>>,
+}
+
+impl Moderation {
+    /// Load the lists stored at `path`, which is created on the first change.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        let mut lists = Lists::default();
+        for (n, line) in text.lines().enumerate() {
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let (list, peer) = match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [] => continue,
+                [list, peer] => (list, peer),
+                _ => bail!("invalid line {} in {}", n + 1, path.display()),
+            };
+            let peer_id: PeerId = peer.parse().with_context(|| {
+                format!("invalid peer id on line {} in {}", n + 1, path.display())
+            })?;
+            match list {
+                "block" => lists.blocked.insert(peer_id),
+                "mute" => lists.muted.insert(peer_id),
+                _ => bail!("invalid line {} in {}", n + 1, path.display()),
+            };
+        }
+        Ok(Moderation {
+            path: Some(path.to_owned()),
+            lists: Arc::new(Mutex::new(lists)),
+        })
+    }
+
+    pub fn is_blocked(&self, peer_id: &PeerId) -> bool {
+        self.lock().blocked.contains(peer_id)
+    }
+
+    pub fn is_muted(&self, peer_id: &PeerId) -> bool {
+        self.lock().muted.contains(peer_id)
+    }
+
+    /// Block a peer, returning false if it already was.
+    pub fn block(&self, peer_id: PeerId) -> anyhow::Result<bool> {
+        self.change(|lists| lists.blocked.insert(peer_id))
+    }
+
+    /// Unblock a peer, returning false if it was not blocked.
+    pub fn unblock(&self, peer_id: &PeerId) -> anyhow::Result<bool> {
+        self.change(|lists| lists.blocked.remove(peer_id))
+    }
+
+    /// Mute a peer, returning false if it already was.
+    pub fn mute(&self, peer_id: PeerId) -> anyhow::Result<bool> {
+        self.change(|lists| lists.muted.insert(peer_id))
+    }
+
+    /// Unmute a peer, returning false if it was not muted.
+    pub fn unmute(&self, peer_id: &PeerId) -> anyhow::Result<bool> {
+        self.change(|lists| lists.muted.remove(peer_id))
+    }
+
+    /// The blocked peers, then the muted ones.
+    pub fn lists(&self) -> (Vec<PeerId>, Vec<PeerId>) {
+        let lists = self.lock();
+        (
+            lists.blocked.iter().copied().collect(),
+            lists.muted.iter().copied().collect(),
+        )
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Lists> {
+        self.lists.lock().expect("moderation lock poisoned")
+    }
+
+    // Apply a change and write the lists out if it did anything.
+    fn change(&self, apply: impl FnOnce(&mut Lists) -> bool) -> anyhow::Result<bool> {
+        let mut lists = self.lock();
+        if !apply(&mut lists) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            save(path, &lists).with_context(|| format!("failed to save {}", path.display()))?;
+        }
+        Ok(true)
+    }
+}
+
+fn save(path: &Path, lists: &Lists) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut text = String::new();
+    for peer_id in &lists.blocked {
+        text.push_str(&format!("block {}\n", peer_id));
+    }
+    for peer_id in &lists.muted {
+        text.push_str(&format!("mute {}\n", peer_id));
+    }
+    // Write a new file first, so a crash can't leave the lists half written
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, text)?;
+    fs::rename(&tmp, path)
+}
+
+/// The gater from `config`, also refusing blocked peers with
+/// [`Config::refuse_blocked`].
+pub(crate) fn gater(config: &Config, moderation: &Moderation) -> Arc<dyn ConnectionGater> {
+    let inner = gate::of(config);
+    match config.refuse_blocked {
+        true => Arc::new(RefuseBlocked {
+            moderation: moderation.clone(),
+            inner,
+        }),
+        false => inner,
+    }
+}
+
+// Turns blocked peers away, then asks the configured gater.
+struct RefuseBlocked {
+    moderation: Moderation,
+    inner: Arc<dyn ConnectionGater>,
+}
+
+impl ConnectionGater for RefuseBlocked {
+    fn allow_dial(&self, addr: &Multiaddr) -> bool {
+        self.inner.allow_dial(addr)
+    }
+
+    fn allow_accept(&self, local_addr: &Multiaddr, remote_addr: &Multiaddr) -> bool {
+        self.inner.allow_accept(local_addr, remote_addr)
+    }
+
+    fn allow_peer(&self, peer_id: &PeerId, endpoint: &ConnectedPoint) -> bool {
+        !self.moderation.is_blocked(peer_id) && self.inner.allow_peer(peer_id, endpoint)
+    }
+
+    fn connected(&self, peer_id: &PeerId, endpoint: &ConnectedPoint) {
+        self.inner.connected(peer_id, endpoint)
+    }
+
+    fn disconnected(&self, peer_id: &PeerId, endpoint: &ConnectedPoint) {
+        self.inner.disconnected(peer_id, endpoint)
+    }
+
+    fn released(&self, addr: &Multiaddr) {
+        self.inner.released(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    // A path of its own for each test, with nothing there yet.
+    fn temp_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("pingpong-p2p-{}-{}", test, process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn reloads_saved_lists() {
+        let path = temp_path("moderation-reload");
+        let (blocked, muted) = (PeerId::random(), PeerId::random());
+        let moderation = Moderation::open(&path).unwrap();
+        assert!(moderation.block(blocked).unwrap());
+        assert!(!moderation.block(blocked).unwrap());
+        assert!(moderation.mute(muted).unwrap());
+        assert!(moderation.mute(blocked).unwrap());
+        assert!(moderation.unmute(&blocked).unwrap());
+
+        let reloaded = Moderation::open(&path).unwrap();
+        assert_eq!(reloaded.lists(), (vec![blocked], vec![muted]));
+        assert!(reloaded.is_blocked(&blocked) && !reloaded.is_muted(&blocked));
+        assert!(reloaded.is_muted(&muted) && !reloaded.is_blocked(&muted));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn skips_comments_and_refuses_bad_lines() {
+        let path = temp_path("moderation-lines");
+        let peer_id = PeerId::random();
+        let text = format!("# blocked by hand\n\n  # indented\nblock {}\n", peer_id);
+        fs::write(&path, text).unwrap();
+        assert_eq!(Moderation::open(&path).unwrap().lists(), (vec![peer_id], vec![]));
+
+        for bad in ["ban {}", "block", "block {} again", "block not-a-peer-id"] {
+            fs::write(&path, bad.replace("{}", &peer_id.to_string())).unwrap();
+            let error = Moderation::open(&path).unwrap_err();
+            assert!(format!("{:#}", error).contains("line 1"), "{}: {:#}", bad, error);
+        }
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn refuses_blocked_peers() {
+        let moderation = Moderation::default();
+        let (blocked, other) = (PeerId::random(), PeerId::random());
+        moderation.block(blocked).unwrap();
+        let endpoint = ConnectedPoint::Dialer {
+            address: "/ip4/127.0.0.1/tcp/4001".parse().unwrap(),
+        };
+        let mut config = Config::new(libp2p::identity::Keypair::generate_ed25519());
+        // Only with refuse_blocked
+        assert!(gater(&config, &moderation).allow_peer(&blocked, &endpoint));
+        config.refuse_blocked = true;
+        let gater = gater(&config, &moderation);
+        assert!(!gater.allow_peer(&blocked, &endpoint));
+        assert!(gater.allow_peer(&other, &endpoint));
+        // Let in again once unblocked
+        moderation.unblock(&blocked).unwrap();
+        assert!(gater.allow_peer(&blocked, &endpoint));
+    }
+}
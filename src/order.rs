@@ -0,0 +1,82 @@
+//! Where a message falls in its author's sequence, going by the `prev` hash
+//! it carries rather than by anyone's clock.
+//!
+//! Messages are handed over in the order they arrived, never reordered. Each
+//! one is compared with the messages its author published on the channel
+//! before, so one arriving ahead of its predecessor, or after its successor,
+//! can be pointed out.
+
+use std::collections::{HashMap, VecDeque};
+
+use libp2p::PeerId;
+
+use crate::{message, Published};
+
+// Messages remembered per author and channel to tell where a late one fits
+const REMEMBERED: usize = 64;
+
+/// How a message arrived compared to the others by its author on the same
+/// channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Order {
+    /// It follows the author's last message we got, or is the first we got.
+    InOrder,
+    /// It follows message `prev`, which is not the last we got from its
+    /// author. With `received` unset, `prev` did not arrive yet.
+    After { prev: String, received: bool },
+    /// It arrived after message `next`, which follows it.
+    Late { next: String },
+}
+
+#[derive(Default)]
+struct Chain {
+    // Digest of the latest message in arrival order, late ones aside
+    last: Option<Vec<u8>>,
+    // Digests of the messages we got, oldest first
+    got: VecDeque<Vec<u8>>,
+    // Digests of messages that did not arrive yet, with the id of the one
+    // following each
+    awaited: VecDeque<(Vec<u8>, String)>,
+}
+
+/// The messages got from each author on each channel.
+#[derive(Default)]
+pub(crate) struct Arrivals {
+    chains: HashMap<(PeerId, String), Chain>,
+}
+
+impl Arrivals {
+    /// Place a message from `author` that just arrived.
+    pub(crate) fn arrive(&mut self, author: PeerId, m: &Published) -> Order {
+        let chain = self.chains.entry((author, m.channel.clone())).or_default();
+        let digest = m.digest();
+        let late = chain.awaited.iter().position(|(awaited, _)| *awaited == digest);
+        let order = match late.and_then(|n| chain.awaited.remove(n)) {
+            Some((_, next)) => Order::Late { next },
+            None if m.prev.is_empty() || chain.last.is_none() => Order::InOrder,
+            None if chain.last.as_ref() == Some(&m.prev) => Order::InOrder,
+            None => {
+                let received = chain.got.contains(&m.prev);
+                if !received {
+                    if chain.awaited.len() == REMEMBERED {
+                        chain.awaited.pop_front();
+                    }
+                    chain.awaited.push_back((m.prev.clone(), m.id()));
+                }
+                Order::After {
+                    prev: message::short_id(&m.prev),
+                    received,
+                }
+            }
+        };
+        // A late message doesn't move the author on
+        if !matches!(order, Order::Late { .. }) {
+            chain.last = Some(digest.clone());
+        }
+        if chain.got.len() == REMEMBERED {
+            chain.got.pop_front();
+        }
+        chain.got.push_back(digest);
+        order
+    }
+}
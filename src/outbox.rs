@@ -0,0 +1,312 @@
+//! Direct messages waiting for their recipient to come back.
+//!
+//! A direct message that can't be delivered because we could not reach its
+//! recipient, or lost the connection before it was acknowledged, is kept
+//! here instead of being given up on. It is sent again once the peer is
+//! connected and identified, whether it dialed us, we found it through mDNS
+//! or one of our periodic dials got through. Messages still waiting after
+//! [`Config::outbox_ttl`] are dropped.
+//!
+//! Messages keep the request id they were first sent with, so the caller of
+//! `Node::send_direct` learns about retries through the same id. At most
+//! [`MAX_WAITING`] wait for the same recipient, further ones are given up
+//! on right away.
+//!
+//! A message whose connection closed before it was acknowledged may have
+//! arrived all the same, so sending it again can deliver it twice. Each
+//! message carries an id of its own for the recipient to tell a copy by, and
+//! the recipient remembers the ids it received lately. Peers predating these
+//! ids may still show a copy.
+//!
+//! [`Config::outbox_ttl`]: crate::Config::outbox_ttl
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use libp2p::{
+    request_response::{OutboundFailure, RequestId},
+    PeerId,
+};
+
+use crate::DirectMessage;
+
+/// How long a message waits for its recipient unless configured otherwise.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How many messages wait for the same recipient at most.
+pub const MAX_WAITING: usize = 100;
+
+// How many message ids are remembered to drop copies by, over all senders
+const RECEIVED_CAPACITY: usize = 1024;
+
+/// A message on its way, or waiting to be.
+#[derive(Debug)]
+pub(crate) struct Letter {
+    /// The id the message was first sent with.
+    pub(crate) request_id: RequestId,
+    pub(crate) message: DirectMessage,
+    queued_at: Instant,
+}
+
+/// What became of a message that was not delivered.
+#[derive(Debug)]
+pub(crate) enum Failure {
+    /// Kept to be sent again when the peer is back.
+    Queued(RequestId),
+    /// Given up on, not worth or no longer allowed to retry.
+    Failed(RequestId, OutboundFailure),
+}
+
+/// Direct messages sent and not yet acknowledged, and those waiting for
+/// their recipient.
+pub(crate) struct Outbox {
+    ttl: Duration,
+    // By the id of the request currently carrying them
+    in_flight: HashMap<RequestId, Letter>,
+    waiting: HashMap<PeerId, VecDeque<Letter>>,
+    next_id: u64,
+}
+
+impl Outbox {
+    /// Keep messages for `ttl`, none at all when zero.
+    pub(crate) fn new(ttl: Duration) -> Self {
+        // Counting from the time we started rather than from one, so ids
+        // aren't used again after a restart
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |since| since.as_nanos() as u64);
+        Outbox {
+            ttl,
+            in_flight: HashMap::new(),
+            waiting: HashMap::new(),
+            next_id: started.max(1),
+        }
+    }
+
+    /// The id of the next message sent, never zero.
+    pub(crate) fn next_id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id = self.next_id.checked_add(1).unwrap_or(1);
+        id
+    }
+
+    /// Remember a message just sent for the first time as `request_id`.
+    pub(crate) fn sent(&mut self, request_id: RequestId, message: DirectMessage) {
+        let letter = Letter {
+            request_id,
+            message,
+            queued_at: Instant::now(),
+        };
+        self.in_flight.insert(request_id, letter);
+    }
+
+    /// Remember a waiting message sent again as `request_id`.
+    pub(crate) fn resent(&mut self, request_id: RequestId, letter: Letter) {
+        self.in_flight.insert(request_id, letter);
+    }
+
+    /// The message carried by `request_id` arrived, returning the id it was
+    /// first sent with.
+    pub(crate) fn delivered(&mut self, request_id: RequestId) -> RequestId {
+        self.in_flight
+            .remove(&request_id)
+            .map_or(request_id, |letter| letter.request_id)
+    }
+
+    /// The message carried by `request_id` to `peer_id` did not arrive.
+    pub(crate) fn failed(
+        &mut self,
+        peer_id: PeerId,
+        request_id: RequestId,
+        error: OutboundFailure,
+    ) -> Failure {
+        let letter = match self.in_flight.remove(&request_id) {
+            Some(letter) => letter,
+            None => return Failure::Failed(request_id, error),
+        };
+        // Retry when the peer could not be reached, or went away before it
+        // answered, in which case the message may have arrived and the
+        // recipient drops the copy by its id. After a timeout the peer is
+        // likely just slow to answer.
+        let retry = matches!(
+            error,
+            OutboundFailure::DialFailure | OutboundFailure::ConnectionClosed
+        );
+        if !retry || letter.queued_at.elapsed() >= self.ttl {
+            return Failure::Failed(letter.request_id, error);
+        }
+        let waiting = self.waiting.entry(peer_id).or_default();
+        if waiting.len() >= MAX_WAITING {
+            return Failure::Failed(letter.request_id, error);
+        }
+        let id = letter.request_id;
+        waiting.push_back(letter);
+        Failure::Queued(id)
+    }
+
+    /// Take the messages waiting for `peer_id`, oldest first, to send them
+    /// again.
+    pub(crate) fn take(&mut self, peer_id: &PeerId) -> VecDeque<Letter> {
+        self.waiting.remove(peer_id).unwrap_or_default()
+    }
+
+    /// Peers we have messages waiting for.
+    pub(crate) fn recipients(&self) -> Vec<PeerId> {
+        self.waiting.keys().copied().collect()
+    }
+
+    /// Drop the messages that waited too long, returning their recipients
+    /// and first ids.
+    pub(crate) fn expire(&mut self) -> Vec<(PeerId, RequestId)> {
+        let ttl = self.ttl;
+        let mut expired = Vec::new();
+        self.waiting.retain(|peer_id, letters| {
+            letters.retain(|letter| {
+                let keep = letter.queued_at.elapsed() < ttl;
+                if !keep {
+                    expired.push((*peer_id, letter.request_id));
+                }
+                keep
+            });
+            !letters.is_empty()
+        });
+        expired
+    }
+}
+
+/// Ids of the direct messages received lately, to drop copies sent again
+/// after their acknowledgement was lost.
+#[derive(Default)]
+pub(crate) struct Received {
+    ids: HashSet<(PeerId, u64)>,
+    order: VecDeque<(PeerId, u64)>,
+}
+
+impl Received {
+    /// Whether message `id` from `peer_id` arrives for the first time.
+    /// Messages without an id always do.
+    pub(crate) fn first(&mut self, peer_id: PeerId, id: u64) -> bool {
+        if id == 0 {
+            return true;
+        }
+        if !self.ids.insert((peer_id, id)) {
+            return false;
+        }
+        self.order.push_back((peer_id, id));
+        if self.order.len() > RECEIVED_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.ids.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter;
+
+    use libp2p::request_response::{ProtocolSupport, RequestResponse, RequestResponseConfig};
+
+    use super::*;
+    use crate::codec::{DirectCodec, DIRECT_PROTOCOL};
+
+    // Request ids as the direct message protocol hands them out.
+    fn requests() -> impl FnMut(&PeerId, DirectMessage) -> RequestId {
+        let mut direct = RequestResponse::new(
+            DirectCodec::default(),
+            iter::once((DIRECT_PROTOCOL, ProtocolSupport::Full)),
+            RequestResponseConfig::default(),
+        );
+        move |peer_id, message| direct.send_request(peer_id, message)
+    }
+
+    fn message(outbox: &mut Outbox, content: &str) -> DirectMessage {
+        DirectMessage {
+            display_name: String::from("alice"),
+            content: content.to_owned(),
+            id: outbox.next_id(),
+        }
+    }
+
+    #[test]
+    fn queues_messages_that_may_not_have_arrived() {
+        let mut send = requests();
+        let mut outbox = Outbox::new(DEFAULT_TTL);
+        let peer_id = PeerId::random();
+        let mut sent = Vec::new();
+        for error in [OutboundFailure::DialFailure, OutboundFailure::ConnectionClosed] {
+            let message = message(&mut outbox, "hello");
+            let request_id = send(&peer_id, message.clone());
+            outbox.sent(request_id, message.clone());
+            let failure = outbox.failed(peer_id, request_id, error);
+            assert!(matches!(failure, Failure::Queued(id) if id == request_id), "{:?}", failure);
+            sent.push((request_id, message));
+        }
+        // The peer got it and was just slow to answer
+        let message = message(&mut outbox, "slow");
+        let request_id = send(&peer_id, message.clone());
+        outbox.sent(request_id, message);
+        let failure = outbox.failed(peer_id, request_id, OutboundFailure::Timeout);
+        assert!(matches!(failure, Failure::Failed(..)), "{:?}", failure);
+
+        // Sent again with their ids, and reported by the first request ids
+        assert_eq!(outbox.recipients(), [peer_id]);
+        for (letter, (request_id, message)) in outbox.take(&peer_id).into_iter().zip(sent) {
+            assert_eq!((letter.request_id, &letter.message), (request_id, &message));
+            let again = send(&peer_id, letter.message.clone());
+            outbox.resent(again, letter);
+            assert_eq!(outbox.delivered(again), request_id);
+        }
+        assert!(outbox.recipients().is_empty());
+    }
+
+    #[test]
+    fn caps_messages_waiting_per_recipient() {
+        let mut send = requests();
+        let mut outbox = Outbox::new(DEFAULT_TTL);
+        let (busy, other) = (PeerId::random(), PeerId::random());
+        let mut fail = |outbox: &mut Outbox, peer_id| {
+            let message = message(outbox, "hello");
+            let request_id = send(&peer_id, message.clone());
+            outbox.sent(request_id, message);
+            outbox.failed(peer_id, request_id, OutboundFailure::DialFailure)
+        };
+        for _ in 0..MAX_WAITING {
+            assert!(matches!(fail(&mut outbox, busy), Failure::Queued(_)));
+        }
+        assert!(matches!(fail(&mut outbox, busy), Failure::Failed(..)));
+        // Others still wait
+        assert!(matches!(fail(&mut outbox, other), Failure::Queued(_)));
+        assert_eq!(outbox.take(&busy).len(), MAX_WAITING);
+    }
+
+    #[test]
+    fn gives_each_message_an_id() {
+        let mut outbox = Outbox::new(DEFAULT_TTL);
+        let ids: HashSet<_> = (0..100).map(|_| outbox.next_id()).collect();
+        assert_eq!(ids.len(), 100);
+        assert!(!ids.contains(&0));
+    }
+
+    #[test]
+    fn drops_copies_of_received_messages() {
+        let mut received = Received::default();
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+        assert!(received.first(alice, 7));
+        assert!(!received.first(alice, 7));
+        // Ids are only unique per sender
+        assert!(received.first(bob, 7));
+        // Nor can copies from old peers be told apart
+        assert!(received.first(alice, 0) && received.first(alice, 0));
+
+        for id in 100..100 + RECEIVED_CAPACITY as u64 {
+            assert!(received.first(bob, id));
+        }
+        // Forgotten by then
+        assert!(received.first(alice, 7));
+        assert!(!received.first(bob, 100 + RECEIVED_CAPACITY as u64 - 1));
+    }
+}
@@ -0,0 +1,82 @@
+//! Who is online, learned from announcements on a control topic.
+//!
+//! Every node announces itself when it first sees another node on the
+//! topic, then every [`ANNOUNCE_INTERVAL`], and says goodbye when it shuts
+//! down. Peers that stop announcing are dropped after a few missed rounds.
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+/// Gossipsub topic announcements are published on, not a chat channel.
+pub const TOPIC: &str = "pingpong/presence";
+
+/// How often a node tells others it is still around.
+pub const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(30);
+
+// Peers not heard from for this long are considered gone
+const TIMEOUT: Duration = Duration::from_secs(3 * ANNOUNCE_INTERVAL.as_secs());
+
+/// A peer currently online.
+#[derive(Debug, Clone)]
+pub struct Presence {
+    pub display_name: String,
+    pub last_seen: Instant,
+}
+
+/// Peers currently online, not including us.
+#[derive(Debug, Default)]
+pub struct Roster {
+    peers: HashMap<PeerId, Presence>,
+}
+
+impl Roster {
+    /// Record that a peer announced itself, returning whether it just came
+    /// online.
+    pub(crate) fn seen(&mut self, peer_id: PeerId, display_name: String) -> bool {
+        let presence = Presence {
+            display_name,
+            last_seen: Instant::now(),
+        };
+        self.peers.insert(peer_id, presence).is_none()
+    }
+
+    pub(crate) fn remove(&mut self, peer_id: &PeerId) -> Option<Presence> {
+        self.peers.remove(peer_id)
+    }
+
+    /// Drop and return the peers that have not announced themselves in a
+    /// while.
+    pub(crate) fn expire(&mut self) -> Vec<(PeerId, Presence)> {
+        let expired: Vec<PeerId> = self
+            .peers
+            .iter()
+            .filter(|(_, presence)| presence.last_seen.elapsed() > TIMEOUT)
+            .map(|(peer_id, _)| *peer_id)
+            .collect();
+        expired
+            .into_iter()
+            .filter_map(|peer_id| Some((peer_id, self.peers.remove(&peer_id)?)))
+            .collect()
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&Presence> {
+        self.peers.get(peer_id)
+    }
+
+    /// Online peers, in no particular order.
+    pub fn iter(&self) -> impl Iterator<Item = (&PeerId, &Presence)> {
+        self.peers.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
@@ -0,0 +1,188 @@
+//! Picking whom to ask when several peers can serve the same request.
+//!
+//! Any member of a channel serving history can answer for it. The one asked
+//! is the one expected to answer the soonest: its average round trip time
+//! measured by ping, plus the time a page takes at the throughput its last
+//! answers came at. Peers that never answered yet count as fast as the
+//! fastest one, so each gets its chance. A provider that fails, or stalls
+//! for [`backfill::STALL_TIMEOUT`], is passed over for a while, twice as long every
+//! time in a row, and the request goes to the next best one, up to
+//! [`MAX_ATTEMPTS`] providers.
+//!
+//! [`backfill::STALL_TIMEOUT`]: crate::backfill::STALL_TIMEOUT
+
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+use crate::latency::LatencyTracker;
+
+/// Providers asked for the same thing before giving up.
+pub(crate) const MAX_ATTEMPTS: usize = 3;
+
+// Bytes of an answer assumed when weighing round trip time and throughput
+const ANSWER_SIZE: f64 = 16.0 * 1024.0;
+// Round trip time assumed for peers we have not pinged yet
+const UNKNOWN_RTT: Duration = Duration::from_millis(500);
+// Weight of the latest answer in a provider's throughput
+const SMOOTHING: f64 = 0.3;
+// How long a provider is passed over after failing once, and at most
+const BACKOFF: Duration = Duration::from_secs(30);
+const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+// Providers remembered at most
+const MAX_PROVIDERS: usize = 1024;
+
+#[derive(Default)]
+struct Record {
+    // Bytes per second, smoothed over the answers so far
+    throughput: Option<f64>,
+    // Failures since the last answer
+    failures: u32,
+    passed_over_until: Option<Instant>,
+}
+
+/// How well each provider answered so far.
+#[derive(Default)]
+pub(crate) struct Providers {
+    records: HashMap<PeerId, Record>,
+}
+
+impl Providers {
+    /// The best of `candidates` to ask, other than those `tried` already.
+    /// Providers that failed lately are only picked if no other is left.
+    pub(crate) fn best(
+        &self,
+        candidates: impl IntoIterator<Item = PeerId>,
+        tried: &[PeerId],
+        latency: &LatencyTracker,
+    ) -> Option<PeerId> {
+        let now = Instant::now();
+        let fastest = self
+            .records
+            .values()
+            .filter_map(|record| record.throughput)
+            .fold(None, |fastest: Option<f64>, t| Some(fastest.map_or(t, |f| f.max(t))));
+        candidates
+            .into_iter()
+            .filter(|peer_id| !tried.contains(peer_id))
+            .map(|peer_id| {
+                let record = self.records.get(&peer_id);
+                let passed_over = record
+                    .and_then(|record| record.passed_over_until)
+                    .is_some_and(|until| until > now);
+                let rtt = latency.stats(&peer_id).map_or(UNKNOWN_RTT, |stats| stats.avg);
+                let mut expected = rtt.as_secs_f64();
+                if let Some(throughput) = record.and_then(|record| record.throughput).or(fastest) {
+                    expected += ANSWER_SIZE / throughput.max(1.0);
+                }
+                (passed_over, expected, peer_id)
+            })
+            .min_by(|(a_over, a, _), (b_over, b, _)| {
+                a_over.cmp(b_over).then(a.partial_cmp(b).unwrap_or(Ordering::Equal))
+            })
+            .map(|(_, _, peer_id)| peer_id)
+    }
+
+    /// `peer_id` answered with `bytes` after `elapsed`.
+    pub(crate) fn answered(&mut self, peer_id: PeerId, bytes: usize, elapsed: Duration) {
+        let sample = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        let record = self.record(peer_id);
+        record.throughput = Some(match record.throughput {
+            Some(throughput) => throughput + SMOOTHING * (sample - throughput),
+            None => sample,
+        });
+        record.failures = 0;
+        record.passed_over_until = None;
+    }
+
+    /// `peer_id` failed to answer, or stalled.
+    pub(crate) fn failed(&mut self, peer_id: PeerId) {
+        let record = self.record(peer_id);
+        let backoff = BACKOFF.saturating_mul(1 << record.failures.min(16)).min(MAX_BACKOFF);
+        record.failures += 1;
+        record.passed_over_until = Some(Instant::now() + backoff);
+    }
+
+    fn record(&mut self, peer_id: PeerId) -> &mut Record {
+        if self.records.len() >= MAX_PROVIDERS && !self.records.contains_key(&peer_id) {
+            // Any one will do, it is soon measured again if it comes back
+            if let Some(other) = self.records.keys().next().copied() {
+                self.records.remove(&other);
+            }
+        }
+        self.records.entry(peer_id).or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peers(n: usize) -> Vec<PeerId> {
+        (0..n).map(|_| PeerId::random()).collect()
+    }
+
+    #[test]
+    fn prefers_short_round_trips() {
+        let peers = peers(2);
+        let mut latency = LatencyTracker::default();
+        latency.record(peers[0], Duration::from_millis(200));
+        latency.record(peers[1], Duration::from_millis(20));
+        let providers = Providers::default();
+        assert_eq!(providers.best(peers.clone(), &[], &latency), Some(peers[1]));
+        // Unless it was asked already
+        assert_eq!(providers.best(peers.clone(), &peers[1..], &latency), Some(peers[0]));
+        assert_eq!(providers.best(peers.clone(), &peers, &latency), None);
+    }
+
+    #[test]
+    fn prefers_fast_answers() {
+        let peers = peers(3);
+        let mut latency = LatencyTracker::default();
+        for peer_id in &peers {
+            latency.record(*peer_id, Duration::from_millis(50));
+        }
+        let mut providers = Providers::default();
+        providers.answered(peers[0], 1024, Duration::from_secs(1));
+        providers.answered(peers[1], 1024 * 1024, Duration::from_secs(1));
+        assert_eq!(providers.best(peers[..2].to_vec(), &[], &latency), Some(peers[1]));
+        // One never asked counts as fast as the fastest, ahead of the slow one
+        let best = providers.best(vec![peers[0], peers[2]], &[], &latency);
+        assert_eq!(best, Some(peers[2]));
+    }
+
+    #[test]
+    fn passes_over_failed_providers() {
+        let peers = peers(2);
+        let mut latency = LatencyTracker::default();
+        latency.record(peers[0], Duration::from_millis(10));
+        latency.record(peers[1], Duration::from_millis(300));
+        let mut providers = Providers::default();
+        providers.failed(peers[0]);
+        assert_eq!(providers.best(peers.clone(), &[], &latency), Some(peers[1]));
+        // Still better than nothing
+        assert_eq!(providers.best(peers.clone(), &peers[1..], &latency), Some(peers[0]));
+        // And forgiven once it answers
+        providers.answered(peers[0], 1024, Duration::from_millis(10));
+        assert_eq!(providers.best(peers.clone(), &[], &latency), Some(peers[0]));
+    }
+
+    #[test]
+    fn backs_off_longer_after_each_failure() {
+        let peer_id = PeerId::random();
+        let mut providers = Providers::default();
+        let mut backoffs = Vec::new();
+        for _ in 0..10 {
+            let before = Instant::now();
+            providers.failed(peer_id);
+            let until = providers.records[&peer_id].passed_over_until.unwrap();
+            backoffs.push((until - before).as_secs());
+        }
+        assert_eq!(backoffs[..3], [30, 60, 120]);
+        assert_eq!(*backoffs.last().unwrap(), MAX_BACKOFF.as_secs());
+    }
+}
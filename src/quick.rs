@@ -0,0 +1,166 @@
+//! Pushing a line into a room in one call, for applications that only ever
+//! notify.
+//!
+//! [`announce`] starts a node with the identity the command line client
+//! uses, joins the room, publishes once a member shows up, waits for one of
+//! them to acknowledge the message with a delivery receipt and shuts the node
+//! down again, all within [`TIMEOUT`]. A room is either a channel name,
+//! whose members are found on the local network and through the DHT, or an
+//! invite, whose inviter is bootstrapped from, see [`invite`]. On broadcast
+//! channels, where listeners stay silent, publishing is all the confirmation
+//! there is.
+//!
+//! ```no_run
+//! # async fn notify() -> Result<(), pingpong_p2p::Error> {
+//! pingpong_p2p::quick::announce("builds", "nightly build passed").await?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`invite`]: crate::invite
+
+use std::time::Duration;
+
+use async_std::future::timeout;
+use futures::prelude::*;
+use libp2p::identity::{error::DecodingError, Keypair};
+
+use crate::{broadcast, identity, invite::Invite, Config, Error, Node, NodeEvent, NodeHandle};
+
+/// How long [`announce`] waits for a member of the room to show up and
+/// acknowledge the message.
+pub const TIMEOUT: Duration = Duration::from_secs(30);
+
+// How often publishing is tried again while no member is known
+const RETRY: Duration = Duration::from_secs(1);
+
+/// Publish `content` in `room` with our identity and wait until a member got
+/// it, returning the id of the message. Fails with [`Error::Dial`] if no
+/// member showed up or acknowledged it within [`TIMEOUT`].
+pub async fn announce(room: &str, content: &str) -> Result<String, Error> {
+    announce_with(Config::new(keypair()?), room, content).await
+}
+
+/// [`announce`] with a config of our own. Its channels are replaced by the
+/// room, and an invite's inviter is added to its bootstrap peers.
+pub async fn announce_with(
+    mut config: Config,
+    room: &str,
+    content: &str,
+) -> Result<String, Error> {
+    let channel = match room.contains("pingpong:") {
+        true => {
+            let invite: Invite = room.parse().map_err(Error::decode)?;
+            let peer_id = invite.peer_id;
+            let addresses = invite.addresses.into_iter();
+            config.bootstrap.extend(addresses.map(|address| (peer_id, address)));
+            invite.channel
+        }
+        false => room.to_owned(),
+    };
+    config.channels = vec![channel.clone()];
+    let node = Node::new(config).await?.spawn();
+    let delivered = timeout(TIMEOUT, deliver(&node, &channel, content)).await;
+    let shutdown = node.shutdown().await;
+    let id = match delivered {
+        Ok(delivered) => delivered?,
+        Err(_) => {
+            let error = format!("no member of {} got the message within {:?}", channel, TIMEOUT);
+            return Err(Error::dial(error));
+        }
+    };
+    shutdown?;
+    Ok(id)
+}
+
+// The identity key of the command line client, or a new one for this call
+// if there is no home to keep it in.
+fn keypair() -> Result<Keypair, Error> {
+    let path = match identity::default_path() {
+        Some(path) => path,
+        None => return Ok(Keypair::generate_ed25519()),
+    };
+    identity::load_or_create(&path).map_err(|e| {
+        match e.root_cause().is::<DecodingError>() {
+            true => Error::decode(e),
+            false => Error::storage(e),
+        }
+    })
+}
+
+// Publish once a member of `channel` shows up, then wait for one of them to
+// acknowledge it.
+async fn deliver(node: &NodeHandle, channel: &str, content: &str) -> Result<String, Error> {
+    let mut events = node.events();
+    let id = loop {
+        match node.send(channel, content).await {
+            Ok(id) => break id,
+            Err(Error::Dial(_)) => {}
+            Err(e) => return Err(e),
+        }
+        // Try again once someone joins, or in a moment in case we missed it
+        let joined = async {
+            while let Some(event) = events.next().await {
+                if matches!(&*event, NodeEvent::PeerJoined { channel: c, .. } if c == channel) {
+                    break;
+                }
+            }
+        };
+        let _ = timeout(RETRY, joined).await;
+    };
+    if broadcast::owner(channel).is_some() {
+        return Ok(id);
+    }
+    while let Some(event) = events.next().await {
+        if let NodeEvent::Receipt { id: acknowledged, .. } = &*event {
+            if *acknowledged == id {
+                return Ok(id);
+            }
+        }
+    }
+    // The node went away
+    Err(Error::transport("the node has shut down"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use async_std::task;
+    use libp2p::Multiaddr;
+
+    use super::*;
+
+    #[test]
+    fn announces_through_an_invite() {
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let address: Multiaddr = format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap();
+        let mut config = Config::new(Keypair::generate_ed25519());
+        config.listen_addrs = vec![address.clone()];
+        config.channels = vec![String::from("quick")];
+        let listener = task::block_on(Node::new(config)).unwrap();
+        let invite = Invite::new(*listener.local_peer_id(), None, Some(address), "quick");
+        let listener = listener.spawn();
+        let mut events = listener.events();
+
+        let mut config = Config::new(Keypair::generate_ed25519());
+        config.listen_addrs = vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()];
+        let id = task::block_on(announce_with(config, &invite.to_string(), "deployed")).unwrap();
+        task::block_on(async {
+            while let Some(event) = events.next().await {
+                if let NodeEvent::Message { message, .. } = &*event {
+                    assert_eq!((message.id(), message.content.as_str()), (id, "deployed"));
+                    break;
+                }
+            }
+            listener.shutdown().await.unwrap();
+        });
+    }
+
+    #[test]
+    fn refuses_invalid_invites() {
+        let config = Config::new(Keypair::generate_ed25519());
+        let error = task::block_on(announce_with(config, "pingpong:nonsense", "hi")).unwrap_err();
+        assert!(matches!(error, Error::Decode(_)), "{:?}", error);
+    }
+}
@@ -0,0 +1,161 @@
+//! Keeping a single peer from flooding a channel.
+//!
+//! Every peer publishing channel messages gets a bucket of
+//! [`Config::message_burst`] tokens, refilled at [`Config::message_rate`] per
+//! second. Each message takes a token from its author's bucket, copies of it
+//! forwarded by other peers don't, and once the bucket is empty further
+//! messages from that peer are dropped and not forwarded, until it slows
+//! down. We warn once when a peer gets throttled and tell how many of its
+//! messages were dropped when it is let through again.
+//!
+//! [`Config::message_burst`]: crate::Config::message_burst
+//! [`Config::message_rate`]: crate::Config::message_rate
+
+use std::{collections::HashMap, time::Instant};
+
+use libp2p::PeerId;
+
+/// Messages per second a peer may publish unless configured otherwise.
+pub const DEFAULT_RATE: f64 = 5.0;
+
+/// Messages a peer may publish at once unless configured otherwise.
+pub const DEFAULT_BURST: u32 = 20;
+
+// Buckets kept before forgetting those that are full again
+const MAX_BUCKETS: usize = 1024;
+
+/// What to do with a message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Verdict {
+    Allow,
+    /// Let through after `dropped` messages were dropped since the peer got
+    /// throttled.
+    Resume { dropped: u32 },
+    /// Drop the first message since the peer got throttled.
+    Throttle,
+    /// Drop another message of a throttled peer.
+    Drop,
+}
+
+struct Bucket {
+    tokens: f64,
+    // When `tokens` was last brought up to date
+    at: Instant,
+    // Messages dropped since the bucket ran empty
+    dropped: u32,
+}
+
+/// Token buckets of the peers we got messages from.
+pub(crate) struct RateLimiter {
+    rate: f64,
+    burst: u32,
+    buckets: HashMap<PeerId, Bucket>,
+}
+
+impl RateLimiter {
+    /// Allow `rate` messages per second and up to `burst` at once, any number
+    /// with a rate of zero.
+    pub(crate) fn new(rate: f64, burst: u32) -> Self {
+        RateLimiter {
+            rate,
+            burst,
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Take a token for a message from `peer_id`.
+    pub(crate) fn check(&mut self, peer_id: PeerId) -> Verdict {
+        if self.rate <= 0.0 {
+            return Verdict::Allow;
+        }
+        let now = Instant::now();
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&peer_id) {
+            self.forget_idle(now);
+        }
+        let burst = f64::from(self.burst.max(1));
+        let rate = self.rate;
+        let bucket = self.buckets.entry(peer_id).or_insert(Bucket {
+            tokens: burst,
+            at: now,
+            dropped: 0,
+        });
+        let elapsed = now.duration_since(bucket.at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return match std::mem::take(&mut bucket.dropped) {
+                0 => Verdict::Allow,
+                dropped => Verdict::Resume { dropped },
+            };
+        }
+        bucket.dropped += 1;
+        match bucket.dropped {
+            1 => Verdict::Throttle,
+            _ => Verdict::Drop,
+        }
+    }
+
+    // Forget the peers whose buckets filled up again, they start afresh
+    // anyway.
+    fn forget_idle(&mut self, now: Instant) {
+        let burst = f64::from(self.burst.max(1));
+        let rate = self.rate;
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.at).as_secs_f64();
+            bucket.dropped > 0 || bucket.tokens + elapsed * rate < burst
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[test]
+    fn throttles_after_burst() {
+        let mut limiter = RateLimiter::new(0.001, 2);
+        let peer_id = PeerId::random();
+        assert_eq!(limiter.check(peer_id), Verdict::Allow);
+        assert_eq!(limiter.check(peer_id), Verdict::Allow);
+        assert_eq!(limiter.check(peer_id), Verdict::Throttle);
+        assert_eq!(limiter.check(peer_id), Verdict::Drop);
+        // Others have their own bucket
+        assert_eq!(limiter.check(PeerId::random()), Verdict::Allow);
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let mut limiter = RateLimiter::new(10.0, 1);
+        let peer_id = PeerId::random();
+        assert_eq!(limiter.check(peer_id), Verdict::Allow);
+        assert_eq!(limiter.check(peer_id), Verdict::Throttle);
+        assert_eq!(limiter.check(peer_id), Verdict::Drop);
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(limiter.check(peer_id), Verdict::Resume { dropped: 2 });
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(limiter.check(peer_id), Verdict::Allow);
+    }
+
+    #[test]
+    fn refills_up_to_burst() {
+        let mut limiter = RateLimiter::new(10.0, 2);
+        let peer_id = PeerId::random();
+        assert_eq!(limiter.check(peer_id), Verdict::Allow);
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(limiter.check(peer_id), Verdict::Allow);
+        assert_eq!(limiter.check(peer_id), Verdict::Allow);
+        assert_eq!(limiter.check(peer_id), Verdict::Throttle);
+    }
+
+    #[test]
+    fn zero_rate_allows_everything() {
+        let mut limiter = RateLimiter::new(0.0, 1);
+        let peer_id = PeerId::random();
+        for _ in 0..100 {
+            assert_eq!(limiter.check(peer_id), Verdict::Allow);
+        }
+    }
+}
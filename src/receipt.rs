@@ -0,0 +1,321 @@
+//! Telling authors that their messages arrived and were read.
+//!
+//! Every message we get on a channel is acknowledged with a delivery
+//! receipt, and once we typed something after it was shown, with a read
+//! receipt. Receipts are published on the message's channel as `Receipt`
+//! control messages, gathered for up to [`INTERVAL`] so a busy channel costs
+//! one receipt per member and second rather than one per message. Nodes
+//! predating receipts skip them.
+//!
+//! Only the author keeps track of who acknowledged a message, for the last
+//! few messages they sent. Nothing is sent on broadcast channels, where
+//! listeners stay silent, nor with [`Config::receipts`] unset.
+//!
+//! [`Config::receipts`]: crate::Config::receipts
+
+use std::{
+    collections::{BTreeSet, HashMap, VecDeque},
+    time::Duration,
+};
+
+use libp2p::PeerId;
+
+use crate::message::{self, Receipt, ReceiptKind};
+
+/// How long receipts are gathered before they are published.
+pub const INTERVAL: Duration = Duration::from_secs(1);
+
+// Own messages whose receipts are kept, older ones are forgotten
+const TRACKED: usize = 256;
+
+// Unread messages kept per channel, older ones are never marked read
+const UNREAD: usize = 256;
+
+// Messages acknowledged by a single receipt at most
+const MAX_DIGESTS: usize = 64;
+
+// Bytes of a message digest
+const DIGEST_LEN: usize = 32;
+
+/// Who acknowledged one of our messages.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub channel: String,
+    /// Peers the message reached, including those who read it.
+    pub delivered: BTreeSet<PeerId>,
+    pub read: BTreeSet<PeerId>,
+}
+
+/// A change in the receipts of one of our messages.
+#[derive(Debug)]
+pub(crate) struct Update {
+    pub(crate) id: String,
+    pub(crate) peer_id: PeerId,
+    pub(crate) read: bool,
+    pub(crate) delivered_to: usize,
+    pub(crate) read_by: usize,
+}
+
+/// Receipts of our messages, and those we owe to others.
+#[derive(Default)]
+pub(crate) struct Receipts {
+    // By message digest
+    tracked: HashMap<Vec<u8>, Delivery>,
+    // Digests of `tracked`, oldest first
+    order: VecDeque<Vec<u8>>,
+    // Messages got but not read yet, by channel
+    unread: HashMap<String, VecDeque<Vec<u8>>>,
+    // Digests to acknowledge at the next round, by channel and kind
+    outgoing: HashMap<(String, ReceiptKind), Vec<Vec<u8>>>,
+}
+
+impl Receipts {
+    /// Start keeping the receipts of a message we just published.
+    pub(crate) fn track(&mut self, digest: Vec<u8>, channel: &str) {
+        if self.order.len() == TRACKED {
+            if let Some(oldest) = self.order.pop_front() {
+                self.tracked.remove(&oldest);
+            }
+        }
+        let delivery = Delivery {
+            channel: channel.to_owned(),
+            delivered: BTreeSet::new(),
+            read: BTreeSet::new(),
+        };
+        self.tracked.insert(digest.clone(), delivery);
+        self.order.push_back(digest);
+    }
+
+    /// The receipts of the message with this short id, see
+    /// [`Published::id`](crate::Published::id).
+    pub(crate) fn delivery(&self, id: &str) -> Option<&Delivery> {
+        self.tracked
+            .iter()
+            .find(|(digest, _)| message::short_id(digest) == id)
+            .map(|(_, delivery)| delivery)
+    }
+
+    /// Acknowledge a message we got on `channel`, and remember it is to be
+    /// read unless it is `hidden`.
+    pub(crate) fn got(&mut self, channel: &str, digest: Vec<u8>, hidden: bool) {
+        if !hidden {
+            let unread = self.unread.entry(channel.to_owned()).or_default();
+            if unread.len() == UNREAD {
+                unread.pop_front();
+            }
+            unread.push_back(digest.clone());
+        }
+        self.queue(channel, ReceiptKind::Delivered, digest);
+    }
+
+    /// Everything got so far was read.
+    pub(crate) fn read(&mut self) {
+        for (channel, digests) in std::mem::take(&mut self.unread) {
+            for digest in digests {
+                self.queue(&channel, ReceiptKind::Read, digest);
+            }
+        }
+    }
+
+    /// Forget what was not read yet on a channel we left.
+    pub(crate) fn leave(&mut self, channel: &str) {
+        self.unread.remove(channel);
+    }
+
+    fn queue(&mut self, channel: &str, kind: ReceiptKind, digest: Vec<u8>) {
+        self.outgoing.entry((channel.to_owned(), kind)).or_default().push(digest);
+    }
+
+    /// The receipts to publish, each with the channel to publish it on.
+    pub(crate) fn take(&mut self) -> Vec<(String, Receipt)> {
+        let mut receipts = Vec::new();
+        for ((channel, kind), digests) in self.outgoing.drain() {
+            for digests in digests.chunks(MAX_DIGESTS) {
+                let receipt = Receipt {
+                    digests: digests.to_vec(),
+                    kind: kind as i32,
+                };
+                receipts.push((channel.clone(), receipt));
+            }
+        }
+        receipts
+    }
+
+    /// Take in a receipt `peer_id` published on `channel`, returning the
+    /// changes to our messages, or `None` if the receipt is malformed.
+    pub(crate) fn receive(
+        &mut self,
+        peer_id: PeerId,
+        channel: &str,
+        receipt: &Receipt,
+    ) -> Option<Vec<Update>> {
+        let read = match ReceiptKind::from_i32(receipt.kind) {
+            Some(ReceiptKind::Delivered) => false,
+            Some(ReceiptKind::Read) => true,
+            // Left to the newer versions that know what it means
+            None => return Some(Vec::new()),
+        };
+        let digests = &receipt.digests;
+        if digests.len() > MAX_DIGESTS || digests.iter().any(|d| d.len() != DIGEST_LEN) {
+            return None;
+        }
+        let mut updates = Vec::new();
+        for digest in digests {
+            let delivery = match self.tracked.get_mut(digest) {
+                Some(delivery) if delivery.channel == channel => delivery,
+                _ => continue,
+            };
+            // Reading a message means it got there
+            let delivered = delivery.delivered.insert(peer_id);
+            let changed = match read {
+                true => delivery.read.insert(peer_id),
+                false => delivered,
+            };
+            if changed {
+                updates.push(Update {
+                    id: message::short_id(digest),
+                    peer_id,
+                    read,
+                    delivered_to: delivery.delivered.len(),
+                    read_by: delivery.read.len(),
+                });
+            }
+        }
+        Some(updates)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(n: u8) -> Vec<u8> {
+        vec![n; DIGEST_LEN]
+    }
+
+    fn receipt(kind: ReceiptKind, digests: Vec<Vec<u8>>) -> Receipt {
+        Receipt {
+            digests,
+            kind: kind as i32,
+        }
+    }
+
+    // The digests queued on `channel` as `kind`
+    fn taken(receipts: &mut Receipts, channel: &str, kind: ReceiptKind) -> Vec<Vec<u8>> {
+        receipts
+            .take()
+            .into_iter()
+            .filter(|(c, r)| c == channel && r.kind == kind as i32)
+            .flat_map(|(_, r)| r.digests)
+            .collect()
+    }
+
+    #[test]
+    fn acknowledges_and_reads() {
+        let mut receipts = Receipts::default();
+        receipts.got("general", digest(1), false);
+        receipts.got("general", digest(2), true);
+        let delivered = taken(&mut receipts, "general", ReceiptKind::Delivered);
+        assert_eq!(delivered, vec![digest(1), digest(2)]);
+        assert!(receipts.take().is_empty());
+
+        // Hidden messages are never read
+        receipts.read();
+        assert_eq!(taken(&mut receipts, "general", ReceiptKind::Read), vec![digest(1)]);
+        receipts.read();
+        assert!(receipts.take().is_empty());
+    }
+
+    #[test]
+    fn forgets_left_channels() {
+        let mut receipts = Receipts::default();
+        receipts.got("general", digest(1), false);
+        receipts.leave("general");
+        receipts.take();
+        receipts.read();
+        assert!(receipts.take().is_empty());
+    }
+
+    #[test]
+    fn bounds_unread() {
+        let mut receipts = Receipts::default();
+        for n in 0..UNREAD + 10 {
+            receipts.got("general", (n as u32).to_be_bytes().repeat(8), false);
+        }
+        receipts.take();
+        receipts.read();
+        let read = taken(&mut receipts, "general", ReceiptKind::Read);
+        assert_eq!(read.len(), UNREAD);
+        assert_eq!(read[0], 10u32.to_be_bytes().repeat(8));
+    }
+
+    #[test]
+    fn splits_large_receipts() {
+        let mut receipts = Receipts::default();
+        for n in 0..MAX_DIGESTS + 1 {
+            receipts.got("general", digest(n as u8), false);
+        }
+        let mut sizes: Vec<_> = receipts.take().iter().map(|(_, r)| r.digests.len()).collect();
+        sizes.sort_unstable();
+        assert_eq!(sizes, vec![1, MAX_DIGESTS]);
+    }
+
+    #[test]
+    fn counts_receipts_of_tracked_messages() {
+        let mut receipts = Receipts::default();
+        receipts.track(digest(1), "general");
+        let (alice, bob) = (PeerId::random(), PeerId::random());
+
+        let delivered = receipt(ReceiptKind::Delivered, vec![digest(1), digest(2)]);
+        let updates = receipts.receive(alice, "general", &delivered).unwrap();
+        assert_eq!(updates.len(), 1);
+        assert_eq!(updates[0].id, message::short_id(&digest(1)));
+        assert_eq!((updates[0].read, updates[0].delivered_to, updates[0].read_by), (false, 1, 0));
+        // Repeated receipts change nothing
+        assert!(receipts.receive(alice, "general", &delivered).unwrap().is_empty());
+
+        // Reading implies delivery
+        let read = receipt(ReceiptKind::Read, vec![digest(1)]);
+        let updates = receipts.receive(bob, "general", &read).unwrap();
+        assert_eq!((updates[0].read, updates[0].delivered_to, updates[0].read_by), (true, 2, 1));
+
+        let delivery = receipts.delivery(&message::short_id(&digest(1))).unwrap();
+        assert_eq!(delivery.read.len(), 1);
+        assert!(delivery.delivered.contains(&alice) && delivery.delivered.contains(&bob));
+    }
+
+    #[test]
+    fn ignores_receipts_from_other_channels() {
+        let mut receipts = Receipts::default();
+        receipts.track(digest(1), "general");
+        let read = receipt(ReceiptKind::Read, vec![digest(1)]);
+        assert!(receipts.receive(PeerId::random(), "random", &read).unwrap().is_empty());
+    }
+
+    #[test]
+    fn forgets_old_tracked_messages() {
+        let mut receipts = Receipts::default();
+        for n in 0..=TRACKED {
+            receipts.track((n as u32).to_be_bytes().repeat(8), "general");
+        }
+        assert!(receipts.delivery(&message::short_id(&0u32.to_be_bytes().repeat(8))).is_none());
+        assert!(receipts.delivery(&message::short_id(&1u32.to_be_bytes().repeat(8))).is_some());
+    }
+
+    #[test]
+    fn rejects_malformed_receipts() {
+        let mut receipts = Receipts::default();
+        let peer_id = PeerId::random();
+        let short = receipt(ReceiptKind::Read, vec![vec![1; DIGEST_LEN - 1]]);
+        assert!(receipts.receive(peer_id, "general", &short).is_none());
+        let many = receipt(ReceiptKind::Read, vec![digest(1); MAX_DIGESTS + 1]);
+        assert!(receipts.receive(peer_id, "general", &many).is_none());
+
+        // Unknown kinds come from newer versions and are not an error
+        let unknown = Receipt {
+            digests: vec![digest(1)],
+            kind: 99,
+        };
+        assert!(receipts.receive(peer_id, "general", &unknown).unwrap().is_empty());
+    }
+}
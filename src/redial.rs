@@ -0,0 +1,254 @@
+//! Reconnecting to the peers we were told about.
+//!
+//! Addresses dialed on startup and bootstrap peers are dialed again when we
+//! lose the connection to them or fail to reach them, first after a second,
+//! then twice as long after every failure, up to five minutes. After
+//! [`Config::max_redials`](crate::Config::max_redials) failures in a row an
+//! address is given up on, until we happen to connect to its peer again.
+
+use core::task::{Context as TaskContext, Poll};
+use std::{
+    collections::VecDeque,
+    error::Error,
+    time::{Duration, Instant},
+};
+
+use futures::FutureExt;
+use futures_timer::Delay;
+use libp2p::{
+    core::{connection::ConnectionId, ConnectedPoint},
+    multiaddr::Protocol,
+    swarm::{
+        protocols_handler::DummyProtocolsHandler, NetworkBehaviour, NetworkBehaviourAction,
+        PollParameters, ProtocolsHandler,
+    },
+    Multiaddr, PeerId,
+};
+
+use crate::Config;
+
+/// How many times in a row an address is redialed unless configured
+/// otherwise.
+pub const DEFAULT_MAX_REDIALS: u32 = 10;
+
+// Wait before the first redial, doubled after every failure
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// What the redialer is up to.
+#[derive(Debug)]
+pub(crate) enum RedialEvent {
+    /// An address will be dialed again after `delay`.
+    Scheduled {
+        address: Multiaddr,
+        attempt: u32,
+        delay: Duration,
+    },
+    /// An address could not be reached `attempts` times in a row and is no
+    /// longer redialed.
+    GaveUp { address: Multiaddr, attempts: u32 },
+}
+
+struct Target {
+    address: Multiaddr,
+    // Learned from the address or from the last connection made through it
+    peer_id: Option<PeerId>,
+    connected: bool,
+    // Redials tried since we were last connected
+    attempts: u32,
+    // When to dial next, if we are waiting to
+    due: Option<Instant>,
+    gave_up: bool,
+}
+
+/// Redials lost peers, without taking part in any protocol.
+pub(crate) struct Redialer {
+    targets: Vec<Target>,
+    max_redials: u32,
+    timer: Delay,
+    events: VecDeque<RedialEvent>,
+}
+
+impl Redialer {
+    pub(crate) fn new(config: &Config) -> Self {
+        let dial = config.dial.iter().map(|address| (peer_of(address), address.clone()));
+        let bootstrap = config
+            .bootstrap
+            .iter()
+            .map(|(peer_id, address)| {
+                (Some(*peer_id), address.clone().with(Protocol::P2p((*peer_id).into())))
+            });
+        let targets = match config.max_redials {
+            0 => Vec::new(),
+            _ => dial
+                .chain(bootstrap)
+                .map(|(peer_id, address)| Target {
+                    address,
+                    peer_id,
+                    connected: false,
+                    attempts: 0,
+                    due: None,
+                    gave_up: false,
+                })
+                .collect(),
+        };
+        Redialer {
+            targets,
+            max_redials: config.max_redials,
+            timer: Delay::new(Duration::from_secs(0)),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Stop redialing anything, as we are going away.
+    pub(crate) fn stop(&mut self) {
+        self.targets.clear();
+    }
+
+    // Wait for the next redial of a target, or give up on it.
+    fn schedule(target: &mut Target, max_redials: u32, events: &mut VecDeque<RedialEvent>) {
+        if target.attempts >= max_redials {
+            log::info!("giving up on redialing {}", target.address);
+            target.due = None;
+            target.gave_up = true;
+            events.push_back(RedialEvent::GaveUp {
+                address: target.address.clone(),
+                attempts: target.attempts,
+            });
+            return;
+        }
+        let delay = INITIAL_DELAY
+            .checked_mul(1 << target.attempts.min(16))
+            .map_or(MAX_DELAY, |delay| delay.min(MAX_DELAY));
+        target.due = Some(Instant::now() + delay);
+        events.push_back(RedialEvent::Scheduled {
+            address: target.address.clone(),
+            attempt: target.attempts + 1,
+            delay,
+        });
+    }
+}
+
+// The peer id an address ends with, if any.
+fn peer_of(address: &Multiaddr) -> Option<PeerId> {
+    match address.iter().last() {
+        Some(Protocol::P2p(hash)) => PeerId::from_multihash(hash).ok(),
+        _ => None,
+    }
+}
+
+// The address without its peer id, as transports can't dial those.
+pub(crate) fn without_peer(address: &Multiaddr) -> Multiaddr {
+    address
+        .iter()
+        .filter(|protocol| !matches!(protocol, Protocol::P2p(_)))
+        .collect()
+}
+
+// Whether two addresses lead to the same place, ignoring the peer id only
+// one of them may end with.
+fn same_address(a: &Multiaddr, b: &Multiaddr) -> bool {
+    without_peer(a) == without_peer(b)
+}
+
+impl NetworkBehaviour for Redialer {
+    type ProtocolsHandler = DummyProtocolsHandler;
+    type OutEvent = RedialEvent;
+
+    fn new_handler(&mut self) -> DummyProtocolsHandler {
+        DummyProtocolsHandler::default()
+    }
+
+    fn addresses_of_peer(&mut self, _: &PeerId) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+
+    fn inject_connected(&mut self, _: &PeerId) {}
+
+    fn inject_disconnected(&mut self, peer_id: &PeerId) {
+        for target in &mut self.targets {
+            if target.peer_id == Some(*peer_id) && target.connected {
+                target.connected = false;
+                log::info!("lost connection to {}", target.address);
+                Self::schedule(target, self.max_redials, &mut self.events);
+            }
+        }
+    }
+
+    fn inject_connection_established(
+        &mut self,
+        peer_id: &PeerId,
+        _: &ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) {
+        for target in &mut self.targets {
+            let dialed = match endpoint {
+                ConnectedPoint::Dialer { address } => same_address(address, &target.address),
+                ConnectedPoint::Listener { .. } => false,
+            };
+            // Whoever answers an address without a peer id is the one we
+            // want, even if it came back with another identity
+            if dialed && peer_of(&target.address).is_none() {
+                target.peer_id = Some(*peer_id);
+            }
+            if target.peer_id == Some(*peer_id) {
+                target.connected = true;
+                target.attempts = 0;
+                target.due = None;
+                target.gave_up = false;
+            }
+        }
+    }
+
+    fn inject_addr_reach_failure(&mut self, _: Option<&PeerId>, addr: &Multiaddr, _: &dyn Error) {
+        for target in &mut self.targets {
+            // Only count the dial we are waiting on, not others made meanwhile,
+            // nor any after giving up
+            let waiting = !target.connected && target.due.is_none() && !target.gave_up;
+            if same_address(addr, &target.address) && waiting {
+                Self::schedule(target, self.max_redials, &mut self.events);
+            }
+        }
+    }
+
+    fn inject_event(
+        &mut self,
+        _: PeerId,
+        _: ConnectionId,
+        event: <DummyProtocolsHandler as ProtocolsHandler>::OutEvent,
+    ) {
+        match event {}
+    }
+
+    fn poll(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+        _: &mut impl PollParameters,
+    ) -> Poll<
+        NetworkBehaviourAction<<DummyProtocolsHandler as ProtocolsHandler>::InEvent, RedialEvent>,
+    > {
+        if let Some(event) = self.events.pop_front() {
+            return Poll::Ready(NetworkBehaviourAction::GenerateEvent(event));
+        }
+        let now = Instant::now();
+        let due = self
+            .targets
+            .iter_mut()
+            .find(|target| target.due.is_some_and(|due| due <= now));
+        if let Some(target) = due {
+            target.due = None;
+            target.attempts += 1;
+            log::debug!("redialing {}, attempt {}", target.address, target.attempts);
+            return Poll::Ready(NetworkBehaviourAction::DialAddress {
+                address: without_peer(&target.address),
+            });
+        }
+        if let Some(next) = self.targets.iter().filter_map(|target| target.due).min() {
+            self.timer.reset(next.saturating_duration_since(now));
+            if self.timer.poll_unpin(cx).is_ready() {
+                cx.waker().wake_by_ref();
+            }
+        }
+        Poll::Pending
+    }
+}
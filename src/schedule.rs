@@ -0,0 +1,85 @@
+//! How file transfers share the link with chat.
+//!
+//! Chat messages and file chunks travel over the same connections, so a
+//! chunk being sent delays any message queued behind it. Chat is always
+//! sent right away; what a [`Schedule`] decides is when the next chunk of a
+//! transfer may follow while chat is active, that is while messages were
+//! sent or received in the last [`QUIET`]. Otherwise transfers go at full
+//! speed. Held back chunks are sent in the order their transfers were
+//! ready, so transfers take turns.
+
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+
+/// How long after the last chat message the link counts as idle again.
+pub const QUIET: Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Schedule {
+    /// Send no file chunks while chat is active.
+    #[default]
+    Priority,
+    /// While chat is active, leave file transfers this percentage of the
+    /// time, measured by how long their chunks take to be acknowledged.
+    Weighted(u8),
+}
+
+impl Schedule {
+    /// How long to wait before the next chunk of a transfer whose last one
+    /// was acknowledged `busy` after it was sent, if chat was last active at
+    /// `last_chat`.
+    pub(crate) fn delay(self, busy: Duration, last_chat: Option<Instant>) -> Duration {
+        let quiet_in = match last_chat {
+            Some(at) => (at + QUIET).saturating_duration_since(Instant::now()),
+            None => Duration::from_secs(0),
+        };
+        match self {
+            Schedule::Priority | Schedule::Weighted(0) => quiet_in,
+            Schedule::Weighted(share) => {
+                let share = u32::from(share.min(100));
+                (busy * (100 - share) / share).min(quiet_in)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Schedule::Priority => f.write_str("priority"),
+            Schedule::Weighted(share) => write!(f, "weighted {}%", share),
+        }
+    }
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let schedule = match words[..] {
+            ["priority"] => Schedule::Priority,
+            ["weighted", share] => {
+                let share: u8 = share
+                    .strip_suffix('%')
+                    .unwrap_or(share)
+                    .parse()
+                    .with_context(|| format!("invalid share in schedule {:?}", s))?;
+                if share > 100 {
+                    bail!("share in schedule {:?} is over 100%", s);
+                }
+                Schedule::Weighted(share)
+            }
+            _ => bail!(
+                "invalid schedule {:?}, expected `priority` or `weighted <PERCENT>`",
+                s
+            ),
+        };
+        Ok(schedule)
+    }
+}
@@ -0,0 +1,199 @@
+//! Checking that a build and machine can run a node, for packaging and
+//! support.
+//!
+//! [`run`] signs a message and verifies it, stores it on disk and reads it
+//! back, builds the transport and listens on loopback, and unless asked not
+//! to, has a second node dial the first over loopback and publish a signed
+//! message to it, which arrives verified. Nothing is written outside a
+//! temporary directory and the nodes keep to a channel of their own.
+
+use std::{
+    fs,
+    path::Path,
+    process,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context};
+use async_std::{future::timeout, task};
+use futures::prelude::*;
+use libp2p::{identity::Keypair, Multiaddr, PeerId};
+
+use crate::{history::History, ChatMessage, Config, Node, NodeEvent, Published};
+
+/// How long listening, then dialing and delivering, may take.
+pub const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How one step went, with what is worth telling about it.
+pub struct Check {
+    pub name: &'static str,
+    pub outcome: anyhow::Result<String>,
+}
+
+/// Run every check in turn, those over loopback only with `loopback`.
+pub async fn run(loopback: bool) -> Vec<Check> {
+    let mut checks = vec![
+        Check {
+            name: "sign and verify a message",
+            outcome: sign(),
+        },
+        Check {
+            name: "store a message and read it back",
+            outcome: store(),
+        },
+    ];
+    // Nodes make for futures too large for small stacks
+    let listener = Box::pin(listen()).await;
+    let outcome = match &listener {
+        Ok((_, address)) => Ok(address.to_string()),
+        Err(e) => Err(anyhow!("{:#}", e)),
+    };
+    checks.push(Check {
+        name: "build the transport and listen on loopback",
+        outcome,
+    });
+    if loopback {
+        let outcome = match listener {
+            Ok((node, address)) => Box::pin(exchange(node, address)).await,
+            Err(_) => Err(anyhow!("nothing to dial")),
+        };
+        checks.push(Check {
+            name: "dial ourselves and exchange a signed message",
+            outcome,
+        });
+    }
+    checks
+}
+
+fn message(keypair: &Keypair, content: &str) -> ChatMessage {
+    ChatMessage {
+        display_name: String::from("selftest"),
+        content: content.to_owned(),
+        channel: String::from("selftest"),
+        author: PeerId::from(keypair.public()).to_bytes(),
+        author_key: keypair.public().into_protobuf_encoding(),
+        ..ChatMessage::default()
+    }
+}
+
+fn sign() -> anyhow::Result<String> {
+    let keypair = Keypair::generate_ed25519();
+    let signed = Published::sign(message(&keypair, "hello"), &keypair, None, false)?;
+    if !signed.verify() {
+        bail!("our own signature does not verify");
+    }
+    match Published::load(signed.store()) {
+        Some(loaded) if loaded.verify() && loaded.digest() == signed.digest() => {}
+        _ => bail!("the signature does not survive encoding"),
+    }
+    // Claiming to be someone else
+    let other = Keypair::generate_ed25519();
+    let forged = Published::sign(signed.message().clone(), &other, None, false)?;
+    if forged.verify() {
+        bail!("a forged signature verifies");
+    }
+    Ok(String::from("ed25519"))
+}
+
+fn store() -> anyhow::Result<String> {
+    let dir = std::env::temp_dir().join(format!("pingpong-p2p-selftest-{}", process::id()));
+    let stored = store_in(&dir);
+    let _ = fs::remove_dir_all(&dir);
+    stored?;
+    Ok(dir.display().to_string())
+}
+
+fn store_in(dir: &Path) -> anyhow::Result<()> {
+    let keypair = Keypair::generate_ed25519();
+    let signed = Published::sign(message(&keypair, "stored"), &keypair, None, false)?;
+    {
+        let history = History::open(dir)?;
+        history.append(&signed)?;
+        history.flush()?;
+    }
+    // Read back from disk rather than the cache
+    let history = History::open(dir)?;
+    match history.last(1)?.first() {
+        Some(read) if read.digest() == signed.digest() => Ok(()),
+        Some(_) => bail!("read back another message than was stored"),
+        None => bail!("read back nothing"),
+    }
+}
+
+// A node listening on loopback, with the address it listens at.
+async fn listen() -> anyhow::Result<(Node, Multiaddr)> {
+    let mut node = Node::new(config(Vec::new())).await?;
+    let listening = async {
+        while let Some(event) = node.next().await {
+            if let NodeEvent::Listening(address) = event {
+                return Some(address);
+            }
+        }
+        None
+    };
+    let address = timeout(TIMEOUT, listening)
+        .await
+        .ok()
+        .flatten()
+        .context("not listening")?;
+    Ok((node, address))
+}
+
+fn config(dial: Vec<Multiaddr>) -> Config {
+    let mut config = Config::new(Keypair::generate_ed25519());
+    config.listen_addrs = vec!["/ip4/127.0.0.1/tcp/0".parse().expect("valid multiaddr")];
+    config.dial = dial;
+    config.channels = Vec::new();
+    config
+}
+
+// Dial `listener` from a second node and have it publish to it.
+async fn exchange(listener: Node, address: Multiaddr) -> anyhow::Result<String> {
+    let started = Instant::now();
+    // Kept to ourselves, whoever else is on the network
+    let channel = format!("selftest-{}", listener.local_peer_id());
+    let listener = listener.spawn();
+    let mut events = listener.events();
+    let dialer = Node::new(config(vec![address])).await?;
+    let author = *dialer.local_peer_id();
+    let dialer = dialer.spawn();
+    let delivered = async {
+        for node in [&listener, &dialer] {
+            let joined = channel.clone();
+            node.call(move |node| node.join(&joined)).await??;
+        }
+        // Until the dialer heard the listener joined
+        while dialer.send(&channel, "ping").await.is_err() {
+            task::sleep(Duration::from_millis(100)).await;
+        }
+        while let Some(event) = events.next().await {
+            if let NodeEvent::Message { message, .. } = &*event {
+                if message.author() == Some(author) && message.content == "ping" {
+                    return Ok(());
+                }
+            }
+        }
+        bail!("the listener stopped")
+    };
+    let delivered = match timeout(TIMEOUT, delivered).await {
+        Ok(delivered) => delivered.map(|()| started.elapsed()),
+        Err(_) => Err(anyhow!("nothing arrived within {:?}", TIMEOUT)),
+    };
+    dialer.shutdown().await?;
+    listener.shutdown().await?;
+    Ok(format!("{:?}", delivered?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes() {
+        for check in task::block_on(run(true)) {
+            if let Err(e) = check.outcome {
+                panic!("{} failed: {:#}", check.name, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,223 @@
+//! How long we have known each peer identity.
+//!
+//! Identities cost nothing to create, so a peer only gets much of a say in
+//! how messages travel once we have known it for a while. The gossipsub score
+//! of an identity starts at zero and rises a little during its probation,
+//! then slowly up to the highest score past it. New identities are still
+//! grafted into a mesh lacking peers, but long-known peers are the ones kept
+//! when a mesh is pruned.
+//!
+//! Identities not seen for a few months are forgotten, and only the most
+//! recently seen ones are kept beyond a hundred thousand.
+
+use std::{
+    collections::HashMap,
+    convert::TryInto,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context;
+use libp2p::PeerId;
+
+/// How long a new identity stays on probation unless configured otherwise.
+pub const DEFAULT_PROBATION: Duration = Duration::from_secs(10 * 60);
+
+// How long after probation an identity takes to reach the highest score
+const MATURITY: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+// Application scores, weighted by ten by gossipsub, reached at the end of
+// probation and at maturity. Staying at zero or above lets gossipsub graft
+// anyone when a mesh needs peers.
+const PROBATION_SCORE: f64 = 0.1;
+const MATURE_SCORE: f64 = 1.0;
+// How long an identity is remembered after it was last seen
+const FORGET_AFTER: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+// Identities remembered at most
+const MAX_PEERS: usize = 100_000;
+// How stale the last-seen time on disk may get, so it is not written every
+// time a peer is scored
+const LAST_SEEN_RESOLUTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Where first-seen times are kept unless another path is given:
+/// `$XDG_DATA_HOME/pingpong-p2p/peers`, falling back to
+/// `~/.local/share/pingpong-p2p/peers`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|dir| dir.join("pingpong-p2p").join("peers"))
+}
+
+struct Known {
+    first_seen: SystemTime,
+    // As stored, brought up to date once it is `LAST_SEEN_RESOLUTION` old
+    last_seen: SystemTime,
+}
+
+/// When each identity was first and last seen, kept in memory only unless
+/// opened from a path.
+#[derive(Default)]
+pub(crate) struct Seniority {
+    db: Option<sled::Db>,
+    known: HashMap<PeerId, Known>,
+}
+
+impl Seniority {
+    /// Load the first-seen times stored at `path`, forgetting identities not
+    /// seen for long and the least recently seen beyond the limit. Entries
+    /// that fail to decode are skipped.
+    pub(crate) fn open(path: &Path) -> anyhow::Result<Self> {
+        let db = sled::open(path)
+            .with_context(|| format!("failed to open known peers at {}", path.display()))?;
+        Self::load(db)
+    }
+
+    fn load(db: sled::Db) -> anyhow::Result<Self> {
+        let now = SystemTime::now();
+        let mut known = HashMap::new();
+        for entry in db.iter() {
+            let (key, value) = entry?;
+            let peer_id = PeerId::from_bytes(&key).ok();
+            let time = |bytes: &[u8]| {
+                let secs = bytes.try_into().ok().map(u64::from_be_bytes)?;
+                Some(UNIX_EPOCH + Duration::from_secs(secs))
+            };
+            let times = match value.len() {
+                8 => time(&value).map(|first_seen| (first_seen, None)),
+                16 => time(&value[..8]).zip(time(&value[8..]).map(Some)),
+                _ => None,
+            };
+            let (peer_id, first_seen, last_seen) = match (peer_id, times) {
+                (Some(peer_id), Some((first_seen, last_seen))) => (peer_id, first_seen, last_seen),
+                _ => {
+                    log::warn!("skipping unreadable known peer entry");
+                    continue;
+                }
+            };
+            let entry = match last_seen {
+                Some(last_seen) => Known {
+                    first_seen,
+                    last_seen,
+                },
+                // Older versions only kept when it was first seen, count it
+                // as seen now
+                None => {
+                    store(&db, peer_id, first_seen, now);
+                    Known {
+                        first_seen,
+                        last_seen: now,
+                    }
+                }
+            };
+            let unseen = now.duration_since(entry.last_seen).unwrap_or_default();
+            if unseen < FORGET_AFTER {
+                known.insert(peer_id, entry);
+            } else {
+                db.remove(key)?;
+            }
+        }
+        if known.len() > MAX_PEERS {
+            let mut by_last_seen: Vec<_> =
+                known.iter().map(|(peer_id, entry)| (entry.last_seen, *peer_id)).collect();
+            by_last_seen.sort();
+            for (_, peer_id) in by_last_seen.drain(..known.len() - MAX_PEERS) {
+                known.remove(&peer_id);
+                db.remove(peer_id.to_bytes())?;
+            }
+        }
+        Ok(Seniority {
+            db: Some(db),
+            known,
+        })
+    }
+
+    /// How long we have known a peer, which starts now if we did not.
+    pub(crate) fn age(&mut self, peer_id: PeerId) -> Duration {
+        let now = SystemTime::now();
+        let entry = self.known.entry(peer_id).or_insert(Known {
+            first_seen: now,
+            last_seen: UNIX_EPOCH,
+        });
+        let stale = now.duration_since(entry.last_seen).unwrap_or_default();
+        if stale >= LAST_SEEN_RESOLUTION {
+            entry.last_seen = now;
+            if let Some(db) = &self.db {
+                store(db, peer_id, entry.first_seen, now);
+            }
+        }
+        now.duration_since(entry.first_seen).unwrap_or_default()
+    }
+
+    /// How many identities we know.
+    pub(crate) fn len(&self) -> usize {
+        self.known.len()
+    }
+
+    /// Make sure every first-seen time recorded so far is on disk.
+    pub(crate) fn flush(&self) -> anyhow::Result<()> {
+        if let Some(db) = &self.db {
+            db.flush()?;
+        }
+        Ok(())
+    }
+}
+
+// Record when `peer_id` was first and last seen, as seconds since the epoch.
+fn store(db: &sled::Db, peer_id: PeerId, first_seen: SystemTime, last_seen: SystemTime) {
+    let secs = |time: SystemTime| time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut value = [0; 16];
+    value[..8].copy_from_slice(&secs(first_seen).to_be_bytes());
+    value[8..].copy_from_slice(&secs(last_seen).to_be_bytes());
+    if let Err(e) = db.insert(peer_id.to_bytes(), &value) {
+        log::warn!("failed to store when {} was seen: {}", peer_id, e);
+    }
+}
+
+/// The gossipsub application score of an identity known for `age`.
+pub(crate) fn score(age: Duration, probation: Duration) -> f64 {
+    if age < probation {
+        return PROBATION_SCORE * age.as_secs_f64() / probation.as_secs_f64();
+    }
+    let matured = ((age - probation).as_secs_f64() / MATURITY.as_secs_f64()).min(1.0);
+    PROBATION_SCORE + (MATURE_SCORE - PROBATION_SCORE) * matured
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MINUTE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn score_rises_from_zero() {
+        let ages = [0, 1, 5, 10, 11, 60, 24 * 60, 7 * 24 * 60 + 10, 30 * 24 * 60];
+        let scores: Vec<_> = ages
+            .iter()
+            .map(|minutes| score(MINUTE * *minutes, DEFAULT_PROBATION))
+            .collect();
+        assert_eq!(scores[0], 0.0);
+        assert!(scores.windows(2).all(|pair| pair[0] <= pair[1]), "{:?}", scores);
+        assert!(scores[1] > 0.0 && scores[2] < PROBATION_SCORE);
+        assert_eq!(scores[3], PROBATION_SCORE);
+        assert_eq!(scores[7], MATURE_SCORE);
+        assert_eq!(scores[8], MATURE_SCORE);
+        assert!(score(MINUTE, Duration::ZERO) > PROBATION_SCORE);
+    }
+
+    #[test]
+    fn forgets_peers_unseen_for_long() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let now = SystemTime::now();
+        let (recent, old, legacy) = (PeerId::random(), PeerId::random(), PeerId::random());
+        store(&db, recent, now - FORGET_AFTER * 2, now - MINUTE);
+        store(&db, old, now - FORGET_AFTER * 2, now - FORGET_AFTER - MINUTE);
+        let secs = (now - MINUTE * 30).duration_since(UNIX_EPOCH).unwrap().as_secs();
+        db.insert(legacy.to_bytes(), &secs.to_be_bytes()).unwrap();
+        db.insert(b"not a peer id", &secs.to_be_bytes()).unwrap();
+        let mut seniority = Seniority::load(db.clone()).unwrap();
+        assert!(seniority.age(recent) > FORGET_AFTER);
+        assert!(seniority.age(legacy) >= MINUTE * 30);
+        assert!(seniority.age(old) < MINUTE);
+        assert_eq!(db.get(legacy.to_bytes()).unwrap().unwrap().len(), 16);
+    }
+}
@@ -0,0 +1,148 @@
+//! Staying connected to the peers we talk to most.
+//!
+//! Connections stay open while idle, but are lost when a peer restarts or
+//! the network hiccups, and the next direct message then waits for a dial
+//! and a handshake. The [`Config::standby`] peers we exchanged the most
+//! direct messages with lately are dialed again every few seconds while we
+//! are not connected to them, so that one is usually ready. Counts halve
+//! every hour, so those we stopped talking to make way, and only the busiest
+//! peers are counted at all.
+//!
+//! [`Config::standby`]: crate::Config::standby
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use libp2p::PeerId;
+
+/// How many peers are kept connected unless configured otherwise.
+pub const DEFAULT_SIZE: usize = 3;
+
+// How long it takes for a message to count half as much
+const HALF_LIFE: Duration = Duration::from_secs(60 * 60);
+// Peers counted at most, the least busy ones make way beyond this
+const MAX_CONTACTS: usize = 64;
+
+struct Contact {
+    // Messages as of `last`, each counting less the older it is
+    messages: f64,
+    last: Instant,
+}
+
+impl Contact {
+    // The messages counted `now`.
+    fn weight(&self, now: Instant) -> f64 {
+        let halvings = now.duration_since(self.last).as_secs_f64() / HALF_LIFE.as_secs_f64();
+        self.messages * 0.5f64.powf(halvings)
+    }
+}
+
+/// Direct messages exchanged with the busiest peers.
+pub(crate) struct Standby {
+    size: usize,
+    contacts: HashMap<PeerId, Contact>,
+}
+
+impl Standby {
+    /// Keep the `size` busiest peers connected, none when zero.
+    pub(crate) fn new(size: usize) -> Self {
+        Standby {
+            size,
+            contacts: HashMap::new(),
+        }
+    }
+
+    /// A direct message was sent to or received from `peer_id`.
+    pub(crate) fn messaged(&mut self, peer_id: PeerId) {
+        let now = Instant::now();
+        if !self.contacts.contains_key(&peer_id) && self.contacts.len() >= MAX_CONTACTS {
+            let least = self
+                .contacts
+                .iter()
+                .min_by(|(_, a), (_, b)| a.weight(now).total_cmp(&b.weight(now)))
+                .map(|(peer_id, _)| *peer_id);
+            if let Some(least) = least {
+                self.contacts.remove(&least);
+            }
+        }
+        let contact = self.contacts.entry(peer_id).or_insert(Contact {
+            messages: 0.0,
+            last: now,
+        });
+        contact.messages = contact.weight(now) + 1.0;
+        contact.last = now;
+    }
+
+    /// The peers to keep connected, busiest first, the latest one first
+    /// among equals.
+    pub(crate) fn pool(&self) -> Vec<PeerId> {
+        let now = Instant::now();
+        let mut contacts: Vec<_> = self
+            .contacts
+            .iter()
+            .map(|(peer_id, contact)| (contact.weight(now), *peer_id))
+            .collect();
+        contacts.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        contacts
+            .into_iter()
+            .take(self.size)
+            .map(|(_, peer_id)| peer_id)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busiest_first() {
+        let mut standby = Standby::new(2);
+        let (quiet, busy, busier) = (PeerId::random(), PeerId::random(), PeerId::random());
+        standby.messaged(quiet);
+        for _ in 0..2 {
+            standby.messaged(busy);
+        }
+        for _ in 0..3 {
+            standby.messaged(busier);
+        }
+        assert_eq!(standby.pool(), vec![busier, busy]);
+        assert!(Standby::new(0).pool().is_empty());
+    }
+
+    #[test]
+    fn latest_first_among_equals() {
+        let mut standby = Standby::new(1);
+        let (earlier, later) = (PeerId::random(), PeerId::random());
+        standby.messaged(earlier);
+        std::thread::sleep(Duration::from_millis(10));
+        standby.messaged(later);
+        assert_eq!(standby.pool(), vec![later]);
+    }
+
+    #[test]
+    fn counts_the_busiest_only() {
+        let mut standby = Standby::new(DEFAULT_SIZE);
+        let busy = PeerId::random();
+        standby.messaged(busy);
+        standby.messaged(busy);
+        for _ in 0..MAX_CONTACTS * 2 {
+            standby.messaged(PeerId::random());
+        }
+        assert_eq!(standby.contacts.len(), MAX_CONTACTS);
+        assert_eq!(standby.pool()[0], busy);
+    }
+
+    #[test]
+    fn counts_fade() {
+        let now = Instant::now();
+        let contact = Contact {
+            messages: 4.0,
+            last: now,
+        };
+        assert!((contact.weight(now + HALF_LIFE) - 2.0).abs() < 1e-9);
+        assert!((contact.weight(now + HALF_LIFE * 2) - 1.0).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,92 @@
+//! Messages saved with `/star`, kept on disk apart from any channel.
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Context};
+
+use crate::Published;
+
+/// Where starred messages are kept unless another path is given:
+/// `$XDG_DATA_HOME/pingpong-p2p/starred`, falling back to
+/// `~/.local/share/pingpong-p2p/starred`.
+pub fn default_path() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|dir| dir.join("pingpong-p2p").join("starred"))
+}
+
+/// Saved messages, in the order they were starred.
+#[derive(Debug, Default)]
+pub struct Starred {
+    // File every starred message is appended to, if persisted
+    path: Option<PathBuf>,
+    messages: Vec<Published>,
+}
+
+impl Starred {
+    /// Load the messages stored at `path`, which is created on the first star.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let messages = match fs::read(path) {
+            Ok(bytes) => decode_all(&bytes)
+                .with_context(|| format!("corrupt starred messages in {}", path.display()))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        Ok(Starred {
+            path: Some(path.to_owned()),
+            messages,
+        })
+    }
+
+    /// Save a message, returning false if it already was starred.
+    pub fn add(&mut self, message: Published) -> anyhow::Result<bool> {
+        let id = message.id();
+        if self.messages.iter().any(|m| m.id() == id) {
+            return Ok(false);
+        }
+        if let Some(path) = &self.path {
+            append(path, &message)
+                .with_context(|| format!("failed to save starred message to {}", path.display()))?;
+        }
+        self.messages.push(message);
+        Ok(true)
+    }
+
+    pub fn messages(&self) -> &[Published] {
+        &self.messages
+    }
+}
+
+// Messages are stored one after the other, each preceded by its length.
+fn decode_all(mut bytes: &[u8]) -> anyhow::Result<Vec<Published>> {
+    let mut messages = Vec::new();
+    while !bytes.is_empty() {
+        let len = prost::decode_length_delimiter(&mut bytes)?;
+        if len > bytes.len() {
+            return Err(anyhow!("truncated message"));
+        }
+        let (stored, rest) = bytes.split_at(len);
+        let message = Published::load(stored.to_vec()).ok_or_else(|| anyhow!("bad message"))?;
+        messages.push(message);
+        bytes = rest;
+    }
+    Ok(messages)
+}
+
+fn append(path: &Path, message: &Published) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let stored = message.store();
+    let mut bytes = Vec::new();
+    prost::encode_length_delimiter(stored.len(), &mut bytes).expect("failed to encode length");
+    bytes.extend(stored);
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    file.write_all(&bytes)?;
+    file.sync_all()
+}
@@ -0,0 +1,414 @@
+//! Files sent chunk by chunk over the file transfer protocol.
+//!
+//! The sender only sends the next chunk once the previous one was
+//! acknowledged, so a slow receiver is never flooded. The receiver writes
+//! chunks to a hidden `.part` file and only moves it into the download
+//! directory once the whole file matches the announced hash.
+//!
+//! Files are only accepted with a download directory, up to a size and a
+//! number at once, and a transfer whose sender goes quiet is given up on.
+//! Files are read, hashed and written on a thread pool, away from the swarm.
+
+use std::{
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+use blocking::unblock;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+use crate::message::FileChunk;
+
+/// Largest file accepted unless configured otherwise.
+pub const DEFAULT_MAX_FILE_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Files received at once, more are refused until one of them is done.
+pub(crate) const MAX_INCOMING: usize = 4;
+
+/// How long a sender may keep us waiting for its next chunk.
+pub(crate) const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+// How much of a file is sent per request.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Where received files are written unless another directory is given:
+/// `$XDG_DATA_HOME/pingpong-p2p/downloads`, falling back to
+/// `~/.local/share/pingpong-p2p/downloads`.
+pub fn default_download_dir() -> Option<PathBuf> {
+    std::env::var_os("XDG_DATA_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local/share")))
+        .map(|dir| dir.join("pingpong-p2p").join("downloads"))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Sending,
+    Receiving,
+}
+
+/// A file we are sending.
+pub(crate) struct Outgoing {
+    pub(crate) peer_id: PeerId,
+    pub(crate) name: String,
+    transfer_id: u64,
+    file: File,
+    size: u64,
+    sent: u64,
+    sha256: Vec<u8>,
+    started: bool,
+    // When the latest chunk was handed out to be sent
+    chunk_sent: Instant,
+}
+
+/// The name a file is sent under.
+pub(crate) fn name_of(path: &Path) -> anyhow::Result<String> {
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .with_context(|| format!("{} has no usable file name", path.display()))?;
+    Ok(name.to_owned())
+}
+
+impl Outgoing {
+    /// Open and hash the file to send as `name`, see [`name_of`].
+    pub(crate) async fn open(
+        peer_id: PeerId,
+        name: String,
+        path: PathBuf,
+        transfer_id: u64,
+    ) -> anyhow::Result<Self> {
+        unblock(move || {
+            let mut file =
+                File::open(&path).with_context(|| format!("failed to open {}", path.display()))?;
+            let size = file.metadata()?.len();
+            let mut hasher = Sha256::new();
+            io::copy(&mut file, &mut hasher)?;
+            file.seek(SeekFrom::Start(0))?;
+            Ok(Outgoing {
+                peer_id,
+                name,
+                transfer_id,
+                file,
+                size,
+                sent: 0,
+                sha256: hasher.finalize().to_vec(),
+                started: false,
+                chunk_sent: Instant::now(),
+            })
+        })
+        .await
+    }
+
+    /// Read the next chunk to send, `None` once the whole file was sent.
+    pub(crate) async fn next_chunk(mut self) -> (Self, io::Result<Option<FileChunk>>) {
+        unblock(move || {
+            let chunk = self.read_chunk();
+            (self, chunk)
+        })
+        .await
+    }
+
+    // An empty file is still sent as one empty chunk.
+    fn read_chunk(&mut self) -> io::Result<Option<FileChunk>> {
+        if self.started && self.sent == self.size {
+            return Ok(None);
+        }
+        let mut data = Vec::with_capacity(CHUNK_SIZE);
+        (&mut self.file)
+            .take(CHUNK_SIZE as u64)
+            .read_to_end(&mut data)?;
+        if data.is_empty() && self.sent < self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file shrank while sending it",
+            ));
+        }
+        let chunk = FileChunk {
+            transfer_id: self.transfer_id,
+            name: self.name.clone(),
+            size: self.size,
+            offset: self.sent,
+            data,
+            sha256: self.sha256.clone(),
+        };
+        self.sent += chunk.data.len() as u64;
+        self.started = true;
+        self.chunk_sent = Instant::now();
+        Ok(Some(chunk))
+    }
+
+    /// Bytes sent so far and the file size.
+    pub(crate) fn progress(&self) -> (u64, u64) {
+        (self.sent, self.size)
+    }
+
+    /// How long ago the latest chunk was handed out to be sent.
+    pub(crate) fn busy(&self) -> Duration {
+        self.chunk_sent.elapsed()
+    }
+}
+
+/// A file we are receiving.
+pub(crate) struct Incoming {
+    pub(crate) name: String,
+    size: u64,
+    received: u64,
+    sha256: Vec<u8>,
+    hasher: Sha256,
+    // Created along with the first chunk
+    file: Option<File>,
+    dir: PathBuf,
+    part_path: PathBuf,
+    last_chunk: Instant,
+}
+
+impl Incoming {
+    /// Start receiving the file `peer_id` announced by its first chunk, if it
+    /// is no larger than `max_size`. Nothing is written before
+    /// [`Incoming::write`].
+    pub(crate) fn start(
+        dir: &Path,
+        peer_id: PeerId,
+        chunk: &FileChunk,
+        max_size: u64,
+    ) -> anyhow::Result<Self> {
+        if chunk.offset != 0 {
+            bail!(
+                "transfer {} does not start at the beginning",
+                chunk.transfer_id
+            );
+        }
+        if chunk.size > max_size {
+            bail!(
+                "file is {} bytes, more than the {} accepted",
+                chunk.size,
+                max_size
+            );
+        }
+        let name = sanitize(&chunk.name)?;
+        // Every sender numbers its transfers from zero
+        let part_path = dir.join(format!(".{}.{}.{}.part", name, peer_id, chunk.transfer_id));
+        Ok(Incoming {
+            name,
+            size: chunk.size,
+            received: 0,
+            sha256: chunk.sha256.clone(),
+            hasher: Sha256::new(),
+            file: None,
+            dir: dir.to_owned(),
+            part_path,
+            last_chunk: Instant::now(),
+        })
+    }
+
+    /// Append a chunk, returning whether the file is complete.
+    pub(crate) async fn write(mut self, chunk: FileChunk) -> (Self, anyhow::Result<bool>) {
+        unblock(move || {
+            let complete = self.append(&chunk);
+            (self, complete)
+        })
+        .await
+    }
+
+    fn append(&mut self, chunk: &FileChunk) -> anyhow::Result<bool> {
+        self.last_chunk = Instant::now();
+        if chunk.offset != self.received {
+            bail!("expected offset {}, got {}", self.received, chunk.offset);
+        }
+        let end = self.received + chunk.data.len() as u64;
+        if end > self.size {
+            bail!("sender went past the announced size of {} bytes", self.size);
+        }
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => {
+                let dir = &self.dir;
+                fs::create_dir_all(dir)
+                    .with_context(|| format!("failed to create {}", dir.display()))?;
+                let file = File::create(&self.part_path)
+                    .with_context(|| format!("failed to create {}", self.part_path.display()))?;
+                self.file.insert(file)
+            }
+        };
+        file.write_all(&chunk.data)?;
+        self.hasher.update(&chunk.data);
+        self.received = end;
+        Ok(self.received == self.size)
+    }
+
+    /// Whether the sender kept us waiting for longer than [`IDLE_TIMEOUT`].
+    pub(crate) fn is_idle(&self) -> bool {
+        self.last_chunk.elapsed() >= IDLE_TIMEOUT
+    }
+
+    /// Bytes received so far and the file size.
+    pub(crate) fn progress(&self) -> (u64, u64) {
+        (self.received, self.size)
+    }
+
+    /// Check the hash and move the file into the download directory,
+    /// returning where it ended up.
+    pub(crate) async fn finish(self) -> anyhow::Result<PathBuf> {
+        unblock(move || {
+            if let Some(file) = &self.file {
+                file.sync_all()?;
+            }
+            if self.hasher.finalize().as_slice() != self.sha256.as_slice() {
+                let _ = fs::remove_file(&self.part_path);
+                bail!("content hash does not match, file discarded");
+            }
+            let path = unique_path(&self.dir, &self.name);
+            fs::rename(&self.part_path, &path)
+                .with_context(|| format!("failed to move file to {}", path.display()))?;
+            Ok(path)
+        })
+        .await
+    }
+
+    /// Give up on the transfer and remove what was received, in the
+    /// background.
+    pub(crate) fn abort(self) {
+        let Incoming { file, part_path, .. } = self;
+        let file = match file {
+            Some(file) => file,
+            None => return,
+        };
+        async_std::task::spawn(unblock(move || {
+            drop(file);
+            if let Err(e) = fs::remove_file(&part_path) {
+                log::warn!("failed to remove {}: {}", part_path.display(), e);
+            }
+        }));
+    }
+}
+
+/// How many tenths of a transfer are done, used to report progress in
+/// steps instead of on every chunk.
+pub(crate) fn tenths(done: u64, size: u64) -> u64 {
+    match size {
+        0 => 10,
+        size => done.saturating_mul(10) / size,
+    }
+}
+
+// Accept a plain file name only, so a sender can't write outside the
+// download directory.
+fn sanitize(name: &str) -> anyhow::Result<String> {
+    let path = Path::new(name);
+    match path.file_name().and_then(|file_name| file_name.to_str()) {
+        Some(file_name) if file_name == name && !name.starts_with('.') => Ok(name.to_owned()),
+        _ => bail!("refusing file name {:?}", name),
+    }
+}
+
+// `name`, or `name (1)`, `name (2)`, ... if it is taken.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    let path = dir.join(name);
+    if !path.exists() {
+        return path;
+    }
+    let (stem, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot..]),
+        _ => (name, ""),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !path.exists())
+        .expect("ran out of file names")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use async_std::task;
+
+    use super::*;
+
+    // An empty directory of its own for each test.
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pingpong-p2p-{}-{}", test, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn chunk(data: &[u8], sha256: Vec<u8>) -> FileChunk {
+        FileChunk {
+            transfer_id: 0,
+            name: String::from("notes.txt"),
+            size: data.len() as u64,
+            offset: 0,
+            data: data.to_vec(),
+            sha256,
+        }
+    }
+
+    fn receive(dir: &Path, peer_id: PeerId, chunk: FileChunk) -> anyhow::Result<PathBuf> {
+        let incoming = Incoming::start(dir, peer_id, &chunk, DEFAULT_MAX_FILE_SIZE)?;
+        let (incoming, complete) = task::block_on(incoming.write(chunk));
+        assert!(complete?);
+        task::block_on(incoming.finish())
+    }
+
+    #[test]
+    fn keeps_matching_file() {
+        let dir = temp_dir("keeps-matching-file");
+        let data = b"hello";
+        let path = receive(&dir, PeerId::random(), chunk(data, Sha256::digest(data).to_vec()));
+        assert_eq!(fs::read(path.unwrap()).unwrap(), data);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn discards_mismatched_file() {
+        let dir = temp_dir("discards-mismatched-file");
+        let wrong = Sha256::digest(b"bye").to_vec();
+        let result = receive(&dir, PeerId::random(), chunk(b"hello", wrong));
+        assert!(result.is_err());
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn senders_get_their_own_part_file() {
+        let dir = temp_dir("own-part-file");
+        let chunk = chunk(b"hello", Sha256::digest(b"hello").to_vec());
+        let one = Incoming::start(&dir, PeerId::random(), &chunk, DEFAULT_MAX_FILE_SIZE).unwrap();
+        let other = Incoming::start(&dir, PeerId::random(), &chunk, DEFAULT_MAX_FILE_SIZE).unwrap();
+        assert_ne!(one.part_path, other.part_path);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn refuses_too_large_file() {
+        let chunk = chunk(b"hello", Vec::new());
+        assert!(Incoming::start(Path::new("."), PeerId::random(), &chunk, 4).is_err());
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize("notes.txt").unwrap(), "notes.txt");
+        for name in ["", ".", "..", "../notes.txt", "dir/notes.txt", "/etc/passwd", ".hidden"] {
+            assert!(sanitize(name).is_err(), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn never_overwrites() {
+        let dir = temp_dir("never-overwrites");
+        assert_eq!(unique_path(&dir, "notes.txt"), dir.join("notes.txt"));
+        fs::write(dir.join("notes.txt"), b"first").unwrap();
+        assert_eq!(unique_path(&dir, "notes.txt"), dir.join("notes (1).txt"));
+        fs::write(dir.join("notes (1).txt"), b"second").unwrap();
+        assert_eq!(unique_path(&dir, "notes.txt"), dir.join("notes (2).txt"));
+        fs::write(dir.join("README"), b"").unwrap();
+        assert_eq!(unique_path(&dir, "README"), dir.join("README (1)"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,303 @@
+//! Full-screen terminal interface: messages on the left, who is online on
+//! the right and the line being typed at the bottom, so output never gets
+//! mixed into what is being typed.
+
+use core::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{self, Stdout},
+};
+
+use crossterm::{
+    event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    execute,
+    terminal::{self, EnterAlternateScreen, LeaveAlternateScreen},
+};
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    prelude::*,
+};
+use ratatui::{
+    backend::CrosstermBackend,
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, List, Paragraph},
+    Terminal,
+};
+
+// Most lines kept for scrolling back.
+const SCROLLBACK: usize = 10_000;
+const SIDEBAR_WIDTH: u16 = 28;
+
+// A line of the message pane.
+struct Entry {
+    text: String,
+    // Id of our message the line shows, if it does
+    sent: Option<String>,
+}
+
+/// The terminal taken over until dropped. As a [`Stream`] it yields every
+/// line entered, and ends on Ctrl-C or Ctrl-D.
+pub struct Tui {
+    terminal: Terminal<CrosstermBackend<Stdout>>,
+    events: EventStream,
+    logs: UnboundedReceiver<String>,
+    // Everything printed, oldest first
+    lines: VecDeque<Entry>,
+    // Receipts of our messages shown after them, by message id
+    marks: HashMap<String, String>,
+    // How many lines the message pane is scrolled up from the bottom
+    scroll: usize,
+    input: Vec<char>,
+    cursor: usize,
+}
+
+impl Tui {
+    /// Switch the terminal to the interface, showing `logs` among the
+    /// messages.
+    pub fn new(logs: UnboundedReceiver<String>) -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen)?;
+        Ok(Tui {
+            terminal: Terminal::new(CrosstermBackend::new(stdout))?,
+            events: EventStream::new(),
+            logs,
+            lines: VecDeque::new(),
+            marks: HashMap::new(),
+            scroll: 0,
+            input: Vec::new(),
+            cursor: 0,
+        })
+    }
+
+    /// Add text to the message pane, one line per line of `text`.
+    pub fn print(&mut self, text: &str) {
+        for line in text.lines() {
+            if self.lines.len() == SCROLLBACK {
+                if let Some(id) = self.lines.pop_front().and_then(|entry| entry.sent) {
+                    self.marks.remove(&id);
+                }
+            }
+            self.lines.push_back(Entry {
+                text: line.to_owned(),
+                sent: None,
+            });
+            // Keep showing the same lines while scrolled back
+            if self.scroll > 0 {
+                self.scroll += 1;
+            }
+        }
+    }
+
+    /// Tie the last line printed to our message `id`, so its receipts can be
+    /// shown after it.
+    pub fn sent(&mut self, id: &str) {
+        if let Some(entry) = self.lines.back_mut() {
+            entry.sent = Some(id.to_owned());
+        }
+    }
+
+    /// Show `mark` after the line of our message `id`, replacing the last
+    /// one.
+    pub fn mark(&mut self, id: &str, mark: String) {
+        if self.lines.iter().any(|entry| entry.sent.as_deref() == Some(id)) {
+            self.marks.insert(id.to_owned(), mark);
+        }
+    }
+
+    /// Redraw the interface, with the active channel as `title` and the
+    /// `online` peers in the sidebar.
+    pub fn draw(&mut self, title: &str, online: &[String]) -> io::Result<()> {
+        let Tui {
+            terminal,
+            lines,
+            marks,
+            scroll,
+            input,
+            cursor,
+            ..
+        } = self;
+        terminal.draw(|frame| {
+            let [main, input_area] =
+                Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+            let [messages_area, sidebar] =
+                Layout::horizontal([Constraint::Min(20), Constraint::Length(SIDEBAR_WIDTH)])
+                    .areas(main);
+
+            // Wrap only as many lines from the bottom as can be shown
+            let width = usize::from(messages_area.width.saturating_sub(2)).max(1);
+            let height = usize::from(messages_area.height.saturating_sub(2));
+            let mut shown: Vec<Line> = Vec::new();
+            for entry in lines.iter().rev() {
+                if shown.len() >= height + *scroll {
+                    break;
+                }
+                let style = style_of(&entry.text);
+                let line = match entry.sent.as_ref().and_then(|id| marks.get(id)) {
+                    Some(mark) => format!("{} {}", entry.text, mark),
+                    None => entry.text.clone(),
+                };
+                let parts: Vec<Line> = wrap(&line, width)
+                    .into_iter()
+                    .map(|part| Line::styled(part, style))
+                    .collect();
+                shown.extend(parts.into_iter().rev());
+            }
+            *scroll = (*scroll).min(shown.len().saturating_sub(height));
+            let shown: Vec<Line> = shown.into_iter().skip(*scroll).take(height).rev().collect();
+            let messages =
+                Paragraph::new(shown).block(Block::bordered().title(format!(" {} ", title)));
+            frame.render_widget(messages, messages_area);
+
+            let peers = List::new(online.iter().map(String::as_str))
+                .block(Block::bordered().title(format!(" Online ({}) ", online.len())));
+            frame.render_widget(peers, sidebar);
+
+            // Keep the cursor in view on long lines
+            let input_width = usize::from(input_area.width.saturating_sub(2)).max(1);
+            let offset = cursor.saturating_sub(input_width - 1);
+            let text: String = input.iter().skip(offset).collect();
+            frame.render_widget(Paragraph::new(text).block(Block::bordered()), input_area);
+            frame.set_cursor_position((
+                input_area.x + 1 + (*cursor - offset) as u16,
+                input_area.y + 1,
+            ));
+        })?;
+        Ok(())
+    }
+
+    // Edit the input line, returning it once entered.
+    fn key(&mut self, key: KeyEvent) -> Option<String> {
+        match key.code {
+            KeyCode::Enter => {
+                self.cursor = 0;
+                return Some(self.input.drain(..).collect());
+            }
+            KeyCode::Char(c) => {
+                self.input.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.input.len() => {
+                self.input.remove(self.cursor);
+            }
+            KeyCode::Left => self.cursor = self.cursor.saturating_sub(1),
+            KeyCode::Right => self.cursor = (self.cursor + 1).min(self.input.len()),
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.input.len(),
+            KeyCode::PageUp => self.scroll += self.page(),
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(self.page()),
+            _ => {}
+        }
+        None
+    }
+
+    // How many lines PageUp and PageDown scroll by.
+    fn page(&self) -> usize {
+        let height = self.terminal.size().map(|size| size.height).unwrap_or(0);
+        usize::from(height.saturating_sub(5) / 2).max(1)
+    }
+}
+
+impl Stream for Tui {
+    type Item = io::Result<String>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        while let Poll::Ready(Some(line)) = this.logs.poll_next_unpin(cx) {
+            this.print(&line);
+        }
+        loop {
+            let event = match this.events.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(event))) => event,
+                Poll::Ready(Some(Err(e))) => return Poll::Ready(Some(Err(e))),
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            };
+            let key = match event {
+                Event::Key(key) if key.kind != KeyEventKind::Release => key,
+                _ => continue,
+            };
+            let quit = key.modifiers.contains(KeyModifiers::CONTROL)
+                && matches!(key.code, KeyCode::Char('c') | KeyCode::Char('d'));
+            if quit {
+                return Poll::Ready(None);
+            }
+            if let Some(line) = this.key(key) {
+                return Poll::Ready(Some(Ok(line)));
+            }
+        }
+    }
+}
+
+impl Drop for Tui {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+        let _ = execute!(self.terminal.backend_mut(), LeaveAlternateScreen);
+        let _ = self.terminal.show_cursor();
+    }
+}
+
+/// Sends log records to the message pane, where they don't end up on top of
+/// the interface.
+pub struct PaneLogger {
+    filter: env_logger::Logger,
+    lines: UnboundedSender<String>,
+}
+
+impl PaneLogger {
+    /// Install a logger keeping the records `filter` lets through, returning
+    /// the lines to hand to [`Tui::new`].
+    pub fn init(
+        filter: env_logger::Logger,
+    ) -> Result<UnboundedReceiver<String>, log::SetLoggerError> {
+        let (lines, receiver) = mpsc::unbounded();
+        log::set_max_level(filter.filter());
+        log::set_boxed_logger(Box::new(PaneLogger { filter, lines }))?;
+        Ok(receiver)
+    }
+}
+
+impl log::Log for PaneLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.filter.matches(record) {
+            let line = format!("[{} {}] {}", record.level(), record.target(), record.args());
+            let _ = self.lines.unbounded_send(line);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+// Color lines by the kind of output they start with.
+fn style_of(line: &str) -> Style {
+    let color = match line.get(..2) {
+        Some("!!") => Color::Red,
+        Some("--") => Color::Cyan,
+        Some("<<") => Color::Reset,
+        _ if line.starts_with('[') => Color::DarkGray,
+        _ => Color::Gray,
+    };
+    Style::default().fg(color)
+}
+
+// Split a line into pieces of at most `width` characters.
+fn wrap(line: &str, width: usize) -> Vec<String> {
+    let chars: Vec<char> = line.chars().collect();
+    if chars.is_empty() {
+        return vec![String::new()];
+    }
+    chars.chunks(width).map(|chunk| chunk.iter().collect()).collect()
+}
@@ -0,0 +1,141 @@
+//! Codes of a few words for peers and channels, short enough to read out
+//! over the phone.
+//!
+//! A code spells the fingerprint of a peer id or channel name, the first
+//! 64 bits of its sha256, with six words of the BIP39 English word list.
+//! As in BIP39, the fingerprint is followed by the first two bits of its own
+//! sha256 and the 66 bits are cut into 11-bit word indices, so a misheard
+//! word is most likely caught. Decoding gives the exact fingerprint back,
+//! which is then matched against the peers and channels we know.
+
+use anyhow::{bail, Context};
+use bip39::Language;
+use libp2p::PeerId;
+use sha2::{Digest, Sha256};
+
+/// Bytes of a fingerprint.
+pub const FINGERPRINT_LEN: usize = 8;
+
+/// Words of a code.
+pub const CODE_WORDS: usize = 6;
+
+// Bits spelled by each word
+const WORD_BITS: usize = 11;
+
+/// The fingerprint of a peer id.
+pub fn peer_fingerprint(peer_id: &PeerId) -> [u8; FINGERPRINT_LEN] {
+    fingerprint(&peer_id.to_bytes())
+}
+
+/// The fingerprint of a channel, going by the topic it is published on.
+pub fn channel_fingerprint(channel: &str) -> [u8; FINGERPRINT_LEN] {
+    fingerprint(channel.as_bytes())
+}
+
+/// The code of a peer id.
+pub fn peer_code(peer_id: &PeerId) -> String {
+    encode(&peer_fingerprint(peer_id))
+}
+
+/// The code of a channel.
+pub fn channel_code(channel: &str) -> String {
+    encode(&channel_fingerprint(channel))
+}
+
+/// Spell a fingerprint as words separated by spaces.
+pub fn encode(fingerprint: &[u8; FINGERPRINT_LEN]) -> String {
+    let words = Language::English.word_list();
+    let checksum = [Sha256::digest(fingerprint)[0]];
+    let mut bits = fingerprint.iter().chain(&checksum).flat_map(|byte| {
+        (0..8).rev().map(move |shift| u16::from(byte >> shift & 1))
+    });
+    (0..CODE_WORDS)
+        .map(|_| {
+            let index = (&mut bits).take(WORD_BITS).fold(0, |index, bit| index << 1 | bit);
+            words[usize::from(index)]
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Read a code back into the fingerprint it spells. Words may be separated
+/// by spaces or dashes, in any case.
+pub fn decode(code: &str) -> anyhow::Result<[u8; FINGERPRINT_LEN]> {
+    let words = code
+        .split(|c: char| c.is_whitespace() || c == '-')
+        .filter(|word| !word.is_empty())
+        .map(|word| {
+            let word = word.to_lowercase();
+            Language::English
+                .find_word(&word)
+                .with_context(|| format!("{} is not a code word", word))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    if words.len() != CODE_WORDS {
+        bail!("a code has {} words, not {}", CODE_WORDS, words.len());
+    }
+    let mut bytes = [0; FINGERPRINT_LEN + 1];
+    let bits = words
+        .iter()
+        .flat_map(|index| (0..WORD_BITS).rev().map(move |shift| index >> shift & 1));
+    for (n, bit) in bits.enumerate() {
+        bytes[n / 8] |= (bit as u8) << (7 - n % 8);
+    }
+    let mut fingerprint = [0; FINGERPRINT_LEN];
+    fingerprint.copy_from_slice(&bytes[..FINGERPRINT_LEN]);
+    // Only the top two bits of the last byte were spelled
+    let checksum_bits = CODE_WORDS * WORD_BITS - FINGERPRINT_LEN * 8;
+    let mask = !(0xffu8 >> checksum_bits);
+    if Sha256::digest(&fingerprint)[0] & mask != bytes[FINGERPRINT_LEN] {
+        bail!("the words don't add up, one may be misheard");
+    }
+    Ok(fingerprint)
+}
+
+fn fingerprint(data: &[u8]) -> [u8; FINGERPRINT_LEN] {
+    let mut fingerprint = [0; FINGERPRINT_LEN];
+    fingerprint.copy_from_slice(&Sha256::digest(data)[..FINGERPRINT_LEN]);
+    fingerprint
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spells_zeros() {
+        // The checksum of eight zero bytes starts with the bits 10
+        assert_eq!(encode(&[0; FINGERPRINT_LEN]), "abandon abandon abandon abandon abandon able");
+    }
+
+    #[test]
+    fn round_trip() {
+        for channel in ["chat", "dev", "news@12D3KooW"] {
+            let fingerprint = channel_fingerprint(channel);
+            assert_eq!(decode(&channel_code(channel)).unwrap(), fingerprint);
+        }
+        let peer_id = PeerId::random();
+        assert_eq!(decode(&peer_code(&peer_id)).unwrap(), peer_fingerprint(&peer_id));
+    }
+
+    #[test]
+    fn reads_dashes_and_any_case() {
+        let code = channel_code("chat").to_uppercase().replace(' ', "-");
+        assert_eq!(decode(&code).unwrap(), channel_fingerprint("chat"));
+    }
+
+    #[test]
+    fn rejects_bad_checksum() {
+        // Only the spelled fingerprint differs from the one of `spells_zeros`
+        assert!(decode("abandon abandon abandon abandon ability able").is_err());
+        // Only the checksum does
+        assert!(decode("abandon abandon abandon abandon abandon ability").is_err());
+    }
+
+    #[test]
+    fn rejects_other_words() {
+        assert!(decode("abandon abandon abandon abandon able").is_err());
+        assert!(decode("abandon abandon abandon abandon abandon abandon able").is_err());
+        assert!(decode("abandon abandon abandon abandon abandon pingpong").is_err());
+    }
+}